@@ -1,22 +1,3 @@
-#[allow(dead_code)]
-struct Response {
-    code: u32,
-    message: String,
-    data: String, // TODO How to use `&str` with lifetime?
-}
-
-impl Response {
-    /// 400, bad request
-    pub fn failure<'a>(code: u32, message: String, data: String) -> Response {
-        Response { code, message, data }
-    }
-
-    /// 200, "ok",
-    pub fn success(code: u32, data: String) -> Response {
-        Response { code, message: "ok".to_string(), data }
-    }
-}
-
 /// Rust Doc1: https://course.rs/basic/result-error/panic.html
 /// 1. ? can accept Option<T> or Result<T, E>
 /// 2. impl std::error::Error for std::io::Error (From trait)
@@ -34,7 +15,8 @@ pub mod error_handlers_test_cases {
 
     use rand::random;
 
-    use crate::Response;
+    // The envelope of the webapp JSON API: `Response<String>`, or `Response<&str>` borrowing it
+    use rs_tutorial::webapp::json::Response;
 
     /// Rust Doc: https://course.rs/basic/result-error/panic.html
     #[test]
@@ -57,12 +39,13 @@ pub mod error_handlers_test_cases {
             let age_str = age.to_string();
             // FIXME creates a temporary value which is freed while still in use
             // let age_str = age.to_string().as_str();
+            // A `Response<&str>` borrows `age_str`, which outlives it
             let result = if age > 100 {
-                Result::Err(Response::failure(500, "too old".to_string(), age_str))
+                Result::Err(Response::failure(500, "too old", age_str.as_str()))
             } else if age < 0 {
-                Result::Err(Response::failure(500, "invalid".to_string(), age_str))
+                Result::Err(Response::failure(500, "invalid", age_str.as_str()))
             } else {
-                Result::Ok(Response::success(200, age_str))
+                Result::Ok(Response::success(200, age_str.as_str()))
             };
             if let Result::Ok(resp) = result {
                 println!("[test_error_handlers1] age: {:?}", resp.data);
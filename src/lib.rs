@@ -0,0 +1,3 @@
+//! The library of the crate, shared by `main.rs` and the examples.
+
+pub mod webapp;
@@ -1,104 +1,478 @@
-use std::io::{BufRead, BufReader, Write};
+use std::{env, io, process};
+use std::io::{BufWriter, Write};
 use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::thread::sleep;
+use std::thread::{sleep, JoinHandle};
 use std::time::Duration;
 
-use futures::executor::ThreadPoolBuilder;
-#[allow(dead_code, unused)]
-use futures::task::SpawnExt;
+use async_std::task;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 pub use crate::module3::module3_submodule1;
+use rs_tutorial::webapp::access_log::{init_tracing, AccessLog};
+use rs_tutorial::webapp::async_connection::serve_connection_async;
+use rs_tutorial::webapp::compression::Compression;
+use rs_tutorial::webapp::conditional::{strong_etag, Conditional, Precondition};
+use rs_tutorial::webapp::config::{ServerConfig, ServerMode};
+use rs_tutorial::webapp::connection::{serve_connection_with, Exchange, KeepAlive, Observer, StreamBody};
+use rs_tutorial::webapp::error::HttpError;
+use rs_tutorial::webapp::form::{parse_form, parse_multipart};
+use rs_tutorial::webapp::http::{HttpRequest, HttpResponse, Version};
+use rs_tutorial::webapp::json;
+use rs_tutorial::webapp::json::{parse_json, Response};
+use rs_tutorial::webapp::metrics::{Metrics, METRICS_PATH};
+use rs_tutorial::webapp::middleware::Pipeline;
+use rs_tutorial::webapp::pool::WorkerPool;
+use rs_tutorial::webapp::rate_limit::{ConnectionLimiter, ConnectionPermit, RateLimiter};
+use rs_tutorial::webapp::server::{ConnectionGuard, ServerHandle};
+use rs_tutorial::webapp::session::{FileStore, MemoryStore, Sessions};
+use rs_tutorial::webapp::sse::{Broadcaster, Event};
+use rs_tutorial::webapp::static_files::StaticFiles;
+use rs_tutorial::webapp::template::TemplateEngine;
+use rs_tutorial::webapp::router::Router;
+use rs_tutorial::webapp::websocket;
 
-#[allow(dead_code, unused)]
-pub fn handle_http_stream(mut stream: TcpStream) {
-    // let mut buffer = String::new();
-    // let read = stream.read_to_string(&mut buffer);
-
-    let buf_reader = BufReader::new(&stream);
-    let http_request: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
-
-    // println!("Request: {:#?}", http_request);
-
-    for line in http_request {
-        match &line[..] {
-            "GET /sleep HTTP/1.1" => {
-                sleep(Duration::from_secs(3)); // Mock IO operate
+/// Context of `hello.html`, and data of the `/api/hello` endpoints
+#[derive(Debug, Serialize)]
+struct Greeting {
+    name: String,
+    greeting: String,
+    wishes: Vec<String>,
+}
+
+impl Greeting {
+    fn new(name: &str) -> Self {
+        Greeting {
+            name: name.to_string(),
+            greeting: "平安喜乐！工作顺利！".to_string(),
+            wishes: vec!["身体健康".to_string(), "万事如意".to_string()],
+        }
+    }
+}
+
+/// Body of `POST /api/hello`
+#[derive(Debug, Deserialize)]
+struct HelloRequest {
+    name: String,
+    #[serde(default)]
+    wishes: Vec<String>,
+}
+
+/// Form of `POST /hello`
+#[derive(Debug, Deserialize)]
+struct HelloForm {
+    name: String,
+}
+
+/// Body of `POST /api/events`
+#[derive(Debug, Deserialize)]
+struct PublishRequest {
+    event: Option<String>,
+    data: String,
+}
+
+/// Body of `PUT /api/motd`
+#[derive(Debug, Deserialize)]
+struct MotdRequest {
+    motd: String,
+}
+
+/// The message of the day with its `ETag`, which a `PUT` must send back in `If-Match`
+fn motd_response(motd: &str) -> HttpResponse {
+    let mut response: HttpResponse = Response::success(200, json!({ "motd": motd })).into();
+    let etag = strong_etag(&response.body);
+    response.headers.insert("ETag", etag);
+    response
+}
+
+/// Render `hello.html` for `name`, or a 500 page if the template is broken.
+fn hello_page(templates: &TemplateEngine, name: &str) -> HttpResponse {
+    let context = json!(Greeting::new(name));
+    match templates.render("hello.html", &context) {
+        Ok(html) => HttpResponse::html(html),
+        Err(e) => {
+            tracing::error!("Failed to render page: {}", e);
+            HttpResponse::status_page(500)
+        }
+    }
+}
+
+/// How long `/sleep` pretends to wait for IO
+const MOCK_IO: Duration = Duration::from_secs(3);
+/// Interval of the `tick` events of `/events`
+const TICK: Duration = Duration::from_secs(5);
+/// Most lines `/numbers` streams
+const MAX_NUMBERS: u64 = 10_000_000;
+
+/// Routes served by every demo WebServer, `/sleep` blocks its thread for `MOCK_IO`
+pub fn app_router(config: &ServerConfig) -> Router {
+    let templates = Arc::new(TemplateEngine::new(&config.template_dir));
+    let (index, sleepy, hello, hello_form) = (Arc::clone(&templates), Arc::clone(&templates), Arc::clone(&templates), templates);
+    let multipart_limits = config.multipart_limits();
+    let events = Broadcaster::new();
+    let (subscribe, publish, ticker) = (events.clone(), events.clone(), events);
+    let motd = Arc::new(Mutex::new("Hello, World!".to_string()));
+    let (read_motd, write_motd) = (Arc::clone(&motd), motd);
+    // Publishes the server time, for dashboards to show something.
+    // Until the router is dropped with its server, then nobody can subscribe anymore
+    let ticker = ticker.downgrade();
+    thread::spawn(move || loop {
+        sleep(TICK);
+        let Some(ticker) = ticker.upgrade() else {
+            return;
+        };
+        ticker.publish(Event::new(chrono::Local::now().to_rfc3339()).with_event("tick"));
+    });
+    Router::new()
+        .get("/", move |_, _| hello_page(&index, "Eric"))
+        .get("/sleep", move |_, _| {
+            sleep(MOCK_IO); // Mock IO operate
+            hello_page(&sleepy, "Eric")
+        })
+        .get("/hello/:name", move |_, params| hello_page(&hello, params.get("name").unwrap_or("Eric")))
+        .post("/hello", move |request, _| match parse_form::<HelloForm>(request) {
+            Ok(form) => hello_page(&hello_form, &form.name),
+            Err(response) => response,
+        })
+        .get("/api/hello/:name", |_, params| Response::success(200, Greeting::new(params.get("name").unwrap_or("Eric"))).into())
+        .post(
+            "/api/hello",
+            json::json(|hello: HelloRequest, _, _| {
+                if hello.name.trim().is_empty() {
+                    return Response::failure(422, "name must not be empty", None);
+                }
+                let mut greeting = Greeting::new(&hello.name);
+                greeting.wishes.extend(hello.wishes);
+                Response::success(200, Some(greeting))
+            }),
+        )
+        .get("/api/visits", |request, _| {
+            let Some(session) = &request.session else {
+                return Response::failure(500, "sessions are not enabled", ()).into();
+            };
+            let visits = session.get::<u64>("visits").unwrap_or(0) + 1;
+            match session.insert("visits", visits) {
+                Ok(()) => Response::success(200, json!({ "visits": visits })).into(),
+                Err(e) => Response::failure(500, e.to_string(), ()).into(),
             }
-            _ => {
-                // TODO CODE HERE
+        })
+        // Log out: the session is deleted and its cookie expired
+        .delete("/api/visits", |request, _| {
+            let Some(session) = &request.session else {
+                return Response::failure(500, "sessions are not enabled", ()).into();
+            };
+            session.destroy();
+            HttpResponse::new(204)
+        })
+        .post("/api/upload", move |request, _| {
+            // Echoes the fields and describes the files, the temporary files are deleted with the form
+            let form = match parse_multipart(request, &multipart_limits) {
+                Ok(form) => form,
+                Err(response) => return response,
+            };
+            let parts: Vec<_> = form
+                .parts
+                .iter()
+                .map(|part| match &part.filename {
+                    Some(filename) => json!({ "name": part.name, "filename": filename, "content_type": part.content_type, "bytes": part.len() }),
+                    None => json!({ "name": part.name, "value": part.text() }),
+                })
+                .collect();
+            Response::success(200, parts).into()
+        })
+        .get("/api/motd", move |_, _| motd_response(&read_motd.lock().unwrap_or_else(|e| e.into_inner())))
+        .put("/api/motd", move |request, _| {
+            // No blind overwrites: the client shows which version it changes
+            if !request.headers.contains("If-Match") {
+                return Response::failure(428, "If-Match required", ()).into();
             }
-        }
+            let mut motd = write_motd.lock().unwrap_or_else(|e| e.into_inner());
+            let current = motd_response(&motd);
+            if let Err(response) = Precondition::check(request, current.headers.get("ETag"), None) {
+                return response;
+            }
+            match parse_json::<MotdRequest>(request) {
+                Ok(body) => {
+                    *motd = body.motd;
+                    motd_response(&motd)
+                }
+                Err(response) => response,
+            }
+        })
+        .get("/ws/echo", |request, _| {
+            websocket::upgrade(request, |socket| {
+                if let Err(e) = socket.run(|socket, message| socket.send(message)) {
+                    tracing::warn!("WebSocket closed: {}", e);
+                }
+            })
+        })
+        .get("/numbers", |request, _| {
+            // Generated as it's sent, `?count=1000000` never sits in memory
+            let count = request.query.get("count").and_then(|count| count.parse::<u64>().ok()).unwrap_or(1000);
+            HttpResponse::new(200).with_header("Content-Type", "text/plain; charset=utf-8").with_stream(StreamBody::new(
+                move |writer, shutdown| {
+                    let mut writer = BufWriter::new(writer);
+                    for n in 1..=count.min(MAX_NUMBERS) {
+                        // Cut short, so the client sees an incomplete body rather than a shorter one
+                        if n % 10_000 == 0 && shutdown.is_shutting_down() {
+                            return Err(io::Error::other("server shutting down"));
+                        }
+                        writeln!(writer, "{}", n)?;
+                    }
+                    writer.flush()
+                },
+            ))
+        })
+        .get("/events", move |request, _| subscribe.subscribe(request))
+        .post(
+            "/api/events",
+            json::json(move |body: PublishRequest, _, _| {
+                let event = Event { event: body.event, ..Event::new(body.data) };
+                Response::success(202, json!({ "id": publish.publish(event) }))
+            }),
+        )
+        .static_files("/static", StaticFiles::new(&config.static_dir).with_listing(true))
+}
+
+/// `router` behind the middlewares of every demo WebServer, `metrics` are served on `/metrics`
+pub fn app_pipeline(config: &ServerConfig, router: Router, metrics: &Metrics) -> io::Result<Pipeline> {
+    let sessions = match &config.session_dir {
+        Some(dir) => Sessions::new(FileStore::new(dir)?),
+        None => Sessions::new(MemoryStore::new()),
+    };
+    // A route, so the rate limiter covers it too
+    let endpoint = metrics.clone();
+    let router = router.get(METRICS_PATH, move |_, _| endpoint.response());
+    // Around the compression, preconditions are evaluated against the encoded response and its `ETag`
+    let mut pipeline = Pipeline::new(router)
+        .with(AccessLog::new(config.access_log))
+        .with(metrics.clone())
+        .with(Conditional::new());
+    // Inside the metrics, so their latency covers the compression too
+    if config.compression_level > 0 {
+        let compression = Compression::new().with_level(config.compression_level);
+        pipeline = pipeline.with(compression.with_min_bytes(config.compression_min_bytes));
     }
+    // Ahead of the rate limiter, so pages of other origins can read a 429 too
+    if let Some(cors) = config.cors().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))? {
+        pipeline = pipeline.with(cors);
+    }
+    if config.rate_limit_per_sec > 0.0 {
+        let limiter = RateLimiter::new(config.rate_limit_per_sec, config.rate_limit_burst);
+        let limiter = limiter.with_header(&config.rate_limit_header).with_sweep_interval(config.rate_limit_sweep());
+        pipeline = pipeline.with(limiter.with_trusted_proxies(config.trusted_proxies.clone()));
+    }
+    let sessions = sessions.with_cookie_name(&config.session_cookie_name).with_secure(config.session_secure);
+    Ok(pipeline.with(sessions.with_ttl(config.session_ttl())))
+}
 
-    let status_line = "HTTP/1.1 200 OK";
-    let contents = r#"
-            <!DOCTYPE html>
-            <html lang="en">
-                <head>
-                    <meta charset="utf-8"/>
-                    <title>Hello, Eric!</title>
-                </head>
-                <body>
-                    <h1>Hello, Eric!</h1>
-                    <h2 style="color:red">平安喜乐！工作顺利！</h2>
-                </body>
-            </html>
-        "#;
-    let length = contents.len();
-    let resp = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n\n{contents}");
-    stream.write_all(resp.as_bytes()).unwrap();
-    stream.flush().unwrap();
+/// Writes the access log and counts the bytes sent of every demo WebServer, once the responses are on the wire
+pub fn app_observer(config: &ServerConfig, metrics: &Metrics) -> Observer {
+    let (access_log, metrics) = (AccessLog::new(config.access_log), metrics.clone());
+    Arc::new(move |exchange: &Exchange| {
+        access_log.log(exchange);
+        metrics.count_sent(exchange);
+    })
+}
+
+/// Serve every request of `stream` until the connection is closed.
+/// SSE subscriptions and WebSockets go on on a thread of their own, so they don't hold the calling one
+/// for as long as the client stays: a pool worker, or the only thread of the single threaded server.
+#[allow(dead_code, unused)]
+pub fn handle_http_stream(
+    stream: TcpStream,
+    pipeline: &Pipeline,
+    metrics: &Metrics,
+    config: &ServerConfig,
+    keep_alive: &KeepAlive,
+    guard: &ConnectionGuard,
+    permit: ConnectionPermit,
+) {
+    let connection = metrics.track_connection();
+    let observer = app_observer(config, metrics);
+    let result = serve_connection_with(
+        stream,
+        guard,
+        keep_alive,
+        &config.timeouts(),
+        &config.limits(),
+        |request| pipeline.handle(request),
+        |rest| {
+            // Still counted, until the client leaves
+            thread::spawn(move || {
+                let (_connection, _permit) = (connection, permit);
+                rest();
+            });
+        },
+        &observer,
+    );
+    log_connection_result(result);
+}
+
+/// Count `stream` against the connections of its client, or answer it with 429 if it has too many open.
+fn admit_connection(limiter: &ConnectionLimiter, stream: &mut TcpStream) -> Option<ConnectionPermit> {
+    // Without a peer address the client is gone already
+    let client = stream.peer_addr().ok()?.ip();
+    let permit = limiter.acquire(client);
+    if permit.is_none() {
+        tracing::warn!("Too many connections from {}, reject connection", client);
+        let response = HttpResponse::status_page(429).with_header("Connection", "close");
+        let _ = response.write_to(Version::Http11, stream);
+    }
+    permit
+}
+
+fn log_connection_result(result: Result<(), HttpError>) {
+    match result {
+        Ok(()) => {}
+        Err(e) if e.status().is_some() => tracing::warn!("Request failed: {}", e),
+        Err(e) => tracing::warn!("Connection dropped: {}", e),
+    }
+}
+
+/// Start the WebServer selected by `config.mode`
+pub fn start_webserver(config: ServerConfig) -> io::Result<ServerHandle> {
+    match config.mode {
+        ServerMode::SingleThread => start_webserver_single_thread(config),
+        ServerMode::MultiThreads => start_webserver_multi_threads(config),
+        ServerMode::ThreadPool => start_webserver_thread_pool(config),
+        ServerMode::Async => start_webserver_async(config),
+    }
 }
 
 /// Demo 1. WebServer (single thread)
+/// Connections are served one by one on the accept thread.
+/// Keep-alive is disabled, otherwise one idle client would block all the others.
 #[allow(dead_code, unused)]
-pub fn start_webserver_single_thread() {
-    let host = "127.0.0.1:8080";
+pub fn start_webserver_single_thread(config: ServerConfig) -> io::Result<ServerHandle> {
+    let metrics = Metrics::new();
+    let pipeline = app_pipeline(&config, app_router(&config), &metrics)?;
+    let keep_alive = KeepAlive::disabled();
     // WARN: it's not tokio::net::TcpListener
-    let listener = TcpListener::bind(host).unwrap();
-    println!("Listen on port: {:?}", host);
-    for result in listener.incoming() {
-        println!("Connection established!!!");
-        let stream = result.unwrap();
-        handle_http_stream(stream);
-    }
+    let listener = TcpListener::bind(&config.bind)?;
+    println!("Listen on port: {:?}", config.bind);
+    let connections = config.connection_limiter();
+    ServerHandle::spawn(listener, move |incoming| {
+        for (mut stream, guard) in incoming {
+            let Some(permit) = admit_connection(&connections, &mut stream) else {
+                continue;
+            };
+            handle_http_stream(stream, &pipeline, &metrics, &config, &keep_alive, &guard, permit);
+        }
+    })
 }
 
 /// Demo 2. WebServer (multi threads)
 #[allow(dead_code, unused)]
-pub fn start_webserver_multi_threads() {
-    let host = "127.0.0.1:8080";
+pub fn start_webserver_multi_threads(config: ServerConfig) -> io::Result<ServerHandle> {
+    let metrics = Metrics::new();
+    let pipeline = Arc::new(app_pipeline(&config, app_router(&config), &metrics)?);
+    let keep_alive = config.keep_alive();
     // WARN: it's not tokio::net::TcpListener
-    let listener = TcpListener::bind(host).unwrap();
-    println!("Listen on port: {:?}", host);
-    for result in listener.incoming() {
-        println!("Connection established!!!");
-        let stream = result.unwrap();
-        thread::spawn(|| {
-            handle_http_stream(stream);
-        });
-    }
+    let listener = TcpListener::bind(&config.bind)?;
+    println!("Listen on port: {:?}", config.bind);
+    let connections = config.connection_limiter();
+    let config = Arc::new(config);
+    ServerHandle::spawn(listener, move |incoming| {
+        let mut workers: Vec<JoinHandle<()>> = Vec::new();
+        for (mut stream, guard) in incoming {
+            // Before a thread is spent on it
+            let Some(permit) = admit_connection(&connections, &mut stream) else {
+                continue;
+            };
+            let pipeline = Arc::clone(&pipeline);
+            let config = Arc::clone(&config);
+            let (metrics, keep_alive) = (metrics.clone(), keep_alive.clone());
+            workers.retain(|worker| !worker.is_finished());
+            workers.push(thread::spawn(move || {
+                handle_http_stream(stream, &pipeline, &metrics, &config, &keep_alive, &guard, permit);
+            }));
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+    })
 }
 
 /// Demo 3. WebServer (thread pool)
+/// `config.workers` threads take connections from a queue holding at most `config.queue_depth` of them.
+/// When the queue is full the connection is answered with 503 right away.
 #[allow(dead_code, unused)]
-pub fn start_webserver_thread_pool() {
-    let host = "127.0.0.1:8080";
-    let mut builder = ThreadPoolBuilder::new();
-    let pool = builder.pool_size(3).create().unwrap();
+pub fn start_webserver_thread_pool(config: ServerConfig) -> io::Result<ServerHandle> {
+    let metrics = Metrics::new();
+    let pipeline = app_pipeline(&config, app_router(&config), &metrics)?;
+    let keep_alive = config.keep_alive();
     // WARN: it's not tokio::net::TcpListener
-    let listener = TcpListener::bind(host).unwrap();
-    println!("Listen on port: {:?}", host);
-    for result in listener.incoming() {
-        println!("Connection established!!!");
-        let stream = result.unwrap();
-        // TODO
-    }
+    let listener = TcpListener::bind(&config.bind)?;
+    println!(
+        "Listen on port: {:?}, workers: {}, queue depth: {}",
+        config.bind, config.workers, config.queue_depth
+    );
+    let (workers, queue_depth) = (config.workers, config.queue_depth);
+    let connections = config.connection_limiter();
+    ServerHandle::spawn(listener, move |incoming| {
+        let worker_metrics = metrics.clone();
+        let pool = WorkerPool::new(workers, queue_depth, move |(stream, guard, permit): (TcpStream, ConnectionGuard, ConnectionPermit)| {
+            worker_metrics.queue_depth().dec();
+            handle_http_stream(stream, &pipeline, &worker_metrics, &config, &keep_alive, &guard, permit);
+        });
+        for (mut stream, guard) in incoming {
+            // One client doesn't get to fill the queue
+            let Some(permit) = admit_connection(&connections, &mut stream) else {
+                continue;
+            };
+            // Counted before queueing, so a worker never takes it out first
+            metrics.queue_depth().inc();
+            if let Err((mut stream, _guard, _permit)) = pool.try_execute((stream, guard, permit)) {
+                metrics.queue_depth().dec();
+                tracing::warn!("Thread pool is busy, reject connection");
+                let response = HttpResponse::status_page(503)
+                    .with_header("Retry-After", "1")
+                    .with_header("Connection", "close");
+                let _ = response.write_to(Version::Http11, &mut stream);
+            }
+        }
+        // drop(pool) joins the workers
+    })
+}
+
+/// Demo 4. WebServer (async-std)
+/// Every connection is an async task on the async-std executor, accepting still happens on the acceptor thread.
+/// The `Pipeline` blocks, on files, sessions or `/sleep`, so it runs on the blocking thread pool of async-std:
+/// the executor threads keep serving the other connections meanwhile.
+#[allow(dead_code, unused)]
+pub fn start_webserver_async(config: ServerConfig) -> io::Result<ServerHandle> {
+    let metrics = Metrics::new();
+    let pipeline = Arc::new(app_pipeline(&config, app_router(&config), &metrics)?);
+    let keep_alive = config.keep_alive();
+    let listener = TcpListener::bind(&config.bind)?;
+    println!("Listen on port: {:?}, async-std", config.bind);
+    let connections = config.connection_limiter();
+    let observer = app_observer(&config, &metrics);
+    let config = Arc::new(config);
+    ServerHandle::spawn(listener, move |incoming| {
+        for (mut stream, guard) in incoming {
+            let Some(permit) = admit_connection(&connections, &mut stream) else {
+                continue;
+            };
+            let (pipeline, config, keep_alive) = (Arc::clone(&pipeline), Arc::clone(&config), keep_alive.clone());
+            let observer = Arc::clone(&observer);
+            let connection = metrics.track_connection();
+            task::spawn(async move {
+                let (_connection, _permit) = (connection, permit);
+                let stream = async_std::net::TcpStream::from(stream);
+                let handler = |mut request: HttpRequest| {
+                    let pipeline = Arc::clone(&pipeline);
+                    task::spawn_blocking(move || pipeline.handle(&mut request))
+                };
+                let result = serve_connection_async(stream, &guard, &keep_alive, &config.timeouts(), &config.limits(), handler, &observer).await;
+                log_connection_result(result);
+            });
+        }
+        // The tasks hold their `ConnectionGuard`, `shutdown` waits for them
+    })
 }
 
 mod module1;
@@ -112,13 +486,32 @@ fn define_multi_modules() {
     module3_submodule1::m3s1::print_m3s1();
 }
 
+/// `cargo run -- --config webserver.json`, see `ServerConfig` for the `WEBAPP_*` environment variables.
 #[allow(dead_code, unused)]
 fn main() {
 
     // define_multi_modules();
 
-    // start_webserver_single_thread();
-    // start_webserver_multi_threads();
-    // start_webserver_thread_pool();
+    let config = match ServerConfig::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    init_tracing();
+    let shutdown_timeout = config.shutdown_timeout();
+    let server = match start_webserver(config) {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("Failed to start WebServer: {}", e);
+            process::exit(1);
+        }
+    };
+    // Ctrl+C to stop
+    match server.shutdown_on_signal(shutdown_timeout) {
+        Ok(true) => println!("WebServer stopped"),
+        Ok(false) => tracing::warn!("WebServer stopped, some connections were cut off"),
+        Err(e) => tracing::error!("Failed to wait for signals: {}", e),
+    }
 }
-
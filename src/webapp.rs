@@ -0,0 +1,31 @@
+//! Building blocks of the demo web servers started from `main.rs`, exported by `lib.rs`.
+//! Same layout as `module3.rs`: this file declares all submodules located at `src/webapp/`.
+//! - src/main.rs  (start_webserver_*, handle_http_stream)
+//! - src/lib.rs  (declare `webapp`)
+//! - src/webapp.rs  (declare submodules)
+//! - src/webapp/{submodule}.rs
+
+pub mod error;
+pub mod http;
+pub mod router;
+pub mod pool;
+pub mod server;
+pub mod connection;
+pub mod static_files;
+pub mod config;
+pub mod template;
+pub mod access_log;
+pub mod middleware;
+pub mod async_connection;
+pub mod websocket;
+pub mod json;
+pub mod sse;
+pub mod cookie;
+pub mod session;
+pub mod form;
+pub mod rate_limit;
+pub mod metrics;
+pub mod compression;
+pub mod chunked;
+pub mod cors;
+pub mod conditional;
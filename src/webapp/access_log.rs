@@ -0,0 +1,322 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, FixedOffset, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{field, info_span, Event, Level, Subscriber};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crate::webapp::connection::Exchange;
+use crate::webapp::http::{HttpRequest, HttpResponse};
+use crate::webapp::middleware::{Middleware, Next};
+
+/// Target of the access log events, `init_tracing` prints them without any decoration.
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+/// Layout of the access log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// Common Log Format: `127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET /a.gif HTTP/1.0" 200 2326`
+    Common,
+    /// One JSON object per line
+    Json,
+    Off,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "json" => Ok(AccessLogFormat::Json),
+            "off" => Ok(AccessLogFormat::Off),
+            _ => Err(format!("unknown access log format {:?}, expected common, json or off", s)),
+        }
+    }
+}
+
+/// One answered request.
+pub struct AccessEntry<'a> {
+    /// `None` if not even its request line could be read
+    pub request: Option<&'a HttpRequest>,
+    pub peer_addr: Option<SocketAddr>,
+    pub status: u16,
+    /// Body bytes sent, see `Exchange::bytes`
+    pub bytes: u64,
+    pub latency: Duration,
+    pub time: DateTime<FixedOffset>,
+}
+
+impl<'a> AccessEntry<'a> {
+    /// The entry of `exchange`, which ended now
+    pub fn new(exchange: &Exchange<'a>) -> Self {
+        AccessEntry {
+            request: exchange.request,
+            peer_addr: exchange.peer_addr,
+            status: exchange.status,
+            bytes: exchange.bytes,
+            latency: exchange.latency,
+            time: Local::now().fixed_offset(),
+        }
+    }
+}
+
+impl AccessLogFormat {
+    /// The log line of `entry`, `None` if logging is off.
+    pub fn format(&self, entry: &AccessEntry) -> Option<String> {
+        let request = entry.request;
+        let peer = entry.peer_addr.map(|addr| addr.ip().to_string());
+        match self {
+            AccessLogFormat::Common => Some(format!(
+                "{} - - [{}] \"{}\" {} {}",
+                peer.as_deref().unwrap_or("-"),
+                entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                match request {
+                    Some(request) => format!("{} {} {}", request.method, request.target, request.version),
+                    None => "-".to_string(),
+                },
+                entry.status,
+                match entry.bytes {
+                    0 => "-".to_string(),
+                    bytes => bytes.to_string(),
+                },
+            )),
+            AccessLogFormat::Json => Some(
+                json!({
+                    "time": entry.time.to_rfc3339(),
+                    "peer": peer,
+                    "method": request.map(|request| request.method.as_str()),
+                    "path": request.map(|request| &request.path),
+                    "target": request.map(|request| &request.target),
+                    "version": request.map(|request| request.version.as_str()),
+                    "status": entry.status,
+                    "bytes": entry.bytes,
+                    "latency_ms": entry.latency.as_secs_f64() * 1000.0,
+                    "user_agent": request.and_then(|request| request.header("User-Agent")),
+                    "referer": request.and_then(|request| request.header("Referer")),
+                })
+                .to_string(),
+            ),
+            AccessLogFormat::Off => None,
+        }
+    }
+}
+
+/// Writes one access log line per request, once its response is written: as the `Observer` of the
+/// connections it sees the bytes that reached the socket, streams and requests that failed to parse included.
+/// ```ignore
+/// let access_log = AccessLog::new(format);
+/// let observer: Observer = Arc::new(move |exchange| access_log.log(exchange));
+/// ```
+/// As a middleware it runs the rest of the chain in a `request` span, for the events of the handlers.
+#[derive(Debug, Clone, Copy)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat) -> Self {
+        AccessLog { format }
+    }
+
+    pub fn log(&self, exchange: &Exchange) {
+        if let Some(line) = self.format.format(&AccessEntry::new(exchange)) {
+            tracing::info!(target: ACCESS_LOG_TARGET, "{}", line);
+        }
+    }
+}
+
+impl Middleware for AccessLog {
+    /// `status` and `handler_ms` are recorded on the span once the response is ready.
+    fn handle(&self, request: &mut HttpRequest, next: Next<'_>) -> HttpResponse {
+        let span = info_span!(
+            "request",
+            method = %request.method,
+            path = %request.path,
+            peer = field::Empty,
+            status = field::Empty,
+            handler_ms = field::Empty,
+        );
+        if let Some(addr) = request.peer_addr {
+            span.record("peer", field::display(addr));
+        }
+        let _entered = span.enter();
+        let started = Instant::now();
+        let response = next.run(request);
+        span.record("status", response.status);
+        span.record("handler_ms", started.elapsed().as_secs_f64() * 1000.0);
+        response
+    }
+}
+
+/// Install the global subscriber: access log lines go to stdout as they are,
+/// other events at `INFO` and above through the default formatter.
+pub fn init_tracing() {
+    let access = tracing_subscriber::fmt::layer()
+        .event_format(LineOnly)
+        .with_filter(filter_fn(|metadata| metadata.target() == ACCESS_LOG_TARGET));
+    let app = tracing_subscriber::fmt::layer()
+        .with_filter(filter_fn(|metadata| metadata.target() != ACCESS_LOG_TARGET && *metadata.level() <= Level::INFO));
+    tracing_subscriber::registry().with(access).with(app).init();
+}
+
+/// Print the message of the event only: no timestamp, level or span context.
+struct LineOnly;
+
+impl<S, N> FormatEvent<S, N> for LineOnly
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+#[cfg(test)]
+pub mod access_log_test_cases {
+    use std::io::{BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use tracing::Dispatch;
+
+    use super::*;
+    use crate::webapp::connection::{serve_connection_with, KeepAlive, Observer, StreamBody, Timeouts};
+    use crate::webapp::http::Limits;
+    use crate::webapp::server::ServerHandle;
+
+    fn entry(request: &HttpRequest, bytes: u64) -> AccessEntry<'_> {
+        AccessEntry {
+            request: Some(request),
+            peer_addr: request.peer_addr,
+            status: 200,
+            bytes,
+            latency: Duration::from_micros(1500),
+            time: DateTime::parse_from_rfc3339("2000-10-10T13:55:36-07:00").unwrap(),
+        }
+    }
+
+    fn request() -> HttpRequest {
+        let raw = "GET /a%20b.gif?x=1 HTTP/1.0\r\nUser-Agent: curl/8.0\r\n\r\n";
+        let mut request = HttpRequest::read_from(&mut BufReader::new(raw.as_bytes())).unwrap().unwrap();
+        request.peer_addr = Some("127.0.0.1:50000".parse().unwrap());
+        request
+    }
+
+    /// What the access log subscriber printed
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_common_format() {
+        let request = request();
+        assert_eq!(
+            AccessLogFormat::Common.format(&entry(&request, 2326)).unwrap(),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] \"GET /a%20b.gif?x=1 HTTP/1.0\" 200 2326"
+        );
+        let line = AccessLogFormat::Common.format(&entry(&request, 0)).unwrap();
+        assert!(line.ends_with(" 200 -"), "{}", line);
+        let unread = AccessEntry { request: None, status: 400, ..entry(&request, 0) };
+        let line = AccessLogFormat::Common.format(&unread).unwrap();
+        assert!(line.ends_with("] \"-\" 400 -"), "{}", line);
+        assert_eq!(AccessLogFormat::Off.format(&entry(&request, 0)), None);
+    }
+
+    #[test]
+    pub fn test_json_format() {
+        let request = request();
+        let line = AccessLogFormat::Json.format(&entry(&request, 10)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["peer"], "127.0.0.1");
+        assert_eq!(value["method"], "GET");
+        assert_eq!(value["path"], "/a b.gif");
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["bytes"], 10);
+        assert_eq!(value["latency_ms"], 1.5);
+        assert_eq!(value["user_agent"], "curl/8.0");
+        assert_eq!(value["referer"], serde_json::Value::Null);
+        assert_eq!(value["time"], "2000-10-10T13:55:36-07:00");
+        assert!("apache".parse::<AccessLogFormat>().is_err());
+        assert_eq!("off".parse::<AccessLogFormat>(), Ok(AccessLogFormat::Off));
+    }
+
+    #[test]
+    pub fn test_logged_after_write() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let layer = tracing_subscriber::fmt::layer()
+            .event_format(LineOnly)
+            .with_writer(move || writer.clone())
+            .with_filter(filter_fn(|metadata| metadata.target() == ACCESS_LOG_TARGET));
+        let dispatch = Dispatch::new(tracing_subscriber::registry().with(layer));
+        let access_log = AccessLog::new(AccessLogFormat::Json);
+        let observer: Observer = Arc::new(move |exchange: &Exchange| access_log.log(exchange));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ServerHandle::spawn(listener, move |incoming| {
+            for (stream, guard) in incoming {
+                let (dispatch, observer) = (dispatch.clone(), Arc::clone(&observer));
+                thread::spawn(move || {
+                    let handler = |request: &mut HttpRequest| match request.path.as_str() {
+                        // Takes a while after the handler returned
+                        "/slow" => HttpResponse::new(200).with_stream(StreamBody::new(|writer, _| {
+                            thread::sleep(Duration::from_millis(100));
+                            writer.write_all(b"abc")
+                        })),
+                        _ => HttpResponse::new(200).with_body("hello"),
+                    };
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        let (keep_alive, timeouts, limits) = (KeepAlive::default(), Timeouts::default(), Limits::default());
+                        serve_connection_with(stream, &guard, &keep_alive, &timeouts, &limits, handler, |rest| rest(), &observer)
+                    })
+                });
+            }
+        })
+        .unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"HEAD /file HTTP/1.1\r\n\r\nGET /file HTTP/1.1\r\n\r\nGET /slow HTTP/1.1\r\n\r\nBAD\r\n\r\n").unwrap();
+        client.read_to_end(&mut Vec::new()).unwrap();
+        assert!(server.shutdown(Duration::from_secs(5)));
+
+        let lines = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let entries: Vec<serde_json::Value> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let summary: Vec<_> = entries.iter().map(|entry| (entry["method"].clone(), entry["status"].clone(), entry["bytes"].clone())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("HEAD".into(), 200.into(), 0.into()),
+                ("GET".into(), 200.into(), 5.into()),
+                // One chunk and the last one, framing included
+                ("GET".into(), 200.into(), (8 + 5).into()),
+                // Not even a request line
+                (serde_json::Value::Null, 400.into(), (HttpResponse::status_page(400).body.len()).into()),
+            ]
+        );
+        assert!(entries[2]["latency_ms"].as_f64().unwrap() >= 100.0, "{}", entries[2]);
+    }
+}
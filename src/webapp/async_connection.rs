@@ -0,0 +1,543 @@
+use std::future::Future;
+use std::mem;
+use std::io::{Seek, SeekFrom, Write};
+use std::net::Shutdown;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+use async_std::future;
+use async_std::io;
+use async_std::io::prelude::{BufReadExt, ReadExt, WriteExt};
+use async_std::io::BufReader;
+use async_std::net::TcpStream;
+use async_std::task;
+use futures::channel::mpsc;
+use futures::{executor, FutureExt, SinkExt, StreamExt};
+
+use crate::webapp::chunked::{check_chunk_end, chunk_end, parse_chunk_line, MAX_CHUNK_LINE};
+use crate::webapp::connection::{
+    keep_connection, panic_message, take_upgrade, Exchange, KeepAlive, Observer, StreamBody, Timeouts, Upgraded,
+    IDLE_POLL_INTERVAL, LINGER_TIMEOUT,
+};
+use crate::webapp::error::HttpError;
+use crate::webapp::form::{multipart_boundary, Multipart, Upload};
+use crate::webapp::http::{read_headers, BodyLength, Headers, HttpRequest, HttpResponse, Limits, Method, Version};
+use crate::webapp::server::ConnectionGuard;
+
+/// `serve_connection` on async-std: reads, writes and the handler are awaited,
+/// so a connection waiting for its client or its handler doesn't hold an OS thread.
+///
+/// The handler takes the request by value and may await anything, e.g. `task::sleep`.
+/// Every request is told to `observer` once its response is written, like `serve_connection_with` does.
+pub async fn serve_connection_async<F, Fut>(
+    stream: TcpStream,
+    guard: &ConnectionGuard,
+    keep_alive: &KeepAlive,
+    timeouts: &Timeouts,
+    limits: &Limits,
+    mut handler: F,
+    observer: &Observer,
+) -> Result<(), HttpError>
+where
+    F: FnMut(HttpRequest) -> Fut,
+    Fut: Future<Output = HttpResponse>,
+{
+    let mut reader = BufReader::new(&stream);
+    let mut served = 0;
+    let peer_addr = stream.peer_addr().ok();
+    loop {
+        let wait = if served == 0 { timeouts.read } else { keep_alive.idle_timeout };
+        if !wait_for_request(&mut reader, wait, guard).await? {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let report = |request: Option<&HttpRequest>, status: u16, bytes: u64| {
+            observer(&Exchange { request, peer_addr, status, bytes, latency: started.elapsed() });
+        };
+        // Error pages answer in the version of the request, once its request line is read
+        let mut read = None;
+        let result = match future::timeout(timeouts.read, read_request(&mut reader, limits, &mut read)).await {
+            Ok(result) => result,
+            Err(_) => Err(HttpError::Timeout),
+        };
+        if let Some(request) = &mut read {
+            request.peer_addr = peer_addr;
+        }
+        if let Err(e) = result {
+            let version = read.as_ref().map_or(Version::Http11, |request| request.version);
+            let (error, sent) = send_error(&stream, e, version, timeouts).await;
+            if let Some((status, bytes)) = sent {
+                report(read.as_ref(), status, bytes);
+            }
+            return Err(error);
+        }
+        let Some(mut request) = read else {
+            return Ok(());
+        };
+        served += 1;
+
+        let (version, wants_keep_alive) = (request.version, request.wants_keep_alive());
+        let head_only = request.method == Method::Head;
+        // The handler takes the request, the observer gets it without its body
+        let body = mem::take(&mut request.body);
+        let observed = request.clone();
+        request.body = body;
+        let mut response = match AssertUnwindSafe(async { handler(request).await }).catch_unwind().await {
+            Ok(response) => response,
+            Err(payload) => {
+                let error = HttpError::Internal(panic_message(payload));
+                let (error, sent) = send_error(&stream, error, version, timeouts).await;
+                if let Some((status, bytes)) = sent {
+                    report(Some(&observed), status, bytes);
+                }
+                return Err(error);
+            }
+        };
+        if let Some(upgrade) = take_upgrade(&mut response) {
+            write_response(&stream, &response, version, false, timeouts.write).await.map_err(HttpError::Io)?;
+            report(Some(&observed), response.status, 0);
+            // The upgraded protocols are blocking, give them a thread of their own
+            let buffered = reader.buffer().to_vec();
+            drop(reader);
+            let stream = std::net::TcpStream::try_from(stream)?;
+            let shutdown = guard.shutdown_signal();
+            task::spawn_blocking(move || upgrade.run(Upgraded { stream, buffered, shutdown })).await;
+            return Ok(());
+        }
+        let keep = keep_connection(version, wants_keep_alive, &mut response, served, keep_alive, guard);
+        if head_only {
+            response.stream = None;
+        }
+        let mut sent = 0;
+        let mut written = write_response(&stream, &response, version, head_only, timeouts.write).await.map(|bytes| sent = bytes);
+        if let (Some(body), Ok(())) = (response.stream.take(), &written) {
+            written = write_stream(&stream, body, version, guard, timeouts.write, &mut sent).await;
+        }
+        report(Some(&observed), response.status, sent);
+        // A write timeout means the client is gone for us, not a 408
+        written.map_err(HttpError::Io)?;
+        if !keep {
+            return Ok(());
+        }
+    }
+}
+
+/// Buffer the request line, then the headers, and let `HttpRequest::read_request_line` and `read_headers`
+/// parse and check them. At most enough bytes for them to reject an oversized head are buffered.
+/// `request` is set as soon as the request line is parsed, before the headers arrive, and left `None` if the client left first.
+async fn read_request(reader: &mut BufReader<&TcpStream>, limits: &Limits, request: &mut Option<HttpRequest>) -> Result<(), HttpError> {
+    let terminators = limits.max_headers.saturating_add(2).saturating_mul(2);
+    let mut budget = limits.max_header_bytes.saturating_mul(2).saturating_add(terminators);
+    let mut line = Vec::new();
+    budget -= (&mut *reader).take(budget as u64).read_until(b'\n', &mut line).await?;
+    let Some(request_line) = HttpRequest::read_request_line(&mut line.as_slice(), limits)? else {
+        return Ok(());
+    };
+    let request = request.insert(request_line);
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let read = (&mut *reader).take(budget as u64).read_until(b'\n', &mut head).await?;
+        budget -= read;
+        let line = &head[start..];
+        let end_of_head = line == b"\r\n" || line == b"\n";
+        if read == 0 || budget == 0 || !line.ends_with(b"\n") || end_of_head {
+            break;
+        }
+    }
+    request.headers = read_headers(&mut head.as_slice(), limits)?;
+    if let Some(boundary) = multipart_boundary(request) {
+        return read_upload(reader, request, boundary, limits).await;
+    }
+    match request.body_length(limits)? {
+        BodyLength::Fixed(length) => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await?;
+            request.body = body;
+        }
+        BodyLength::Chunked => {
+            let mut body = Vec::new();
+            request.trailers = read_chunked(reader, limits, &mut body).await?;
+            request.body = body;
+        }
+    }
+    Ok(())
+}
+
+/// `form::read_upload` on async-std: the body goes to a temporary file as it arrives,
+/// then it's parsed from there on a blocking thread.
+async fn read_upload(reader: &mut BufReader<&TcpStream>, request: &mut HttpRequest, boundary: String, limits: &Limits) -> Result<(), HttpError> {
+    let upload_limits = Limits { max_body_bytes: limits.max_upload_bytes, ..limits.clone() };
+    let mut file = tempfile::tempfile().map_err(|e| HttpError::Internal(format!("failed to buffer upload: {}", e)))?;
+    match request.body_length(&upload_limits)? {
+        BodyLength::Fixed(length) => copy_exact(reader, length, &mut file).await?,
+        BodyLength::Chunked => request.trailers = read_chunked(reader, &upload_limits, &mut file).await?,
+    }
+    let multipart = limits.multipart.clone();
+    let parsed = task::spawn_blocking(move || {
+        file.seek(SeekFrom::Start(0))?;
+        Multipart::read_from(std::io::BufReader::new(file), &boundary, &multipart)
+    })
+    .await;
+    request.upload = Some(Upload::new(parsed));
+    Ok(())
+}
+
+/// `chunked::read_chunked` on async-std, the body goes to `sink`.
+async fn read_chunked<W: Write>(reader: &mut BufReader<&TcpStream>, limits: &Limits, sink: &mut W) -> Result<Headers, HttpError> {
+    let mut length = 0;
+    loop {
+        let mut line = Vec::new();
+        (&mut *reader).take(MAX_CHUNK_LINE as u64 + 2).read_until(b'\n', &mut line).await?;
+        let size = parse_chunk_line(&line)?;
+        if size == 0 {
+            return read_trailers(reader, limits).await;
+        }
+        length = chunk_end(length, size, limits)?;
+        copy_exact(reader, size, sink).await?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).await?;
+        check_chunk_end(&crlf)?;
+    }
+}
+
+/// Copy the next `length` bytes of `reader` to `sink`, a buffer at a time.
+/// A failing sink, e.g. a temporary file on a full disk, is the server's fault: 500.
+async fn copy_exact<W: Write>(reader: &mut BufReader<&TcpStream>, mut length: usize, sink: &mut W) -> Result<(), HttpError> {
+    let mut buffer = vec![0; length.min(COPY_BYTES)];
+    while length > 0 {
+        let read = length.min(COPY_BYTES);
+        reader.read_exact(&mut buffer[..read]).await?;
+        sink.write_all(&buffer[..read]).map_err(|e| HttpError::Internal(format!("failed to buffer body: {}", e)))?;
+        length -= read;
+    }
+    Ok(())
+}
+
+/// Buffer the trailer section like `read_request` buffers the head, then let `read_headers` parse it.
+async fn read_trailers(reader: &mut BufReader<&TcpStream>, limits: &Limits) -> Result<Headers, HttpError> {
+    let mut section = Vec::new();
+    let terminators = limits.max_headers.saturating_add(1).saturating_mul(2);
+    let mut budget = limits.max_header_bytes.saturating_mul(2).saturating_add(terminators);
+    loop {
+        let start = section.len();
+        let read = (&mut *reader).take(budget as u64).read_until(b'\n', &mut section).await?;
+        budget -= read;
+        let line = &section[start..];
+        if read == 0 || budget == 0 || !line.ends_with(b"\n") || line == b"\r\n" || line == b"\n" {
+            break;
+        }
+    }
+    read_headers(&mut section.as_slice(), limits)
+}
+
+/// Run a streamed body on a blocking thread, like upgrades, and write what it produces from here.
+/// The connection stays async, so it can serve the next request once a chunked body ends.
+/// `sent` counts the bytes written, chunk framing included.
+async fn write_stream(
+    stream: &TcpStream,
+    body: StreamBody,
+    version: Version,
+    guard: &ConnectionGuard,
+    timeout: Duration,
+    sent: &mut u64,
+) -> io::Result<()> {
+    let (sender, mut receiver) = mpsc::channel(STREAM_BUFFER);
+    let shutdown = guard.shutdown_signal();
+    let producer = task::spawn_blocking(move || body.write_framed(version, &mut ChannelWriter(sender), &shutdown));
+    let mut writer = stream;
+    while let Some(bytes) = receiver.next().await {
+        // Dropping the receiver fails the producer's next write, which ends the body
+        io::timeout(timeout, writer.write_all(&bytes)).await?;
+        *sent += bytes.len() as u64;
+    }
+    producer.await
+}
+
+/// Pieces of a streamed body in flight between its thread and the connection
+const STREAM_BUFFER: usize = 16;
+
+/// Bytes of a request body copied at once
+const COPY_BYTES: usize = 8 * 1024;
+
+/// Hands every write over to `write_stream`, blocking while the buffer is full.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        executor::block_on(self.0.send(buf.to_vec()))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "connection closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write `response`, only its head if `head_only`, e.g. for a `HEAD` request. `Ok` with the bytes of its body.
+async fn write_response(
+    stream: &TcpStream,
+    response: &HttpResponse,
+    version: Version,
+    head_only: bool,
+    timeout: Duration,
+) -> io::Result<u64> {
+    let mut bytes = Vec::new();
+    match head_only {
+        true => response.write_head_to(version, &mut bytes)?,
+        false => response.write_to(version, &mut bytes)?,
+    }
+    let mut writer = stream;
+    io::timeout(timeout, async {
+        writer.write_all(&bytes).await?;
+        writer.flush().await
+    })
+    .await?;
+    Ok(if head_only || response.stream.is_some() { 0 } else { response.body.len() as u64 })
+}
+
+/// Answer `error` with its error page if the client can still get one, and give it back,
+/// with the status and the body bytes of the page if it was sent.
+async fn send_error(stream: &TcpStream, error: HttpError, version: Version, timeouts: &Timeouts) -> (HttpError, Option<(u16, u64)>) {
+    let Some(response) = error.to_response() else {
+        return (error, None);
+    };
+    let response = response.with_header("Connection", "close");
+    let written = write_response(stream, &response, version, false, timeouts.write).await;
+    if written.is_ok() {
+        linger(stream).await;
+    }
+    (error, Some((response.status, written.unwrap_or(0))))
+}
+
+/// Close our side and discard what the client is still sending for a moment, see `connection::linger`.
+async fn linger(stream: &TcpStream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let mut reader = stream;
+    let mut buf = [0; 4096];
+    let _ = io::timeout(LINGER_TIMEOUT, async {
+        while reader.read(&mut buf).await? > 0 {}
+        Ok(())
+    })
+    .await;
+}
+
+/// Wait until the next request starts to arrive, see `connection::wait_for_request`.
+async fn wait_for_request(reader: &mut BufReader<&TcpStream>, timeout: Duration, guard: &ConnectionGuard) -> io::Result<bool> {
+    if !reader.buffer().is_empty() {
+        return Ok(true); // pipelined
+    }
+    let expire_at = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= expire_at {
+            return Ok(false);
+        }
+        let slice = (expire_at - now).min(IDLE_POLL_INTERVAL);
+        match future::timeout(slice, futures::AsyncBufReadExt::fill_buf(reader)).await {
+            Ok(Ok(buf)) => return Ok(!buf.is_empty()),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                if guard.is_shutting_down() {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod async_connection_test_cases {
+    use std::io::{BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+    use crate::webapp::form::{parse_multipart, MultipartLimits};
+    use crate::webapp::server::ServerHandle;
+
+    /// Every response body is the request path, `/sleep` awaits 300ms and `/panic` panics.
+    /// `/stream` streams the request body back twice, `/upload` answers the lengths of the parts.
+    fn spawn_server(timeouts: Timeouts) -> ServerHandle {
+        spawn_observed_server(timeouts, Arc::new(|_: &Exchange| {}))
+    }
+
+    fn spawn_observed_server(timeouts: Timeouts, observer: Observer) -> ServerHandle {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        ServerHandle::spawn(listener, move |incoming| {
+            for (stream, guard) in incoming {
+                let (timeouts, observer) = (timeouts.clone(), Arc::clone(&observer));
+                task::spawn(async move {
+                    let stream = TcpStream::from(stream);
+                    let handler = |request: HttpRequest| async move {
+                        match request.path.as_str() {
+                            "/sleep" => task::sleep(Duration::from_millis(300)).await,
+                            "/panic" => panic!("handler panicked"),
+                            _ => {}
+                        }
+                        if request.path == "/upload" {
+                            let lengths = match parse_multipart(&request, &MultipartLimits::default()) {
+                                Ok(form) => form.parts.iter().map(|part| part.len().to_string()).collect::<Vec<_>>().join(","),
+                                Err(response) => return response,
+                            };
+                            return HttpResponse::new(200).with_body(lengths);
+                        }
+                        if request.path == "/stream" {
+                            let body = request.body;
+                            return HttpResponse::new(200).with_stream(StreamBody::new(move |writer, _| {
+                                writer.write_all(&body)?;
+                                writer.write_all(&body)
+                            }));
+                        }
+                        HttpResponse::new(200).with_body(request.path)
+                    };
+                    let _ = serve_connection_async(stream, &guard, &KeepAlive::default(), &timeouts, &Limits::default(), handler, &observer).await;
+                });
+            }
+        })
+        .unwrap()
+    }
+
+    fn get(server: &ServerHandle, raw: &str) -> (HttpResponse, usize) {
+        let mut client = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(raw.as_bytes()).unwrap();
+        let mut reader = BufReader::new(client);
+        let response = HttpResponse::read_from(&mut reader).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        (response, rest.len())
+    }
+
+    #[test]
+    pub fn test_pipelined_requests() {
+        let server = spawn_server(Timeouts::default());
+        let mut client = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 3\r\n\r\nxyzGET /c HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+        for path in ["/a", "/b", "/c"] {
+            assert_eq!(HttpResponse::read_from(&mut reader).unwrap().body, path.as_bytes());
+        }
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_chunked_bodies() {
+        let server = spawn_server(Timeouts::default());
+        let mut client = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        client
+            .write_all(b"POST /stream HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n1\r\nc\r\n0\r\nX-Sum: 1\r\n\r\n")
+            .unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+        let streamed = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(streamed.headers.get("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(streamed.body, b"abcabc");
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().body, b"/a");
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_observed_exchanges() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let observer: Observer = {
+            let seen = Arc::clone(&seen);
+            Arc::new(move |exchange: &Exchange| {
+                let path = exchange.request.map(|request| request.path.clone());
+                seen.lock().unwrap().push((path, exchange.status, exchange.bytes));
+            })
+        };
+        let server = spawn_observed_server(Timeouts::default(), observer);
+        let mut client = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"HEAD /head HTTP/1.1\r\n\r\nPOST /stream HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc").unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\nGET /bad HTTP/1.1\r\nNo colon\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(server.shutdown(Duration::from_secs(5)));
+
+        let error_page = HttpResponse::status_page(400).body.len() as u64;
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            *seen,
+            vec![
+                (Some("/head".to_string()), 200, 0),
+                // Two chunks of 3 bytes and the last chunk, framing included
+                (Some("/stream".to_string()), 200, 2 * 8 + 5),
+                (Some("/a".to_string()), 200, 2),
+                (Some("/bad".to_string()), 400, error_page),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_streamed_upload() {
+        let server = spawn_server(Timeouts::default());
+        let mut client = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+        // Beyond `max_body_bytes` together, each part within `max_part_bytes`
+        let part = vec![b'x'; 600_000];
+        for name in ["a", "b", "c"] {
+            let head = format!("--XyZ\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}.txt\"\r\n\r\n", name, name);
+            let content = [head.as_bytes(), &part, b"\r\n"].concat();
+            client.write_all(format!("{:x}\r\n", content.len()).as_bytes()).unwrap();
+            client.write_all(&content).unwrap();
+            client.write_all(b"\r\n").unwrap();
+        }
+        client.write_all(b"9\r\n--XyZ--\r\n\r\n0\r\n\r\n").unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().body, b"600000,600000,600000");
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().body, b"/a");
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_sleeping_handlers_run_concurrently() {
+        let server = spawn_server(Timeouts::default());
+        let started = Instant::now();
+        let clients: Vec<_> = (0..64)
+            .map(|_| {
+                let addr = server.local_addr();
+                thread::spawn(move || {
+                    let mut client = std::net::TcpStream::connect(addr).unwrap();
+                    client.write_all(b"GET /sleep HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+                    HttpResponse::read_from(&mut BufReader::new(client)).unwrap().status
+                })
+            })
+            .collect();
+        for client in clients {
+            assert_eq!(client.join().unwrap(), 200);
+        }
+        // 64 x 300ms one after the other would take about 20 seconds
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_error_responses() {
+        let server = spawn_server(Timeouts { read: Duration::from_millis(300), write: Duration::from_secs(1) });
+        for (raw, status) in [
+            ("GET /\r\n\r\n", 400),
+            ("GET /panic HTTP/1.1\r\n\r\n", 500),
+            ("GET / HTTP/1.1\r\nX-Slow: never ends", 408),
+            (&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000)), 414),
+            (&format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(101)), 431),
+        ] {
+            let (response, rest) = get(&server, raw);
+            assert_eq!(response.status, status, "{:?}", raw);
+            assert_eq!(response.headers.get("Connection"), Some("close"));
+            assert_eq!(rest, 0);
+        }
+        for raw in ["GET /panic HTTP/1.0\r\n\r\n", "GET / HTTP/1.0\r\nX-Slow: never ends"] {
+            let mut client = std::net::TcpStream::connect(server.local_addr()).unwrap();
+            client.write_all(raw.as_bytes()).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.0 "), "{:?}", response);
+        }
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+}
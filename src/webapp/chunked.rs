@@ -0,0 +1,159 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::webapp::error::HttpError;
+use crate::webapp::http::{read_headers, unexpected_eof, Headers, Limits};
+
+/// Longest chunk-size line we accept, chunk extensions included
+pub const MAX_CHUNK_LINE: usize = 1024;
+
+/// Writes a `Transfer-Encoding: chunked` body, every `write` becomes one chunk.
+///
+/// `finish` writes the last chunk. Dropped without it the body stays incomplete,
+/// so the client can tell a stream that broke off from one that ended.
+/// ```ignore
+/// let mut writer = ChunkedWriter::new(&mut stream);
+/// writer.write_all(b"hello")?; // 5\r\nhello\r\n
+/// writer.finish()?; // 0\r\n\r\n
+/// ```
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    /// Write the last chunk and give the inner writer back.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // One write per chunk, a socket sends it in one segment if it can
+        let mut chunk = Vec::with_capacity(buf.len() + 20);
+        chunk.extend_from_slice(format!("{:x}\r\n", buf.len()).as_bytes());
+        chunk.extend_from_slice(buf);
+        chunk.extend_from_slice(b"\r\n");
+        self.inner.write_all(&chunk)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read a chunked body and the trailer fields after it, RFC 9112 section 7.1.
+/// Fail with 413 as soon as the decoded body exceeds `limits.max_body_bytes`.
+pub fn read_chunked<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<(Vec<u8>, Headers), HttpError> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        reader.by_ref().take(MAX_CHUNK_LINE as u64 + 2).read_until(b'\n', &mut line)?;
+        let size = parse_chunk_line(&line)?;
+        if size == 0 {
+            let trailers = read_headers(reader, limits)?;
+            return Ok((body, trailers));
+        }
+        let start = body.len();
+        body.resize(chunk_end(start, size, limits)?, 0);
+        reader.read_exact(&mut body[start..])?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+        check_chunk_end(&crlf)?;
+    }
+}
+
+/// Size of the chunk announced by `line`, as read up to and including its `\n`.
+/// Extensions after `;` are ignored.
+pub(crate) fn parse_chunk_line(line: &[u8]) -> Result<usize, HttpError> {
+    if !line.ends_with(b"\n") {
+        return Err(match line.len() > MAX_CHUNK_LINE {
+            true => HttpError::BadRequest("chunk size line too long".to_string()),
+            false => unexpected_eof("connection closed in chunked body").into(),
+        });
+    }
+    let line = String::from_utf8_lossy(line);
+    let size = line.trim_end_matches(['\r', '\n']).split(';').next().unwrap_or("").trim();
+    // `from_str_radix` takes a leading `+`, the grammar doesn't
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(HttpError::BadRequest(format!("invalid chunk size: {:?}", size)));
+    }
+    usize::from_str_radix(size, 16).map_err(|_| HttpError::BadRequest(format!("chunk size out of range: {}", size)))
+}
+
+/// Body length once a chunk of `size` bytes is appended to `length`, 413 past the limit.
+pub(crate) fn chunk_end(length: usize, size: usize, limits: &Limits) -> Result<usize, HttpError> {
+    match length.checked_add(size) {
+        Some(end) if end <= limits.max_body_bytes => Ok(end),
+        _ => Err(HttpError::PayloadTooLarge { length: length.saturating_add(size), limit: limits.max_body_bytes }),
+    }
+}
+
+/// Chunk data is followed by CRLF, anything else means we lost track of the framing.
+pub(crate) fn check_chunk_end(crlf: &[u8; 2]) -> Result<(), HttpError> {
+    match crlf {
+        b"\r\n" => Ok(()),
+        _ => Err(HttpError::BadRequest("chunk data not followed by CRLF".to_string())),
+    }
+}
+
+#[cfg(test)]
+pub mod chunked_test_cases {
+    use super::*;
+
+    fn read(raw: &str, limits: &Limits) -> Result<(Vec<u8>, Headers), HttpError> {
+        read_chunked(&mut raw.as_bytes(), limits)
+    }
+
+    #[test]
+    pub fn test_write_chunks() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"hello").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(&[b'x'; 26]).unwrap();
+        let written = writer.finish().unwrap();
+        let expected = format!("5\r\nhello\r\n1a\r\n{}\r\n0\r\n\r\n", "x".repeat(26));
+        assert_eq!(String::from_utf8(written.clone()).unwrap(), expected);
+
+        // What we write, we read
+        let (body, trailers) = read_chunked(&mut written.as_slice(), &Limits::default()).unwrap();
+        assert_eq!(body, format!("hello{}", "x".repeat(26)).as_bytes());
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    pub fn test_read_chunks_and_trailers() {
+        let raw = "4;name=value\r\nWiki\r\nA\r\npedia in\r\n\r\n0\r\nExpires: never\r\nX-Checksum: abc\r\n\r\nGET / HTTP/1.1";
+        let mut reader = raw.as_bytes();
+        let (body, trailers) = read_chunked(&mut reader, &Limits::default()).unwrap();
+        assert_eq!(body, b"Wikipedia in\r\n");
+        assert_eq!(trailers.get("x-checksum"), Some("abc"));
+        assert_eq!(trailers.len(), 2);
+        // The next request is left alone
+        assert_eq!(reader, b"GET / HTTP/1.1");
+    }
+
+    #[test]
+    pub fn test_chunk_errors() {
+        let limits = Limits { max_body_bytes: 8, ..Limits::default() };
+        assert_eq!(read("zz\r\nhello\r\n0\r\n\r\n", &limits).unwrap_err().status(), Some(400));
+        assert_eq!(read("+5\r\nhello\r\n0\r\n\r\n", &limits).unwrap_err().status(), Some(400));
+        assert_eq!(read("5\r\nhelloXX0\r\n\r\n", &limits).unwrap_err().status(), Some(400));
+        assert_eq!(read("5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n", &limits).unwrap_err().status(), Some(413));
+        assert_eq!(read("ffffffffffffffffff\r\n", &limits).unwrap_err().status(), Some(400));
+        assert_eq!(read(&format!("1{}\r\n", "0".repeat(MAX_CHUNK_LINE)), &limits).unwrap_err().status(), Some(400));
+        // Closed mid-body: nobody left to answer
+        assert_eq!(read("5\r\nhel", &limits).unwrap_err().status(), None);
+        assert_eq!(read("5\r\nhello\r\n", &limits).unwrap_err().status(), None);
+    }
+}
@@ -0,0 +1,292 @@
+use std::io::{self, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression as Level;
+
+use crate::webapp::connection::StreamBody;
+use crate::webapp::http::{HttpRequest, HttpResponse};
+use crate::webapp::middleware::Middleware;
+
+/// Content codings we can produce, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// zlib stream, which is what HTTP calls `deflate`
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Best coding allowed by an `Accept-Encoding` header, `None` for identity.
+    ///
+    /// Honors q-values and `*`, ties go to gzip: `deflate;q=0.5, *` => gzip, `gzip;q=0` => deflate.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let mut gzip = None;
+        let mut deflate = None;
+        let mut any = None;
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q=").or_else(|| param.trim().strip_prefix("Q=")))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match coding.as_str() {
+                "gzip" | "x-gzip" => gzip = Some(q),
+                "deflate" => deflate = Some(q),
+                "*" => any = Some(q),
+                _ => {}
+            }
+        }
+        let gzip = gzip.or(any).unwrap_or(0.0);
+        let deflate = deflate.or(any).unwrap_or(0.0);
+        if gzip > 0.0 && gzip >= deflate {
+            Some(Encoding::Gzip)
+        } else if deflate > 0.0 {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    /// Compress a whole body.
+    pub fn encode(&self, body: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        let buffer = Vec::with_capacity(body.len() / 2);
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(buffer, Level::new(level));
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(buffer, Level::new(level));
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Compress a streamed body on its way to the client.
+    /// Every `flush` of the stream, e.g. after an SSE event, flushes the encoder too.
+    pub fn encode_stream(self, body: StreamBody, level: u32) -> StreamBody {
+        let long_lived = body.is_long_lived();
+        StreamBody::new(move |writer, shutdown| match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(writer, Level::new(level));
+                body.run(&mut encoder, shutdown)?;
+                encoder.finish()?.flush()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(writer, Level::new(level));
+                body.run(&mut encoder, shutdown)?;
+                encoder.finish()?.flush()
+            }
+        })
+        .with_long_lived(long_lived)
+    }
+}
+
+/// Whether compressing a `Content-Type` is worth it: not for media and archives, which already are.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    match mime.split_once('/') {
+        Some(("image", subtype)) => subtype == "svg+xml" || subtype == "x-icon" || subtype == "bmp",
+        Some(("audio" | "video" | "font", _)) => false,
+        Some(("application", subtype)) => !matches!(
+            subtype,
+            "gzip" | "x-gzip" | "zip" | "x-7z-compressed" | "x-bzip2" | "x-xz" | "zstd" | "pdf" | "octet-stream" | "wasm"
+        ),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Compresses responses with gzip or deflate when the client accepts it, negotiated with `Accept-Encoding`.
+///
+/// Fixed bodies are compressed from `min_bytes` on, streamed bodies always.
+/// Responses without a compressible `Content-Type`, partial content and upgrades are left alone.
+/// ```ignore
+/// Pipeline::new(router).with(AccessLog::new(format)).with(Compression::new().with_min_bytes(512))
+/// ```
+pub struct Compression {
+    min_bytes: usize,
+    level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Compression {
+    /// Bodies from 1 KiB on, at level 6 like gzip's default.
+    pub fn new() -> Self {
+        Compression { min_bytes: 1024, level: 6 }
+    }
+
+    /// Smaller bodies are sent as they are: headers would eat most of the gain.
+    pub fn with_min_bytes(mut self, min_bytes: usize) -> Self {
+        self.min_bytes = min_bytes;
+        self
+    }
+
+    /// 1 (fastest) to 9 (smallest)
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = level.clamp(1, 9);
+        self
+    }
+
+    fn applies_to(&self, response: &HttpResponse) -> bool {
+        matches!(response.status, 200..=299)
+            && response.status != 204
+            && response.status != 206
+            && response.upgrade.is_none()
+            && !response.headers.contains("Content-Encoding")
+            && !response.headers.contains("Content-Range")
+            && !response.headers.contains_token("Cache-Control", "no-transform")
+            && response.headers.get("Content-Type").is_some_and(is_compressible)
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        if !self.applies_to(response) {
+            return;
+        }
+        // Whatever we decide, the response depends on `Accept-Encoding` from now on
+        if !response.headers.contains_token("Vary", "Accept-Encoding") {
+            response.headers.append("Vary", "Accept-Encoding");
+        }
+        let encoding = match request.header("Accept-Encoding").and_then(Encoding::negotiate) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        if let Some(stream) = response.stream.take() {
+            response.stream = Some(encoding.encode_stream(stream, self.level));
+        } else if response.body.len() >= self.min_bytes {
+            match encoding.encode(&response.body, self.level) {
+                Ok(body) if body.len() < response.body.len() => response.body = body,
+                Ok(_) => return,
+                Err(e) => {
+                    tracing::warn!("Failed to compress response: {}", e);
+                    return;
+                }
+            }
+        } else {
+            return;
+        }
+        response.headers.insert("Content-Encoding", encoding.as_str());
+        // The encoded bytes differ, so a strong validator of the identity body no longer holds
+        if let Some(etag) = response.headers.get("ETag").filter(|etag| !etag.starts_with("W/")) {
+            let weak = format!("W/{}", etag);
+            response.headers.insert("ETag", weak);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod compression_test_cases {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use super::*;
+    use crate::webapp::server::ShutdownSignal;
+
+    fn request(accept_encoding: Option<&str>) -> HttpRequest {
+        let header = accept_encoding.map(|value| format!("Accept-Encoding: {}\r\n", value)).unwrap_or_default();
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", header);
+        HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn text(len: usize) -> HttpResponse {
+        HttpResponse::new(200).with_header("Content-Type", "text/plain; charset=utf-8").with_body("a".repeat(len))
+    }
+
+    #[test]
+    pub fn test_negotiate() {
+        assert_eq!(Encoding::negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(Encoding::negotiate("deflate;q=0.5, *"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("*, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate("br, *;q=0"), None);
+        assert_eq!(Encoding::negotiate(""), None);
+    }
+
+    #[test]
+    pub fn test_compress_fixed_body() {
+        let compression = Compression::new().with_min_bytes(100);
+
+        let mut response = text(1000).with_header("ETag", "\"v1\"");
+        compression.after(&request(Some("gzip")), &mut response);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("ETag"), Some("W/\"v1\""));
+        let mut body = String::new();
+        GzDecoder::new(response.body.as_slice()).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "a".repeat(1000));
+
+        let mut response = text(1000);
+        compression.after(&request(Some("deflate")), &mut response);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("deflate"));
+        let mut body = String::new();
+        ZlibDecoder::new(response.body.as_slice()).read_to_string(&mut body).unwrap();
+        assert_eq!(body.len(), 1000);
+
+        // Too small, or not accepted: identity, still varying on the header
+        for (response, accept) in [(text(99), Some("gzip")), (text(1000), None)] {
+            let mut response = response;
+            compression.after(&request(accept), &mut response);
+            assert!(!response.headers.contains("Content-Encoding"));
+            assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        }
+    }
+
+    #[test]
+    pub fn test_skip_incompressible() {
+        let compression = Compression::new().with_min_bytes(0);
+        let responses = [
+            HttpResponse::new(200).with_header("Content-Type", "image/png").with_body(vec![0; 2000]),
+            HttpResponse::new(200).with_header("Content-Type", "application/gzip").with_body(vec![0; 2000]),
+            HttpResponse::new(200).with_body(vec![0; 2000]),
+            HttpResponse { status: 206, ..text(2000) }.with_header("Content-Range", "bytes 0-1999/4000"),
+            text(2000).with_header("Content-Encoding", "br"),
+        ];
+        for mut response in responses {
+            let before = response.body.clone();
+            compression.after(&request(Some("gzip")), &mut response);
+            assert_eq!(response.body, before);
+            assert_ne!(response.headers.get("Content-Encoding"), Some("gzip"));
+        }
+        assert!(is_compressible("image/svg+xml"));
+        assert!(is_compressible("application/json"));
+        assert!(!is_compressible("video/mp4"));
+    }
+
+    #[test]
+    pub fn test_compress_stream() {
+        let stream = StreamBody::new(|writer, _| {
+            writer.write_all(b"data: one\n\n")?;
+            writer.flush()?;
+            writer.write_all(b"data: two\n\n")
+        });
+        let mut response =
+            HttpResponse::new(200).with_header("Content-Type", "text/event-stream").with_stream(stream);
+        Compression::new().after(&request(Some("gzip")), &mut response);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+
+        let mut written = Vec::new();
+        response.stream.take().unwrap().run(&mut written, &ShutdownSignal::never()).unwrap();
+        let mut body = String::new();
+        GzDecoder::new(written.as_slice()).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "data: one\n\ndata: two\n\n");
+    }
+}
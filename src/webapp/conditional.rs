@@ -0,0 +1,272 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+use crate::webapp::http::{http_date, parse_http_date, HttpRequest, HttpResponse, Method};
+use crate::webapp::middleware::Middleware;
+
+/// Strong validator of `body`: the same bytes, and only those, get the same tag.
+pub fn strong_etag(body: &[u8]) -> String {
+    format!("\"{}\"", URL_SAFE_NO_PAD.encode(Sha1::digest(body)))
+}
+
+/// Weak validator of `body`, for bodies that may change in ways that don't matter, e.g. a timestamp.
+pub fn weak_etag(body: &[u8]) -> String {
+    format!("W/{}", strong_etag(body))
+}
+
+/// Outcome of the conditional headers of a request, RFC 9110 section 13.2.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    /// Go on and answer the request
+    Passed,
+    /// 304, the client's copy is still good
+    NotModified,
+    /// 412, the resource isn't in the state the client expects, e.g. a lost update
+    Failed,
+}
+
+impl Precondition {
+    /// Evaluate `If-Match`, `If-Unmodified-Since`, `If-None-Match` and `If-Modified-Since`, in that order,
+    /// against the current `etag` and `modified` time of the resource. `None` means the resource has none,
+    /// e.g. it doesn't exist yet.
+    pub fn evaluate(request: &HttpRequest, etag: Option<&str>, modified: Option<SystemTime>) -> Precondition {
+        let safe = matches!(request.method, Method::Get | Method::Head);
+        if let Some(if_match) = request.header("If-Match") {
+            // Strong comparison: writes need the exact bytes the client saw
+            if !matches_any(if_match, etag, strong_eq) {
+                return Precondition::Failed;
+            }
+        } else if let (Some(since), Some(modified)) = (request.header("If-Unmodified-Since").and_then(parse_http_date), modified) {
+            if truncate_to_secs(modified) > since {
+                return Precondition::Failed;
+            }
+        }
+        if let Some(if_none_match) = request.header("If-None-Match") {
+            if matches_any(if_none_match, etag, |tag, etag| weak(tag) == weak(etag)) {
+                return if safe { Precondition::NotModified } else { Precondition::Failed };
+            }
+        } else if let (true, Some(since), Some(modified)) =
+            (safe, request.header("If-Modified-Since").and_then(parse_http_date), modified)
+        {
+            if truncate_to_secs(modified) <= since {
+                return Precondition::NotModified;
+            }
+        }
+        Precondition::Passed
+    }
+
+    /// `Err` with the 304 or 412 response to send instead of running the request.
+    /// A handler changing a resource calls it with the resource's current validators first.
+    /// ```ignore
+    /// Precondition::check(request, Some(&strong_etag(&current)), None)?;
+    /// ```
+    pub fn check(request: &HttpRequest, etag: Option<&str>, modified: Option<SystemTime>) -> Result<(), HttpResponse> {
+        match Precondition::evaluate(request, etag, modified) {
+            Precondition::Passed => Ok(()),
+            Precondition::NotModified => {
+                let mut response = HttpResponse::new(304);
+                if let Some(etag) = etag {
+                    response.headers.insert("ETag", etag);
+                }
+                if let Some(modified) = modified {
+                    response.headers.insert("Last-Modified", http_date(modified));
+                }
+                Err(response)
+            }
+            Precondition::Failed => Err(HttpResponse::status_page(412)),
+        }
+    }
+}
+
+/// Whether a list of entity tags like `"a", W/"b"` (or `*`) names the current `etag`
+fn matches_any(list: &str, etag: Option<&str>, eq: impl Fn(&str, &str) -> bool) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    list.split(',').map(str::trim).any(|tag| tag == "*" || eq(tag, etag))
+}
+
+/// Strong comparison of two entity tags, RFC 9110 section 8.8.3.2: weak tags never match.
+pub(crate) fn strong_eq(tag: &str, etag: &str) -> bool {
+    !tag.starts_with("W/") && !etag.starts_with("W/") && tag == etag
+}
+
+fn weak(tag: &str) -> &str {
+    tag.trim_start_matches("W/")
+}
+
+/// HTTP dates have no sub-second part.
+pub(crate) fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Conditional `GET` and `HEAD`: tags generated bodies with an `ETag`, and answers 304 when the client's
+/// copy is still good or 412 when its `If-Match` fails.
+///
+/// Requests changing a resource are left to their handler, which knows the current state of it:
+/// see `Precondition::check`.
+///
+/// Goes ahead of `Compression`: it then sees the response as it's sent, with the weakened `ETag` and
+/// `Vary` of an encoded body, and a 304 carries the same validators as the 200 the client cached.
+/// ```ignore
+/// Pipeline::new(router).with(Conditional::new()).with(Compression::new())
+/// ```
+pub struct Conditional {
+    weak: bool,
+}
+
+impl Default for Conditional {
+    fn default() -> Self {
+        Conditional::new()
+    }
+}
+
+impl Conditional {
+    /// Strong `ETag`s
+    pub fn new() -> Self {
+        Conditional { weak: false }
+    }
+
+    /// Generate weak `ETag`s, they still give 304s but never pass an `If-Match`
+    pub fn with_weak_etags(mut self, weak: bool) -> Self {
+        self.weak = weak;
+        self
+    }
+}
+
+impl Middleware for Conditional {
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        // A streamed body is unknown until it's sent
+        if !matches!(request.method, Method::Get | Method::Head) || response.status != 200 || response.stream.is_some() {
+            return;
+        }
+        if !response.headers.contains("ETag") && !response.headers.contains_token("Cache-Control", "no-store") {
+            let etag = if self.weak { weak_etag(&response.body) } else { strong_etag(&response.body) };
+            response.headers.insert("ETag", etag);
+        }
+        let etag = response.headers.get("ETag").map(str::to_string);
+        let modified = response.headers.get("Last-Modified").and_then(parse_http_date);
+        match Precondition::evaluate(request, etag.as_deref(), modified) {
+            Precondition::Passed => {}
+            // Keep the headers of the 200, `ETag`, `Cache-Control` and the like refresh the client's copy
+            Precondition::NotModified => {
+                response.status = 304;
+                response.body.clear();
+            }
+            Precondition::Failed => *response = HttpResponse::status_page(412),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod conditional_test_cases {
+    use super::*;
+    use crate::webapp::compression::Compression;
+    use crate::webapp::middleware::Pipeline;
+    use crate::webapp::router::Router;
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        let headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+        let raw = format!("{} / HTTP/1.1\r\n{}\r\n", method, headers);
+        HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn app(conditional: Conditional) -> Pipeline {
+        let router = Router::new()
+            .get("/", |_, _| HttpResponse::html("hello").with_header("Cache-Control", "max-age=60"))
+            .post("/", |_, _| HttpResponse::new(201));
+        Pipeline::new(router).with(conditional)
+    }
+
+    #[test]
+    pub fn test_generated_etags() {
+        let etag = strong_etag(b"hello");
+        assert_eq!(etag, strong_etag(b"hello"));
+        assert_ne!(etag, strong_etag(b"hello!"));
+        assert_eq!(weak_etag(b"hello"), format!("W/{}", etag));
+
+        let response = app(Conditional::new()).handle(&mut request("GET", &[]));
+        assert_eq!(response.headers.get("ETag"), Some(etag.as_str()));
+        let response = app(Conditional::new().with_weak_etags(true)).handle(&mut request("HEAD", &[]));
+        assert_eq!(response.headers.get("ETag"), Some(weak_etag(b"hello").as_str()));
+        // Not for writes
+        assert!(!app(Conditional::new()).handle(&mut request("POST", &[])).headers.contains("ETag"));
+    }
+
+    #[test]
+    pub fn test_not_modified() {
+        let app = app(Conditional::new());
+        let etag = strong_etag(b"hello");
+        let response = app.handle(&mut request("GET", &[("If-None-Match", &format!("\"x\", W/{}", etag))]));
+        assert_eq!(response.status, 304);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get("ETag"), Some(etag.as_str()));
+        assert_eq!(response.headers.get("Cache-Control"), Some("max-age=60"));
+        assert_eq!(app.handle(&mut request("GET", &[("If-None-Match", "*")])).status, 304);
+        assert_eq!(app.handle(&mut request("GET", &[("If-None-Match", "\"x\"")])).status, 200);
+
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        let page = |headers: &[(&str, &str)]| Precondition::evaluate(&request("GET", headers), None, Some(now));
+        assert_eq!(page(&[("If-Modified-Since", &http_date(now))]), Precondition::NotModified);
+        assert_eq!(page(&[("If-Modified-Since", &http_date(now - hour))]), Precondition::Passed);
+        assert_eq!(page(&[("If-Modified-Since", "garbage")]), Precondition::Passed);
+    }
+
+    #[test]
+    pub fn test_preconditions() {
+        let etag = strong_etag(b"v1");
+        let put = |headers: &[(&str, &str)]| Precondition::evaluate(&request("PUT", headers), Some(&etag), None);
+        assert_eq!(put(&[]), Precondition::Passed);
+        assert_eq!(put(&[("If-Match", &etag)]), Precondition::Passed);
+        assert_eq!(put(&[("If-Match", "*")]), Precondition::Passed);
+        assert_eq!(put(&[("If-Match", &strong_etag(b"v0"))]), Precondition::Failed);
+        // Weak tags never pass `If-Match`
+        assert_eq!(put(&[("If-Match", &format!("W/{}", etag))]), Precondition::Failed);
+        // `If-None-Match: *` creates only what doesn't exist yet
+        assert_eq!(put(&[("If-None-Match", "*")]), Precondition::Failed);
+        assert_eq!(Precondition::evaluate(&request("PUT", &[("If-None-Match", "*")]), None, None), Precondition::Passed);
+        assert_eq!(Precondition::evaluate(&request("PUT", &[("If-Match", "*")]), None, None), Precondition::Failed);
+
+        let modified = UNIX_EPOCH + Duration::from_secs(784111777);
+        let since = |date: SystemTime| Precondition::evaluate(&request("DELETE", &[("If-Unmodified-Since", &http_date(date))]), None, Some(modified));
+        assert_eq!(since(modified), Precondition::Passed);
+        assert_eq!(since(modified - Duration::from_secs(1)), Precondition::Failed);
+
+        assert_eq!(Precondition::check(&request("PUT", &[("If-Match", "\"old\"")]), Some(&etag), None).unwrap_err().status, 412);
+        // The middleware checks `If-Match` of reads too
+        assert_eq!(app(Conditional::new()).handle(&mut request("GET", &[("If-Match", "\"old\"")])).status, 412);
+    }
+
+    #[test]
+    pub fn test_with_compression() {
+        let router = Router::new()
+            .get("/", |_, _| HttpResponse::html("hello ".repeat(100)))
+            .get("/tagged", |_, _| HttpResponse::html("hello ".repeat(100)).with_header("ETag", "\"x\""));
+        let app = Pipeline::new(router).with(Conditional::new()).with(Compression::new().with_min_bytes(0));
+        let get = |target: &str, headers: &[(&str, &str)]| {
+            let headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+            let raw = format!("GET {} HTTP/1.1\r\nAccept-Encoding: gzip\r\n{}\r\n", target, headers);
+            app.handle(&mut HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap())
+        };
+
+        for target in ["/", "/tagged"] {
+            let cached = get(target, &[]);
+            assert_eq!(cached.headers.get("Content-Encoding"), Some("gzip"));
+            let etag = cached.headers.get("ETag").unwrap();
+            // The 304 refreshes the cached 200 with its own validators
+            let response = get(target, &[("If-None-Match", etag)]);
+            assert_eq!(response.status, 304, "{}", target);
+            assert!(response.body.is_empty());
+            assert_eq!(response.headers.get("ETag"), Some(etag));
+            assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        }
+        // The tag of the handler is weakened with the encoding, and no longer passes `If-Match`
+        assert_eq!(get("/tagged", &[]).headers.get("ETag"), Some("W/\"x\""));
+        assert_eq!(get("/tagged", &[("If-Match", "\"x\"")]).status, 412);
+    }
+}
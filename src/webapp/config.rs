@@ -0,0 +1,519 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::webapp::access_log::AccessLogFormat;
+use crate::webapp::connection::{KeepAlive, Timeouts};
+use crate::webapp::cors::{AllowedOrigin, Cors};
+use crate::webapp::form::MultipartLimits;
+use crate::webapp::http::{is_token, Limits, Method};
+use crate::webapp::rate_limit::ConnectionLimiter;
+
+/// Prefix of the environment variables overriding the config file, e.g. `WEBAPP_BIND=0.0.0.0:80`
+pub const ENV_PREFIX: &str = "WEBAPP_";
+
+/// Which demo WebServer `main` starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMode {
+    SingleThread,
+    MultiThreads,
+    ThreadPool,
+    /// async-std tasks
+    Async,
+}
+
+impl FromStr for ServerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single_thread" => Ok(ServerMode::SingleThread),
+            "multi_threads" => Ok(ServerMode::MultiThreads),
+            "thread_pool" => Ok(ServerMode::ThreadPool),
+            "async" => Ok(ServerMode::Async),
+            _ => Err(format!("unknown mode {:?}, expected single_thread, multi_threads, thread_pool or async", s)),
+        }
+    }
+}
+
+/// WebServer settings. Missing fields in the JSON file take the default value.
+/// ```json
+/// { "bind": "0.0.0.0:8080", "mode": "thread_pool", "workers": 8 }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub mode: ServerMode,
+    /// Worker threads of the `thread_pool` mode
+    pub workers: usize,
+    /// Accepted connections waiting for a worker in the `thread_pool` mode
+    pub queue_depth: usize,
+    /// Close keep-alive connections idle for longer
+    pub idle_timeout_secs: u64,
+    pub max_requests_per_connection: usize,
+    /// How long `shutdown` waits for in-flight requests
+    pub shutdown_timeout_secs: u64,
+    /// The whole request must arrive within this time once it started, otherwise 408
+    pub read_timeout_secs: u64,
+    /// Give up on clients not reading their response
+    pub write_timeout_secs: u64,
+    pub max_body_bytes: usize,
+    /// Longest request line and largest header section
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    /// Parts of a `multipart/form-data` body
+    pub max_form_parts: usize,
+    /// Largest part of a `multipart/form-data` body, files included
+    pub max_part_bytes: usize,
+    /// Largest `multipart/form-data` body, read as it arrives rather than within `max_body_bytes`
+    pub max_upload_bytes: usize,
+    /// Served under `/static`
+    pub static_dir: PathBuf,
+    /// Pages rendered by `TemplateEngine`
+    pub template_dir: PathBuf,
+    /// `common`, `json` or `off`
+    pub access_log: AccessLogFormat,
+    /// Requests per second allowed per client on average, `0` disables rate limiting
+    pub rate_limit_per_sec: f64,
+    /// Requests a client may send at once
+    pub rate_limit_burst: u32,
+    /// Proxies trusted to tell the client IP in `rate_limit_header`
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Header listing the client and proxy addresses, e.g. `X-Real-IP` behind some proxies
+    pub rate_limit_header: String,
+    /// How often the buckets of idle clients are dropped
+    pub rate_limit_sweep_secs: u64,
+    /// Open connections allowed per client IP, checked on accept. `0` for no limit
+    pub max_connections_per_ip: usize,
+    /// Sessions expire after this time without change
+    pub session_ttl_secs: u64,
+    /// Keep sessions as files in this directory, in memory if `null`
+    pub session_dir: Option<PathBuf>,
+    /// Name of the cookie holding the session ID
+    pub session_cookie_name: String,
+    /// Mark the session cookie `Secure`, for servers behind an HTTPS proxy
+    pub session_secure: bool,
+    /// gzip/deflate level from 1 (fastest) to 9 (smallest), `0` disables compression
+    pub compression_level: u32,
+    /// Smaller bodies are sent uncompressed
+    pub compression_min_bytes: usize,
+    /// Origins allowed to call the server from a browser: `*`, `https://app.example.com`,
+    /// `https://*.example.com` or a regex after `~`. Empty disables CORS.
+    pub cors_origins: Vec<String>,
+    pub cors_methods: Vec<String>,
+    /// Request headers pages may send, `*` for any
+    pub cors_headers: Vec<String>,
+    /// Let allowed origins, except `*`, send cookies
+    pub cors_credentials: bool,
+    /// How long browsers cache a preflight answer
+    pub cors_max_age_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "127.0.0.1:8080".to_string(),
+            mode: ServerMode::ThreadPool,
+            workers: 3,
+            queue_depth: 16,
+            idle_timeout_secs: 5,
+            max_requests_per_connection: 100,
+            shutdown_timeout_secs: 10,
+            read_timeout_secs: 10,
+            write_timeout_secs: 10,
+            max_body_bytes: 1024 * 1024,
+            max_header_bytes: 8 * 1024,
+            max_headers: 100,
+            max_form_parts: 32,
+            max_part_bytes: 1024 * 1024,
+            max_upload_bytes: 16 * 1024 * 1024,
+            static_dir: PathBuf::from("static"),
+            template_dir: PathBuf::from("templates"),
+            access_log: AccessLogFormat::Common,
+            rate_limit_per_sec: 20.0,
+            rate_limit_burst: 40,
+            trusted_proxies: Vec::new(),
+            rate_limit_header: "X-Forwarded-For".to_string(),
+            rate_limit_sweep_secs: 60,
+            max_connections_per_ip: 16,
+            session_ttl_secs: 30 * 60,
+            session_dir: None,
+            session_cookie_name: "sid".to_string(),
+            session_secure: false,
+            compression_level: 6,
+            compression_min_bytes: 1024,
+            cors_origins: Vec::new(),
+            cors_methods: vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()],
+            cors_headers: vec!["Content-Type".to_string()],
+            cors_credentials: false,
+            cors_max_age_secs: 600,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Command line: `rs-tutorial [--config <file>]`, then apply `WEBAPP_*` environment variables.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<ServerConfig, ConfigError> {
+        let mut path = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" | "--config" => {
+                    let value = args.next().ok_or_else(|| ConfigError::Usage(format!("{} needs a file path", arg)))?;
+                    path = Some(PathBuf::from(value));
+                }
+                _ => match arg.strip_prefix("--config=") {
+                    Some(value) => path = Some(PathBuf::from(value)),
+                    None => return Err(ConfigError::Usage(format!("unknown argument {:?}", arg))),
+                },
+            }
+        }
+        let mut config = match path {
+            Some(path) => ServerConfig::from_file(&path)?,
+            None => ServerConfig::default(),
+        };
+        config.apply_env(env::vars())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<ServerConfig, ConfigError> {
+        let json = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Override fields from `WEBAPP_BIND`, `WEBAPP_MODE`, `WEBAPP_WORKERS`, ...
+    /// Every field has a variable named after it in upper case.
+    pub fn apply_env<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) -> Result<(), ConfigError> {
+        for (name, value) in vars {
+            let Some(field) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            match field {
+                "BIND" => self.bind = value,
+                "MODE" => self.mode = parse_env(&name, &value)?,
+                "WORKERS" => self.workers = parse_env(&name, &value)?,
+                "QUEUE_DEPTH" => self.queue_depth = parse_env(&name, &value)?,
+                "IDLE_TIMEOUT_SECS" => self.idle_timeout_secs = parse_env(&name, &value)?,
+                "MAX_REQUESTS_PER_CONNECTION" => self.max_requests_per_connection = parse_env(&name, &value)?,
+                "SHUTDOWN_TIMEOUT_SECS" => self.shutdown_timeout_secs = parse_env(&name, &value)?,
+                "READ_TIMEOUT_SECS" => self.read_timeout_secs = parse_env(&name, &value)?,
+                "WRITE_TIMEOUT_SECS" => self.write_timeout_secs = parse_env(&name, &value)?,
+                "MAX_BODY_BYTES" => self.max_body_bytes = parse_env(&name, &value)?,
+                "MAX_HEADER_BYTES" => self.max_header_bytes = parse_env(&name, &value)?,
+                "MAX_HEADERS" => self.max_headers = parse_env(&name, &value)?,
+                "MAX_FORM_PARTS" => self.max_form_parts = parse_env(&name, &value)?,
+                "MAX_PART_BYTES" => self.max_part_bytes = parse_env(&name, &value)?,
+                "MAX_UPLOAD_BYTES" => self.max_upload_bytes = parse_env(&name, &value)?,
+                "STATIC_DIR" => self.static_dir = PathBuf::from(value),
+                "TEMPLATE_DIR" => self.template_dir = PathBuf::from(value),
+                "ACCESS_LOG" => self.access_log = parse_env(&name, &value)?,
+                "RATE_LIMIT_PER_SEC" => self.rate_limit_per_sec = parse_env(&name, &value)?,
+                "RATE_LIMIT_BURST" => self.rate_limit_burst = parse_env(&name, &value)?,
+                // Comma separated, e.g. `10.0.0.1,10.0.0.2`
+                "TRUSTED_PROXIES" => {
+                    self.trusted_proxies = split_list(&value).map(|ip| parse_env(&name, ip)).collect::<Result<_, _>>()?
+                }
+                "RATE_LIMIT_HEADER" => self.rate_limit_header = value,
+                "RATE_LIMIT_SWEEP_SECS" => self.rate_limit_sweep_secs = parse_env(&name, &value)?,
+                "MAX_CONNECTIONS_PER_IP" => self.max_connections_per_ip = parse_env(&name, &value)?,
+                "SESSION_TTL_SECS" => self.session_ttl_secs = parse_env(&name, &value)?,
+                // Empty for in-memory sessions
+                "SESSION_DIR" => self.session_dir = Some(PathBuf::from(value)).filter(|dir| !dir.as_os_str().is_empty()),
+                "SESSION_COOKIE_NAME" => self.session_cookie_name = value,
+                "SESSION_SECURE" => self.session_secure = parse_env(&name, &value)?,
+                "COMPRESSION_LEVEL" => self.compression_level = parse_env(&name, &value)?,
+                "COMPRESSION_MIN_BYTES" => self.compression_min_bytes = parse_env(&name, &value)?,
+                // Comma separated too
+                "CORS_ORIGINS" => self.cors_origins = split_list(&value).map(str::to_string).collect(),
+                "CORS_METHODS" => self.cors_methods = split_list(&value).map(str::to_string).collect(),
+                "CORS_HEADERS" => self.cors_headers = split_list(&value).map(str::to_string).collect(),
+                "CORS_CREDENTIALS" => self.cors_credentials = parse_env(&name, &value)?,
+                "CORS_MAX_AGE_SECS" => self.cors_max_age_secs = parse_env(&name, &value)?,
+                _ => eprintln!("Ignore unknown environment variable {}", name),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.bind.is_empty() {
+            return Err(ConfigError::Invalid("bind must not be empty".to_string()));
+        }
+        if self.workers == 0 {
+            return Err(ConfigError::Invalid("workers must be greater than 0".to_string()));
+        }
+        // 0 would close every keep-alive connection before its next request could arrive
+        if self.idle_timeout_secs == 0 {
+            return Err(ConfigError::Invalid("idle_timeout_secs must be greater than 0".to_string()));
+        }
+        if self.max_requests_per_connection == 0 {
+            return Err(ConfigError::Invalid("max_requests_per_connection must be greater than 0".to_string()));
+        }
+        if self.read_timeout_secs == 0 || self.write_timeout_secs == 0 {
+            return Err(ConfigError::Invalid("read_timeout_secs and write_timeout_secs must be greater than 0".to_string()));
+        }
+        if !(self.rate_limit_per_sec >= 0.0 && self.rate_limit_per_sec.is_finite()) {
+            return Err(ConfigError::Invalid("rate_limit_per_sec must be 0 or a positive number".to_string()));
+        }
+        if self.rate_limit_per_sec > 0.0 && self.rate_limit_burst == 0 {
+            return Err(ConfigError::Invalid("rate_limit_burst must be greater than 0".to_string()));
+        }
+        if !is_token(&self.rate_limit_header) {
+            return Err(ConfigError::Invalid(format!("invalid rate_limit_header {:?}", self.rate_limit_header)));
+        }
+        if self.rate_limit_sweep_secs == 0 {
+            return Err(ConfigError::Invalid("rate_limit_sweep_secs must be greater than 0".to_string()));
+        }
+        if self.session_ttl_secs == 0 {
+            return Err(ConfigError::Invalid("session_ttl_secs must be greater than 0".to_string()));
+        }
+        if !is_token(&self.session_cookie_name) {
+            return Err(ConfigError::Invalid(format!("invalid session_cookie_name {:?}", self.session_cookie_name)));
+        }
+        if self.compression_level > 9 {
+            return Err(ConfigError::Invalid("compression_level must be between 0 and 9".to_string()));
+        }
+        self.cors()?;
+        Ok(())
+    }
+
+    pub fn keep_alive(&self) -> KeepAlive {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(self.idle_timeout_secs),
+            max_requests: self.max_requests_per_connection,
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_body_bytes: self.max_body_bytes,
+            max_header_bytes: self.max_header_bytes,
+            max_headers: self.max_headers,
+            max_upload_bytes: self.max_upload_bytes,
+            multipart: self.multipart_limits(),
+        }
+    }
+
+    pub fn multipart_limits(&self) -> MultipartLimits {
+        MultipartLimits {
+            max_parts: self.max_form_parts,
+            max_part_bytes: self.max_part_bytes,
+            ..MultipartLimits::default()
+        }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            read: Duration::from_secs(self.read_timeout_secs),
+            write: Duration::from_secs(self.write_timeout_secs),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn rate_limit_sweep(&self) -> Duration {
+        Duration::from_secs(self.rate_limit_sweep_secs)
+    }
+
+    /// The per-IP connection cap, trusted proxies aren't capped
+    pub fn connection_limiter(&self) -> ConnectionLimiter {
+        ConnectionLimiter::new(self.max_connections_per_ip).with_trusted_proxies(self.trusted_proxies.clone())
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl_secs)
+    }
+
+    /// The CORS policy, `None` without `cors_origins`
+    pub fn cors(&self) -> Result<Option<Cors>, ConfigError> {
+        if self.cors_origins.is_empty() {
+            return Ok(None);
+        }
+        let mut cors = Cors::new()
+            .with_headers(self.cors_headers.clone())
+            .with_credentials(self.cors_credentials)
+            .with_max_age(Duration::from_secs(self.cors_max_age_secs));
+        for origin in &self.cors_origins {
+            let allowed: AllowedOrigin =
+                origin.parse().map_err(|e| ConfigError::Invalid(format!("invalid cors_origins entry {:?}: {}", origin, e)))?;
+            cors = cors.allow_origin(allowed);
+        }
+        let methods = self
+            .cors_methods
+            .iter()
+            .map(|method| method.parse::<Method>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| ConfigError::Invalid(format!("invalid cors_methods: {}", e)))?;
+        Ok(Some(cors.with_methods(methods)))
+    }
+}
+
+/// Entries of a comma separated list, e.g. `10.0.0.1, 10.0.0.2`
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|entry| !entry.is_empty())
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Env { name: name.to_string(), value: value.to_string() })
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Usage(String),
+    Io(PathBuf, io::Error),
+    Json(serde_json::Error),
+    Env { name: String, value: String },
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Usage(message) => write!(f, "{}\nUsage: rs-tutorial [--config <file>]", message),
+            ConfigError::Io(path, e) => write!(f, "failed to read config {}: {}", path.display(), e),
+            ConfigError::Json(e) => write!(f, "invalid config: {}", e),
+            ConfigError::Env { name, value } => write!(f, "invalid value {:?} of {}", value, name),
+            ConfigError::Invalid(message) => write!(f, "invalid config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(value: serde_json::Error) -> Self {
+        ConfigError::Json(value)
+    }
+}
+
+#[cfg(test)]
+pub mod config_test_cases {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    pub fn test_partial_json() {
+        let config: ServerConfig = serde_json::from_str(r#"{ "bind": "0.0.0.0:80", "mode": "multi_threads" }"#).unwrap();
+        assert_eq!(config.bind, "0.0.0.0:80");
+        assert_eq!(config.mode, ServerMode::MultiThreads);
+        assert_eq!(config.workers, ServerConfig::default().workers);
+
+        assert!(serde_json::from_str::<ServerConfig>(r#"{ "port": 80 }"#).is_err());
+        assert!(serde_json::from_str::<ServerConfig>(r#"{ "mode": "forking" }"#).is_err());
+    }
+
+    #[test]
+    pub fn test_env_overrides() {
+        let mut config = ServerConfig::default();
+        config
+            .apply_env(vars(&[
+                ("WEBAPP_BIND", "0.0.0.0:9090"),
+                ("WEBAPP_MODE", "async"),
+                ("WEBAPP_WORKERS", "8"),
+                ("WEBAPP_MAX_BODY_BYTES", "10"),
+                ("WEBAPP_ACCESS_LOG", "json"),
+                ("WEBAPP_MAX_HEADERS", "20"),
+                ("WEBAPP_MAX_FORM_PARTS", "4"),
+                ("WEBAPP_MAX_UPLOAD_BYTES", "2048"),
+                ("WEBAPP_TRUSTED_PROXIES", "10.0.0.1, ::1"),
+                ("WEBAPP_READ_TIMEOUT_SECS", "3"),
+                ("WEBAPP_RATE_LIMIT_HEADER", "X-Real-IP"),
+                ("WEBAPP_RATE_LIMIT_SWEEP_SECS", "30"),
+                ("WEBAPP_MAX_CONNECTIONS_PER_IP", "4"),
+                ("WEBAPP_COMPRESSION_LEVEL", "0"),
+                ("WEBAPP_CORS_ORIGINS", "https://app.example.com, https://*.example.com"),
+                ("WEBAPP_SESSION_DIR", "/var/lib/rs-tutorial/sessions"),
+                ("WEBAPP_SESSION_COOKIE_NAME", "__Host-sid"),
+                ("WEBAPP_SESSION_SECURE", "true"),
+                ("PATH", "/usr/bin"),
+            ]))
+            .unwrap();
+        assert_eq!(config.bind, "0.0.0.0:9090");
+        assert_eq!(config.mode, ServerMode::Async);
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.limits().max_body_bytes, 10);
+        assert_eq!(config.access_log, AccessLogFormat::Json);
+        assert_eq!(config.limits().max_headers, 20);
+        assert_eq!(config.multipart_limits().max_parts, 4);
+        assert_eq!(config.limits().max_upload_bytes, 2048);
+        assert_eq!(config.limits().multipart.max_parts, 4);
+        assert_eq!(config.trusted_proxies, vec!["10.0.0.1".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+        assert_eq!(config.timeouts().read, Duration::from_secs(3));
+        assert_eq!(config.rate_limit_header, "X-Real-IP");
+        assert_eq!(config.rate_limit_sweep(), Duration::from_secs(30));
+        assert_eq!(config.max_connections_per_ip, 4);
+        assert_eq!(config.compression_level, 0);
+        assert_eq!(config.cors_origins, vec!["https://app.example.com", "https://*.example.com"]);
+        assert!(config.cors().unwrap().is_some());
+        assert_eq!(config.session_dir, Some(PathBuf::from("/var/lib/rs-tutorial/sessions")));
+        assert_eq!(config.session_cookie_name, "__Host-sid");
+        assert!(config.session_secure);
+        config.apply_env(vars(&[("WEBAPP_SESSION_DIR", "")])).unwrap();
+        assert_eq!(config.session_dir, None);
+
+        let err = config.apply_env(vars(&[("WEBAPP_WORKERS", "many")])).unwrap_err();
+        assert_eq!(err.to_string(), "invalid value \"many\" of WEBAPP_WORKERS");
+    }
+
+    #[test]
+    pub fn test_from_args() {
+        let path = env::temp_dir().join(format!("rs-tutorial-config-{}.json", std::process::id()));
+        fs::write(&path, r#"{ "workers": 7, "idle_timeout_secs": 1 }"#).unwrap();
+
+        let config = ServerConfig::from_args(vec!["--config".to_string(), path.display().to_string()]).unwrap();
+        assert_eq!(config.workers, 7);
+        assert_eq!(config.keep_alive().idle_timeout, Duration::from_secs(1));
+        let config = ServerConfig::from_args(vec![format!("--config={}", path.display())]).unwrap();
+        assert_eq!(config.workers, 7);
+
+        assert!(matches!(ServerConfig::from_args(vec!["--config".to_string()]), Err(ConfigError::Usage(_))));
+        assert!(matches!(ServerConfig::from_args(vec!["--verbose".to_string()]), Err(ConfigError::Usage(_))));
+        assert!(matches!(
+            ServerConfig::from_args(vec!["-c".to_string(), "/no/such/file.json".to_string()]),
+            Err(ConfigError::Io(..))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let config = ServerConfig { workers: 0, ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { read_timeout_secs: 0, ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { idle_timeout_secs: 0, ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { rate_limit_per_sec: -1.0, ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { rate_limit_per_sec: 0.0, rate_limit_burst: 0, ..ServerConfig::default() };
+        assert!(config.validate().is_ok());
+        let config = ServerConfig { session_cookie_name: "my sid".to_string(), ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { rate_limit_header: "X-Forwarded-For:".to_string(), ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { rate_limit_sweep_secs: 0, ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { compression_level: 10, ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { cors_origins: vec!["~(".to_string()], ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = ServerConfig { cors_origins: vec!["*".to_string()], cors_methods: vec!["get".to_string()], ..ServerConfig::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        assert!(ServerConfig::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,840 @@
+use std::any::Any;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::fmt;
+
+use crate::webapp::chunked::ChunkedWriter;
+use crate::webapp::error::HttpError;
+use crate::webapp::http::{read_headers, HttpRequest, HttpResponse, Limits, Method, Version};
+use crate::webapp::server::{ConnectionGuard, ShutdownSignal};
+
+/// While a connection is idle, check for shutdown this often.
+pub(crate) const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long an error page waits for the client to stop sending before closing.
+pub(crate) const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Persistent connection settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepAlive {
+    /// Close the connection if no request arrives within this time.
+    pub idle_timeout: Duration,
+    /// Close the connection after this many requests, `1` disables keep-alive.
+    pub max_requests: usize,
+}
+
+impl KeepAlive {
+    /// One request per connection
+    pub fn disabled() -> Self {
+        KeepAlive { idle_timeout: Duration::from_secs(5), max_requests: 1 }
+    }
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive { idle_timeout: Duration::from_secs(5), max_requests: 100 }
+    }
+}
+
+/// Socket timeouts, so a slow client can't hold a worker forever.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeouts {
+    /// Deadline for a whole request, from its first byte to the end of the body.
+    /// Dribbling one header line every few seconds doesn't extend it.
+    pub read: Duration,
+    /// Time allowed for each write of the response
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts { read: Duration::from_secs(10), write: Duration::from_secs(10) }
+    }
+}
+
+/// The connection handed over by a `101 Switching Protocols` response, see `Upgrade`.
+pub struct Upgraded {
+    pub stream: TcpStream,
+    /// Bytes the client sent right after the request, already read from the socket
+    pub buffered: Vec<u8>,
+    pub shutdown: ShutdownSignal,
+}
+
+/// Protocol taking over the connection once its `101` response is written, e.g. WebSocket.
+/// Runs where `serve_connection_with` hands the connection off to, which keeps it counted until it returns.
+#[derive(Clone)]
+pub struct Upgrade(Arc<Mutex<Option<UpgradeFn>>>);
+
+type UpgradeFn = Box<dyn FnOnce(Upgraded) + Send>;
+
+impl Upgrade {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: FnOnce(Upgraded) + Send + 'static,
+    {
+        Upgrade(Arc::new(Mutex::new(Some(Box::new(f)))))
+    }
+
+    /// Run the protocol, a clone of an upgrade that already ran does nothing.
+    pub fn run(self, upgraded: Upgraded) {
+        let f = self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(f) = f {
+            f(upgraded);
+        }
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+impl PartialEq for Upgrade {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Body written piece by piece after the response head, e.g. Server-Sent Events or a file.
+/// Of unknown length, HTTP/1.1 clients get it chunked and the connection may serve more requests after it,
+/// HTTP/1.0 clients know the body is complete when the connection closes.
+/// A `sized` body is sent with `Content-Length` instead, to either.
+/// It keeps the thread that served the request, unless it's `long_lived`, and should return on shutdown.
+#[derive(Clone)]
+pub struct StreamBody {
+    f: Arc<Mutex<Option<StreamFn>>>,
+    length: Option<u64>,
+    long_lived: bool,
+}
+
+type StreamFn = Box<dyn FnOnce(&mut dyn Write, &ShutdownSignal) -> io::Result<()> + Send>;
+
+impl StreamBody {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write, &ShutdownSignal) -> io::Result<()> + Send + 'static,
+    {
+        StreamBody { f: Arc::new(Mutex::new(Some(Box::new(f)))), length: None, long_lived: false }
+    }
+
+    /// Body of exactly `length` bytes. Writing more, or returning after fewer, fails the connection:
+    /// the client would take the rest of the stream for the next response, or wait forever.
+    pub fn sized<F>(length: u64, f: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write, &ShutdownSignal) -> io::Result<()> + Send + 'static,
+    {
+        StreamBody { length: Some(length), ..StreamBody::new(f) }
+    }
+
+    /// A body lasting as long as the client stays, e.g. Server-Sent Events. Its connection is closed
+    /// after it, and `serve_connection_with` hands it off instead of keeping a worker busy.
+    pub fn with_long_lived(mut self, long_lived: bool) -> Self {
+        self.long_lived = long_lived;
+        self
+    }
+
+    /// `Content-Length` of a `sized` body
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    pub fn is_long_lived(&self) -> bool {
+        self.long_lived
+    }
+
+    /// Write the body, a clone of a body that was already written writes nothing.
+    pub fn run(self, writer: &mut dyn Write, shutdown: &ShutdownSignal) -> io::Result<()> {
+        let f = self.f.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match f {
+            Some(f) => f(writer, shutdown),
+            None => Ok(()),
+        }
+    }
+
+    /// `run` with the framing of `version`: as it is when sized or for HTTP/1.0, chunked otherwise.
+    pub fn write_framed(self, version: Version, writer: &mut dyn Write, shutdown: &ShutdownSignal) -> io::Result<()> {
+        match (self.length, version) {
+            (Some(length), _) => {
+                let mut sized = SizedWriter { inner: writer, left: length };
+                self.run(&mut sized, shutdown)?;
+                if sized.left > 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body shorter than its Content-Length"));
+                }
+                sized.inner.flush()
+            }
+            (None, Version::Http11) => {
+                let mut chunked = ChunkedWriter::new(writer);
+                self.run(&mut chunked, shutdown)?;
+                chunked.finish().map(drop)
+            }
+            (None, Version::Http10) => {
+                self.run(writer, shutdown)?;
+                writer.flush()
+            }
+        }
+    }
+}
+
+/// Keeps a `sized` body to its length.
+struct SizedWriter<'a> {
+    inner: &'a mut dyn Write,
+    left: u64,
+}
+
+impl Write for SizedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.left {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "body longer than its Content-Length"));
+        }
+        let written = self.inner.write(buf)?;
+        self.left -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl fmt::Debug for StreamBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StreamBody")
+    }
+}
+
+impl PartialEq for StreamBody {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.f, &other.f)
+    }
+}
+
+/// Counts the bytes that went through to `inner`.
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    pub written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, written: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// One request of a connection as it went on the wire, told to the `Observer` once its response
+/// is written, or failed to be.
+pub struct Exchange<'a> {
+    /// As far as it was read, `None` if not even its request line was
+    pub request: Option<&'a HttpRequest>,
+    pub peer_addr: Option<SocketAddr>,
+    pub status: u16,
+    /// Bytes written to the socket after the head: the body, with its chunk framing if any. None for `HEAD`.
+    pub bytes: u64,
+    /// From the first byte of the request until the last one of the response
+    pub latency: Duration,
+}
+
+/// Told about every `Exchange` of the connections, e.g. to log them.
+/// Also runs where long-lived responses are handed off to, once they end.
+pub type Observer = Arc<dyn Fn(&Exchange) + Send + Sync>;
+
+/// Read side of the stream with an optional deadline for all reads together.
+struct DeadlineStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            self.stream.set_read_timeout(Some(deadline - now))?;
+        }
+        (&mut &*self.stream).read(buf)
+    }
+}
+
+/// Serve requests of one connection until the client or `keep_alive` closes it.
+/// Pipelined requests stay in the `BufReader` and are answered one by one, so responses
+/// go out in request order.
+///
+/// A request that can't be read is answered with its error page (400, 408, 413, 431, ...) and
+/// a panicking handler with 500, then the connection is closed and the error returned for logging.
+///
+/// Upgrades and long-lived bodies keep the calling thread until they end, see `serve_connection_with`.
+pub fn serve_connection<F>(
+    stream: TcpStream,
+    guard: &ConnectionGuard,
+    keep_alive: &KeepAlive,
+    timeouts: &Timeouts,
+    limits: &Limits,
+    handler: F,
+) -> Result<(), HttpError>
+where
+    F: FnMut(&mut HttpRequest) -> HttpResponse,
+{
+    let observer: Observer = Arc::new(|_: &Exchange| {});
+    serve_connection_with(stream, guard, keep_alive, timeouts, limits, handler, |rest| rest(), &observer)
+}
+
+/// The rest of a connection handed off by `serve_connection_with`
+pub type Handoff = Box<dyn FnOnce() + Send>;
+
+/// `serve_connection`, handing the connection off to `hand_off` once the head of an `Upgrade`
+/// or of a `long_lived` body is written, and returning right away.
+/// `hand_off` should run the rest on a thread of its own: a pool worker or the only thread
+/// of a server then goes on with the next connection, instead of waiting for the client to leave.
+/// The handed off connection still counts for shutdown until it ends.
+///
+/// Every request is told to `observer` once its response is written, error pages included.
+#[allow(clippy::too_many_arguments)]
+pub fn serve_connection_with<F, H>(
+    stream: TcpStream,
+    guard: &ConnectionGuard,
+    keep_alive: &KeepAlive,
+    timeouts: &Timeouts,
+    limits: &Limits,
+    mut handler: F,
+    hand_off: H,
+    observer: &Observer,
+) -> Result<(), HttpError>
+where
+    F: FnMut(&mut HttpRequest) -> HttpResponse,
+    H: FnOnce(Handoff),
+{
+    stream.set_write_timeout(Some(timeouts.write))?;
+    let mut reader = BufReader::new(DeadlineStream { stream: &stream, deadline: None });
+    let mut writer = &stream;
+    let mut served = 0;
+    let peer_addr = stream.peer_addr().ok();
+    loop {
+        // Idle only between requests, a new connection gets as long as any request takes to arrive
+        let wait = if served == 0 { timeouts.read } else { keep_alive.idle_timeout };
+        if !wait_for_request(&mut reader, wait, guard)? {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let report = |request: Option<&HttpRequest>, status: u16, bytes: u64| {
+            observer(&Exchange { request, peer_addr, status, bytes, latency: started.elapsed() });
+        };
+        reader.get_mut().deadline = Some(started + timeouts.read);
+        // Error pages answer in the version of the request, once its request line is read
+        let mut read = None;
+        let result = read_request(&mut reader, limits, &mut read);
+        reader.get_mut().deadline = None;
+        if let Some(request) = &mut read {
+            request.peer_addr = peer_addr;
+        }
+        if let Err(e) = result {
+            let version = read.as_ref().map_or(Version::Http11, |request| request.version);
+            return Err(send_error(&mut writer, e, version, |status, bytes| report(read.as_ref(), status, bytes)));
+        }
+        let Some(mut request) = read else {
+            return Ok(());
+        };
+        served += 1;
+
+        let mut response = match panic::catch_unwind(AssertUnwindSafe(|| handler(&mut request))) {
+            Ok(response) => response,
+            Err(payload) => {
+                let error = HttpError::Internal(panic_message(payload));
+                return Err(send_error(&mut writer, error, request.version, |status, bytes| report(Some(&request), status, bytes)));
+            }
+        };
+        if let Some(upgrade) = take_upgrade(&mut response) {
+            response.write_to(request.version, &mut writer).map_err(HttpError::Io)?;
+            report(Some(&request), response.status, 0);
+            let buffered = reader.buffer().to_vec();
+            drop(reader);
+            stream.set_read_timeout(None)?;
+            let (shutdown, guard) = (guard.shutdown_signal(), guard.clone());
+            hand_off(Box::new(move || {
+                upgrade.run(Upgraded { stream, buffered, shutdown });
+                drop(guard);
+            }));
+            return Ok(());
+        }
+        let keep = keep_connection(request.version, request.wants_keep_alive(), &mut response, served, keep_alive, guard);
+        let mut sent = CountingWriter::new(writer);
+        let mut written = response.write_head_to(request.version, &mut writer);
+        if request.method == Method::Head {
+            response.stream = None;
+        } else if written.is_ok() && response.stream.is_none() {
+            written = sent.write_all(&response.body);
+        }
+        match response.stream.take() {
+            // Not kept, the connection ends with the body
+            Some(body) if body.is_long_lived() && written.is_ok() => {
+                drop(reader);
+                let (guard, observer) = (guard.clone(), Arc::clone(observer));
+                hand_off(Box::new(move || {
+                    let mut sent = CountingWriter::new(&stream);
+                    if let Err(e) = body.write_framed(request.version, &mut sent, &guard.shutdown_signal()) {
+                        tracing::warn!("Connection dropped: {}", e);
+                    }
+                    let latency = started.elapsed();
+                    observer(&Exchange { request: Some(&request), peer_addr, status: response.status, bytes: sent.written, latency });
+                }));
+                return Ok(());
+            }
+            Some(body) if written.is_ok() => written = body.write_framed(request.version, &mut sent, &guard.shutdown_signal()),
+            _ => {}
+        }
+        report(Some(&request), response.status, sent.written);
+        // A write timeout means the client is gone for us, not a 408
+        written.map_err(HttpError::Io)?;
+        if !keep {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether the connection persists after answering the `served`-th request with `response`.
+/// Sets the `Connection` header of `response` accordingly.
+pub(crate) fn keep_connection(
+    version: Version,
+    wants_keep_alive: bool,
+    response: &mut HttpResponse,
+    served: usize,
+    keep_alive: &KeepAlive,
+    guard: &ConnectionGuard,
+) -> bool {
+    let keep = wants_keep_alive
+        && served < keep_alive.max_requests
+        && !guard.is_shutting_down()
+        // Only chunks or a length tell where a streamed body ends, without them the connection has to
+        && (response.stream.as_ref().is_none_or(|stream| stream.length().is_some()) || version == Version::Http11)
+        && !response.stream.as_ref().is_some_and(StreamBody::is_long_lived)
+        && !response.headers.contains_token("Connection", "close");
+    if !keep {
+        response.headers.insert("Connection", "close");
+    } else if version == Version::Http10 {
+        response.headers.insert("Connection", "keep-alive");
+    }
+    keep
+}
+
+/// The upgrade of a `101` response, any other response keeps speaking HTTP.
+pub(crate) fn take_upgrade(response: &mut HttpResponse) -> Option<Upgrade> {
+    match response.status {
+        101 => response.upgrade.take(),
+        _ => None,
+    }
+}
+
+/// The message of a handler panic, e.g. `panic!("oops")` => `oops`
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "handler panicked".to_string())
+}
+
+/// Answer `error` with its error page if the client can still get one, and give it back.
+/// What was sent is told to `sent`: the status and the bytes of the body.
+fn send_error<S>(writer: &mut &TcpStream, error: HttpError, version: Version, sent: S) -> HttpError
+where
+    S: FnOnce(u16, u64),
+{
+    if let Some(response) = error.to_response() {
+        let response = response.with_header("Connection", "close");
+        // The client may be gone already, the original error is more interesting.
+        let written = response.write_to(version, writer);
+        sent(response.status, if written.is_ok() { response.body.len() as u64 } else { 0 });
+        if written.is_ok() {
+            linger(writer);
+        }
+    }
+    error
+}
+
+/// `HttpRequest::read_with_limits` into `request`, which holds what was read of it if it fails,
+/// e.g. the request line, so the error page can answer in its version. `None` if the client left first.
+fn read_request<R: BufRead>(reader: &mut R, limits: &Limits, request: &mut Option<HttpRequest>) -> Result<(), HttpError> {
+    let Some(line) = HttpRequest::read_request_line(reader, limits)? else {
+        return Ok(());
+    };
+    let request = request.insert(line);
+    request.headers = read_headers(reader, limits)?;
+    request.read_body(reader, limits)
+}
+
+/// Close our side and discard what the client is still sending for a moment.
+/// Closing with unread data resets the connection, and the client may lose the error page with it.
+fn linger(stream: &TcpStream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut stream = DeadlineStream { stream, deadline: Some(deadline) };
+    let mut buf = [0; 4096];
+    while let Ok(1..) = stream.read(&mut buf) {}
+}
+
+/// Wait until the next request starts to arrive.
+/// Return `false` if the client closed the connection, stayed idle for `timeout`,
+/// or the server started to shut down in the meantime.
+fn wait_for_request(reader: &mut BufReader<DeadlineStream>, timeout: Duration, guard: &ConnectionGuard) -> io::Result<bool> {
+    if !reader.buffer().is_empty() {
+        return Ok(true); // pipelined
+    }
+    let expire_at = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= expire_at {
+            return Ok(false);
+        }
+        let slice = (expire_at - now).clamp(Duration::from_millis(1), IDLE_POLL_INTERVAL);
+        reader.get_ref().stream.set_read_timeout(Some(slice))?;
+        match reader.fill_buf() {
+            Ok(buf) => return Ok(!buf.is_empty()),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if guard.is_shutting_down() {
+                    return Ok(false);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod connection_test_cases {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::webapp::pool::WorkerPool;
+    use crate::webapp::server::ServerHandle;
+
+    /// Every response body is the request path, `/panic` panics.
+    /// `/echo` answers the request body and its trailer count, `/stream` streams the body back twice.
+    fn spawn_server(keep_alive: KeepAlive) -> ServerHandle {
+        spawn_server_with(keep_alive, Timeouts::default())
+    }
+
+    fn spawn_server_with(keep_alive: KeepAlive, timeouts: Timeouts) -> ServerHandle {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        ServerHandle::spawn(listener, move |incoming| {
+            let mut workers = Vec::new();
+            for (stream, guard) in incoming {
+                let (keep_alive, timeouts) = (keep_alive.clone(), timeouts.clone());
+                workers.push(thread::spawn(move || {
+                    let _ = serve_connection(stream, &guard, &keep_alive, &timeouts, &Limits::default(), |request| {
+                        match request.path.as_str() {
+                            "/panic" => panic!("handler panicked"),
+                            "/echo" => HttpResponse::new(200)
+                                .with_header("X-Trailers", request.trailers.len().to_string())
+                                .with_body(request.body.clone()),
+                            "/stream" => {
+                                let body = request.body.clone();
+                                HttpResponse::new(200).with_stream(StreamBody::new(move |writer, _| {
+                                    writer.write_all(&body)?;
+                                    writer.write_all(&body)
+                                }))
+                            }
+                            _ => HttpResponse::new(200).with_body(request.path.clone()),
+                        }
+                    });
+                }));
+            }
+            for worker in workers {
+                worker.join().unwrap();
+            }
+        })
+        .unwrap()
+    }
+
+    fn read_to_eof(reader: &mut BufReader<TcpStream>) -> usize {
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        rest.len()
+    }
+
+    #[test]
+    pub fn test_pipelined_requests() {
+        let server = spawn_server(KeepAlive::default());
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+        let mut reader = BufReader::new(client);
+        for path in ["/a", "/b"] {
+            let response = HttpResponse::read_from(&mut reader).unwrap();
+            assert_eq!(response.body, path.as_bytes());
+            assert_eq!(response.headers.get("Connection"), None);
+        }
+        let last = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(last.body, b"/c");
+        assert_eq!(last.headers.get("Connection"), Some("close"));
+        assert_eq!(read_to_eof(&mut reader), 0);
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_http10_keep_alive() {
+        let server = spawn_server(KeepAlive::default());
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n").unwrap();
+
+        let mut reader = BufReader::new(client);
+        let first = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(first.headers.get("Connection"), Some("keep-alive"));
+        let second = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(second.headers.get("Connection"), Some("close"));
+        assert_eq!(read_to_eof(&mut reader), 0);
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_chunked_bodies() {
+        let server = spawn_server(KeepAlive::default());
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client
+            .write_all(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 1\r\n\r\n")
+            .unwrap();
+        client.write_all(b"POST /stream HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc").unwrap();
+        client.write_all(b"POST /stream HTTP/1.0\r\nConnection: keep-alive\r\nContent-Length: 3\r\n\r\nxyz").unwrap();
+
+        let mut reader = BufReader::new(client);
+        let echo = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(echo.body, b"hello world");
+        assert_eq!(echo.headers.get("X-Trailers"), Some("1"));
+        // Chunked, so the connection goes on after it
+        let streamed = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(streamed.headers.get("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(streamed.headers.get("Connection"), None);
+        assert_eq!(streamed.body, b"abcabc");
+        // HTTP/1.0 has no chunks, the body ends with the connection
+        let head = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(head.headers.get("Connection"), Some("close"));
+        assert!(!head.headers.contains("Transfer-Encoding"));
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"xyzxyz");
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_head_request() {
+        let server = spawn_server(KeepAlive::default());
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"HEAD /abc HTTP/1.1\r\n\r\nHEAD /stream HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+        let mut reader = BufReader::new(client);
+        let mut heads = String::new();
+        for _ in 0..2 {
+            while !heads.ends_with("\r\n\r\n") {
+                reader.read_line(&mut heads).unwrap();
+            }
+            heads.push('|');
+        }
+        let (head, streamed) = heads.split_once('|').unwrap();
+        assert!(head.contains("Content-Length: 4\r\n"), "{}", head);
+        assert!(streamed.contains("Transfer-Encoding: chunked\r\n"), "{}", streamed);
+        // No body after either head, the next response follows right away
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().body, b"/b");
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_max_requests() {
+        let server = spawn_server(KeepAlive { idle_timeout: Duration::from_secs(5), max_requests: 2 });
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut reader = BufReader::new(client);
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().headers.get("Connection"), None);
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().headers.get("Connection"), Some("close"));
+        assert_eq!(read_to_eof(&mut reader), 0);
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_error_responses() {
+        let server = spawn_server(KeepAlive::default());
+        for (raw, status) in [
+            ("GET /\r\n\r\n", 400),
+            ("BREW /pot HTTP/1.1\r\n\r\n", 501),
+            ("GET / HTTP/3\r\n\r\n", 505),
+            ("POST / HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n", 413),
+            ("GET /panic HTTP/1.1\r\n\r\n", 500),
+            (&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000)), 414),
+            (&format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(9000)), 431),
+            (&format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(101)), 431),
+        ] {
+            let mut client = TcpStream::connect(server.local_addr()).unwrap();
+            client.write_all(raw.as_bytes()).unwrap();
+            let mut reader = BufReader::new(client);
+            let response = HttpResponse::read_from(&mut reader).unwrap();
+            assert_eq!(response.status, status, "{:?}", raw);
+            assert_eq!(response.headers.get("Connection"), Some("close"));
+            assert_eq!(read_to_eof(&mut reader), 0);
+        }
+
+        // Once the request line is read, the error page answers in its version
+        for (raw, status_line) in [
+            ("POST / HTTP/1.0\r\nContent-Length: 2000000\r\n\r\n", "HTTP/1.0 413 "),
+            ("GET /panic HTTP/1.0\r\n\r\n", "HTTP/1.0 500 "),
+            ("GET / HTTP/3\r\n\r\n", "HTTP/1.1 505 "),
+        ] {
+            let mut client = TcpStream::connect(server.local_addr()).unwrap();
+            client.write_all(raw.as_bytes()).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with(status_line), "{:?}", response);
+        }
+
+        // A client vanishing in the middle of a request doesn't take the server down
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+        drop(client);
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"GET /ok HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert_eq!(HttpResponse::read_from(&mut BufReader::new(client)).unwrap().body, b"/ok");
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_slow_request() {
+        let timeouts = Timeouts { read: Duration::from_millis(300), write: Duration::from_secs(1) };
+        let server = spawn_server_with(KeepAlive::default(), timeouts);
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        let started = Instant::now();
+        // One header line at a time, each well within the deadline but not the whole request
+        for line in ["GET /slow HTTP/1.1\r\n", "X-A: 1\r\n", "X-B: 2\r\n", "X-C: 3\r\n"] {
+            if client.write_all(line.as_bytes()).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(150));
+        }
+        let mut reader = BufReader::new(client);
+        let response = HttpResponse::read_from(&mut reader).unwrap();
+        assert_eq!(response.status, 408);
+        assert_eq!(read_to_eof(&mut reader), 0);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_idle_timeout() {
+        let server = spawn_server(KeepAlive { idle_timeout: Duration::from_millis(200), max_requests: 100 });
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut reader = BufReader::new(client);
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().status, 200);
+        let started = Instant::now();
+        assert_eq!(read_to_eof(&mut reader), 0);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_first_request_not_idle() {
+        // The idle timeout is for keep-alive only, the first request gets the read timeout
+        let server = spawn_server(KeepAlive { idle_timeout: Duration::ZERO, max_requests: 100 });
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().status, 200);
+        assert_eq!(read_to_eof(&mut reader), 0);
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_idle_connection_closed_on_shutdown() {
+        let server = spawn_server(KeepAlive { idle_timeout: Duration::from_secs(30), max_requests: 100 });
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+        assert_eq!(HttpResponse::read_from(&mut reader).unwrap().status, 200);
+
+        let started = Instant::now();
+        assert!(server.shutdown(Duration::from_secs(5)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(read_to_eof(&mut reader), 0);
+    }
+
+    /// Sends a line then waits for shutdown, like an SSE subscription or a WebSocket with nothing to say
+    fn until_shutdown(writer: &mut dyn Write, shutdown: &ShutdownSignal) -> io::Result<()> {
+        writer.write_all(b"open\n")?;
+        writer.flush()?;
+        while !shutdown.is_shutting_down() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_long_lived_handed_off() {
+        const WORKERS: usize = 2;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ServerHandle::spawn(listener, move |incoming| {
+            let pool = WorkerPool::new(WORKERS, 8, |(stream, guard): (TcpStream, ConnectionGuard)| {
+                let handler = |request: &mut HttpRequest| match request.path.as_str() {
+                    "/events" => HttpResponse::new(200).with_stream(StreamBody::new(until_shutdown).with_long_lived(true)),
+                    "/upgrade" => HttpResponse::new(101).with_upgrade(Upgrade::new(|mut upgraded| {
+                        let _ = until_shutdown(&mut upgraded.stream, &upgraded.shutdown);
+                    })),
+                    _ => HttpResponse::new(200).with_body(request.path.clone()),
+                };
+                let handed_off = |rest: Handoff| {
+                    thread::spawn(rest);
+                };
+                let observer: Observer = Arc::new(|_: &Exchange| {});
+                let _ = serve_connection_with(stream, &guard, &KeepAlive::default(), &Timeouts::default(), &Limits::default(), handler, handed_off, &observer);
+            });
+            for job in incoming {
+                let _ = pool.try_execute(job);
+            }
+        })
+        .unwrap();
+
+        // More long-lived connections than workers, each past its head
+        let mut open = Vec::new();
+        for path in ["/events", "/events", "/upgrade"] {
+            let mut client = TcpStream::connect(server.local_addr()).unwrap();
+            write!(client, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+            let mut reader = BufReader::new(client);
+            let mut line = String::new();
+            while line != "open\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            open.push(reader);
+        }
+
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"GET /plain HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let response = HttpResponse::read_from(&mut BufReader::new(client)).unwrap();
+        assert_eq!(response.body, b"/plain");
+
+        // The handed off connections still count, and end with the shutdown
+        assert!(server.shutdown(Duration::from_secs(5)));
+        for mut reader in open {
+            read_to_eof(&mut reader);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime};
+
+use crate::webapp::http::http_date;
+
+/// `SameSite` attribute of a cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Sent with cross-site requests too, browsers require `Secure` with it
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// Cookie sent with `Set-Cookie`, RFC 6265 section 4.1.
+/// The value is written as is, keep it to cookie-safe characters, e.g. base64url.
+/// ```ignore
+/// let cookie = Cookie::new("theme", "dark").with_max_age(Duration::from_secs(3600)).with_http_only(true);
+/// response.with_cookie(&cookie) // Set-Cookie: theme=dark; Max-Age=3600; HttpOnly
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub expires: Option<SystemTime>,
+    /// Takes precedence over `expires` for the clients supporting both
+    pub max_age: Option<Duration>,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            expires: None,
+            max_age: None,
+            domain: None,
+            path: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Cookie telling the client to delete `name`, give it the path and domain it was set with.
+    pub fn removal(name: impl Into<String>) -> Self {
+        Cookie::new(name, "").with_max_age(Duration::ZERO).with_expires(SystemTime::UNIX_EPOCH)
+    }
+
+    pub fn with_expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn with_domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn with_http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl Display for Cookie {
+    /// The `Set-Cookie` header value
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Name-value pairs of a `Cookie` request header, in order.
+/// `a=1; b="2"; broken` => `[("a", "1"), ("b", "2")]`
+pub fn parse_cookies(header: &str) -> Vec<(String, String)> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+            (name.trim().to_string(), value.to_string())
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+pub mod cookie_test_cases {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_cookies() {
+        let cookies = parse_cookies(r#"sid=abc-123; theme="dark" ;broken; =nameless; empty="#);
+        assert_eq!(
+            cookies,
+            vec![
+                ("sid".to_string(), "abc-123".to_string()),
+                ("theme".to_string(), "dark".to_string()),
+                ("empty".to_string(), "".to_string()),
+            ]
+        );
+        assert!(parse_cookies("").is_empty());
+    }
+
+    #[test]
+    pub fn test_set_cookie() {
+        assert_eq!(Cookie::new("a", "1").to_string(), "a=1");
+        let cookie = Cookie::new("sid", "abc")
+            .with_expires(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777))
+            .with_max_age(Duration::from_secs(3600))
+            .with_domain("example.com")
+            .with_path("/")
+            .with_secure(true)
+            .with_http_only(true)
+            .with_same_site(SameSite::Strict);
+        assert_eq!(
+            cookie.to_string(),
+            "sid=abc; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Max-Age=3600; Domain=example.com; Path=/; Secure; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(
+            Cookie::removal("sid").with_path("/").to_string(),
+            "sid=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0; Path=/"
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use regex::Regex;
+
+use crate::webapp::http::{HttpRequest, HttpResponse, Method};
+use crate::webapp::middleware::{Middleware, Next};
+
+/// Origins a `Cors` policy lets in.
+/// - `*` => `Any`, every origin, without credentials
+/// - `https://*.example.com` => a `Pattern` where `*` stands for one or more subdomains
+/// - `~^https://pr-\d+\.example\.com$` => a `Pattern` from the regex after `~`
+/// - anything else => `Exact`, e.g. `https://app.example.com`
+#[derive(Debug, Clone)]
+pub enum AllowedOrigin {
+    Any,
+    Exact(String),
+    Pattern(Regex),
+}
+
+impl AllowedOrigin {
+    pub fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Pattern(pattern) => pattern.is_match(origin),
+        }
+    }
+}
+
+impl FromStr for AllowedOrigin {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(AllowedOrigin::Any);
+        }
+        if let Some(pattern) = s.strip_prefix('~') {
+            return Regex::new(pattern).map(AllowedOrigin::Pattern);
+        }
+        if s.contains('*') {
+            // Labels of a host name only, so `*` can't reach into another part of the origin
+            let parts: Vec<String> = s.split('*').map(regex::escape).collect();
+            let pattern = format!("(?i)^{}$", parts.join(r"[a-z0-9-]+(?:\.[a-z0-9-]+)*"));
+            return Regex::new(&pattern).map(AllowedOrigin::Pattern);
+        }
+        Ok(AllowedOrigin::Exact(s.trim_end_matches('/').to_string()))
+    }
+}
+
+impl Display for AllowedOrigin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AllowedOrigin::Any => f.write_str("*"),
+            AllowedOrigin::Exact(origin) => f.write_str(origin),
+            AllowedOrigin::Pattern(pattern) => write!(f, "~{}", pattern),
+        }
+    }
+}
+
+/// Cross-Origin Resource Sharing: lets pages of other origins call the server.
+///
+/// Preflight requests (`OPTIONS` with `Access-Control-Request-Method`) are answered right here:
+/// 204 with the allowed methods and headers, or 403 for an origin that isn't allowed.
+/// Other responses to an allowed origin get `Access-Control-Allow-Origin`, and the browser reads them.
+/// ```ignore
+/// let cors = Cors::new()
+///     .allow_origin("https://*.example.com".parse()?)
+///     .with_methods(vec![Method::Get, Method::Post])
+///     .with_credentials(true);
+/// Pipeline::new(router).with(cors)
+/// ```
+pub struct Cors {
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    credentials: bool,
+    max_age: Duration,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors::new()
+    }
+}
+
+impl Cors {
+    /// No origin, `GET`, `HEAD` and `POST` with `Content-Type`, no credentials, preflights cached for 10 minutes.
+    pub fn new() -> Self {
+        Cors {
+            origins: Vec::new(),
+            methods: vec![Method::Get, Method::Head, Method::Post],
+            headers: vec!["Content-Type".to_string()],
+            credentials: false,
+            max_age: Duration::from_secs(600),
+        }
+    }
+
+    pub fn allow_origin(mut self, origin: AllowedOrigin) -> Self {
+        self.origins.push(origin);
+        self
+    }
+
+    pub fn with_methods(mut self, methods: Vec<Method>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    /// Request headers a page may send, `*` allows the ones a preflight asks for
+    pub fn with_headers(mut self, headers: Vec<String>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Let pages send cookies and read the response. Never for `AllowedOrigin::Any`:
+    /// browsers refuse credentials with `*`, and echoing any origin would hand sessions to every site.
+    pub fn with_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache a preflight answer
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Value of `Access-Control-Allow-Origin` for `origin`, `None` if it isn't allowed.
+    /// Exact and pattern matches win over `Any`, so they can get credentials.
+    fn allow_origin_value(&self, origin: &str) -> Option<String> {
+        let matched = self.origins.iter().filter(|allowed| allowed.matches(origin));
+        match matched.min_by_key(|allowed| matches!(allowed, AllowedOrigin::Any)) {
+            Some(AllowedOrigin::Any) => Some("*".to_string()),
+            Some(_) => Some(origin.to_string()),
+            None => None,
+        }
+    }
+
+    /// Add the headers of an allowed `origin` to `response`.
+    fn decorate(&self, response: &mut HttpResponse, allow_origin: &str) {
+        response.headers.insert("Access-Control-Allow-Origin", allow_origin);
+        if self.credentials && allow_origin != "*" {
+            response.headers.insert("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    fn preflight(&self, request: &HttpRequest, origin: &str) -> HttpResponse {
+        let Some(allow_origin) = self.allow_origin_value(origin) else {
+            return HttpResponse::status_page(403);
+        };
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        let headers = match self.headers.iter().any(|header| header == "*") {
+            true => request.header("Access-Control-Request-Headers").unwrap_or("").to_string(),
+            false => self.headers.join(", "),
+        };
+        let mut response = HttpResponse::new(204)
+            .with_header("Access-Control-Allow-Methods", methods.join(", "))
+            .with_header("Access-Control-Max-Age", self.max_age.as_secs().to_string());
+        if !headers.is_empty() {
+            response.headers.insert("Access-Control-Allow-Headers", headers);
+        }
+        self.decorate(&mut response, &allow_origin);
+        response
+    }
+
+    /// Whether the answer depends on the `Origin` header, i.e. caches must keep one per origin
+    fn varies_by_origin(&self) -> bool {
+        !self.origins.iter().all(|allowed| matches!(allowed, AllowedOrigin::Any))
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut HttpRequest, next: Next<'_>) -> HttpResponse {
+        let origin = request.header("Origin").map(str::to_string);
+        let preflight = request.method == Method::Options && request.headers.contains("Access-Control-Request-Method");
+        let mut response = match origin {
+            Some(origin) if preflight => self.preflight(request, &origin),
+            Some(origin) => {
+                let mut response = next.run(request);
+                if let Some(allow_origin) = self.allow_origin_value(&origin) {
+                    self.decorate(&mut response, &allow_origin);
+                }
+                response
+            }
+            None => next.run(request),
+        };
+        // Even without `Origin`: a cached answer must not be handed to a page of another origin
+        if self.varies_by_origin() && !response.headers.contains_token("Vary", "Origin") {
+            response.headers.append("Vary", "Origin");
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+pub mod cors_test_cases {
+    use super::*;
+    use crate::webapp::middleware::Pipeline;
+    use crate::webapp::router::Router;
+
+    fn app(cors: Cors) -> Pipeline {
+        let router = Router::new().get("/api", |_, _| HttpResponse::new(200).with_body("data"));
+        Pipeline::new(router).with(cors)
+    }
+
+    fn request(method: &str, origin: Option<&str>, extra: &str) -> HttpRequest {
+        let origin = origin.map(|origin| format!("Origin: {}\r\n", origin)).unwrap_or_default();
+        let raw = format!("{} /api HTTP/1.1\r\n{}{}\r\n", method, origin, extra);
+        HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn origin(s: &str) -> AllowedOrigin {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    pub fn test_allowed_origins() {
+        assert!(origin("*").matches("http://localhost:3000"));
+        assert!(origin("https://app.example.com/").matches("https://app.example.com"));
+        assert!(!origin("https://app.example.com").matches("https://app.example.com.evil.net"));
+        let wildcard = origin("https://*.example.com");
+        assert!(wildcard.matches("https://app.example.com"));
+        assert!(wildcard.matches("https://a.b.example.com"));
+        assert!(!wildcard.matches("https://example.com"));
+        assert!(!wildcard.matches("https://evil.net/.example.com"));
+        assert!(!wildcard.matches("http://app.example.com"));
+        let regex = origin(r"~^https://pr-\d+\.preview\.dev$");
+        assert!(regex.matches("https://pr-42.preview.dev"));
+        assert!(!regex.matches("https://pr-x.preview.dev"));
+        assert!("~(".parse::<AllowedOrigin>().is_err());
+    }
+
+    #[test]
+    pub fn test_preflight() {
+        let cors = Cors::new()
+            .allow_origin(origin("https://*.example.com"))
+            .with_methods(vec![Method::Get, Method::Put])
+            .with_headers(vec!["Content-Type".to_string(), "X-Token".to_string()])
+            .with_credentials(true)
+            .with_max_age(Duration::from_secs(60));
+        let pipeline = app(cors);
+        let ask = "Access-Control-Request-Method: PUT\r\nAccess-Control-Request-Headers: x-token\r\n";
+
+        let response = pipeline.handle(&mut request("OPTIONS", Some("https://app.example.com"), ask));
+        assert_eq!(response.status, 204);
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("https://app.example.com"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Methods"), Some("GET, PUT"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Headers"), Some("Content-Type, X-Token"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Credentials"), Some("true"));
+        assert_eq!(response.headers.get("Access-Control-Max-Age"), Some("60"));
+        assert_eq!(response.headers.get("Vary"), Some("Origin"));
+
+        let response = pipeline.handle(&mut request("OPTIONS", Some("https://evil.net"), ask));
+        assert_eq!(response.status, 403);
+        assert!(!response.headers.contains("Access-Control-Allow-Origin"));
+        // Without an `Origin` it's no preflight, the router answers with `Allow`
+        let response = pipeline.handle(&mut request("OPTIONS", None, ask));
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    pub fn test_actual_requests() {
+        let pipeline = app(
+            Cors::new()
+                .allow_origin(origin("*"))
+                .allow_origin(origin("https://app.example.com"))
+                .with_credentials(true),
+        );
+        let response = pipeline.handle(&mut request("GET", Some("https://app.example.com"), ""));
+        assert_eq!(response.body, b"data");
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("https://app.example.com"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Credentials"), Some("true"));
+        // Any other origin may read, but never with credentials
+        let response = pipeline.handle(&mut request("GET", Some("https://other.net"), ""));
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("*"));
+        assert!(!response.headers.contains("Access-Control-Allow-Credentials"));
+
+        let pipeline = app(Cors::new().allow_origin(origin("https://app.example.com")));
+        let response = pipeline.handle(&mut request("GET", Some("https://other.net"), ""));
+        assert_eq!(response.status, 200);
+        assert!(!response.headers.contains("Access-Control-Allow-Origin"));
+        assert_eq!(response.headers.get("Vary"), Some("Origin"));
+        let response = pipeline.handle(&mut request("GET", None, ""));
+        assert_eq!(response.headers.get("Vary"), Some("Origin"));
+        let response = app(Cors::new().allow_origin(origin("*"))).handle(&mut request("GET", Some("https://other.net"), ""));
+        assert!(!response.headers.contains("Vary"));
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::webapp::http::HttpResponse;
+
+/// Why a request couldn't be read or served.
+/// Client mistakes map onto a status code, a broken connection has no one left to answer.
+#[derive(Debug)]
+pub enum HttpError {
+    /// 400, malformed request line, header or body framing
+    BadRequest(String),
+    /// 408, the client didn't finish the request in time
+    Timeout,
+    /// 413, body larger than `Limits::max_body_bytes`
+    PayloadTooLarge { length: usize, limit: usize },
+    /// 414, request line longer than `Limits::max_header_bytes`
+    UriTooLong(usize),
+    /// 431, too many or too large header fields
+    HeadersTooLarge(String),
+    /// 500, e.g. a handler panicked
+    Internal(String),
+    /// 501, method or transfer coding unknown to the server
+    NotImplemented(String),
+    /// 505
+    VersionNotSupported(String),
+    /// The connection broke (reset, EOF in the middle of a request, ...). No response possible.
+    Io(io::Error),
+}
+
+impl HttpError {
+    /// Status code answered to the client, `None` if the connection is gone.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::BadRequest(_) => Some(400),
+            HttpError::Timeout => Some(408),
+            HttpError::PayloadTooLarge { .. } => Some(413),
+            HttpError::UriTooLong(_) => Some(414),
+            HttpError::HeadersTooLarge(_) => Some(431),
+            HttpError::Internal(_) => Some(500),
+            HttpError::NotImplemented(_) => Some(501),
+            HttpError::VersionNotSupported(_) => Some(505),
+            HttpError::Io(_) => None,
+        }
+    }
+
+    /// Error page sent before closing the connection.
+    pub fn to_response(&self) -> Option<HttpResponse> {
+        self.status().map(HttpResponse::status_page)
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::BadRequest(message) => write!(f, "bad request: {}", message),
+            HttpError::Timeout => write!(f, "request timeout"),
+            HttpError::PayloadTooLarge { length, limit } => {
+                write!(f, "body of {} bytes exceeds limit of {} bytes", length, limit)
+            }
+            HttpError::UriTooLong(limit) => write!(f, "request line exceeds {} bytes", limit),
+            HttpError::HeadersTooLarge(message) => write!(f, "headers too large: {}", message),
+            HttpError::Internal(message) => write!(f, "internal error: {}", message),
+            HttpError::NotImplemented(what) => write!(f, "not implemented: {}", what),
+            HttpError::VersionNotSupported(version) => write!(f, "version not supported: {}", version),
+            HttpError::Io(e) => write!(f, "connection error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HttpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    /// Read timeouts become 408, invalid UTF-8 becomes 400, everything else means the connection is broken.
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HttpError::Timeout,
+            io::ErrorKind::InvalidData => HttpError::BadRequest(value.to_string()),
+            _ => HttpError::Io(value),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod error_test_cases {
+    use super::*;
+
+    #[test]
+    pub fn test_status() {
+        assert_eq!(HttpError::BadRequest("x".to_string()).status(), Some(400));
+        assert_eq!(HttpError::PayloadTooLarge { length: 2, limit: 1 }.status(), Some(413));
+        assert_eq!(HttpError::HeadersTooLarge("x".to_string()).status(), Some(431));
+        assert_eq!(HttpError::Io(io::Error::from(io::ErrorKind::ConnectionReset)).status(), None);
+        assert_eq!(HttpError::Timeout.to_response().unwrap().body, b"408 Request Timeout");
+    }
+
+    #[test]
+    pub fn test_from_io_error() {
+        assert!(matches!(HttpError::from(io::Error::from(io::ErrorKind::WouldBlock)), HttpError::Timeout));
+        assert!(matches!(HttpError::from(io::Error::from(io::ErrorKind::TimedOut)), HttpError::Timeout));
+        assert!(matches!(HttpError::from(io::Error::from(io::ErrorKind::InvalidData)), HttpError::BadRequest(_)));
+        assert!(matches!(HttpError::from(io::Error::from(io::ErrorKind::UnexpectedEof)), HttpError::Io(_)));
+    }
+}
@@ -0,0 +1,668 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use tempfile::NamedTempFile;
+
+use crate::webapp::error::HttpError;
+use crate::webapp::http::{percent_decode, read_headers, BodyReader, Headers, HttpRequest, HttpResponse, Limits};
+use crate::webapp::json::Response;
+
+/// Bytes read from the body at once
+const CHUNK_BYTES: usize = 8 * 1024;
+
+/// Limits of a `multipart/form-data` body, beyond `Limits::max_body_bytes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultipartLimits {
+    pub max_parts: usize,
+    /// Largest part, files included
+    pub max_part_bytes: usize,
+    /// Files larger than this go to a temporary file instead of memory
+    pub file_threshold: usize,
+    /// Header section of one part
+    pub max_header_bytes: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits { max_parts: 32, max_part_bytes: 1024 * 1024, file_threshold: 64 * 1024, max_header_bytes: 2 * 1024 }
+    }
+}
+
+/// Why a `multipart/form-data` body was rejected.
+#[derive(Debug)]
+pub enum MultipartError {
+    /// 400, broken framing or headers
+    Malformed(String),
+    /// 413, more than `MultipartLimits::max_parts` parts
+    TooManyParts(usize),
+    /// 413, a part larger than `MultipartLimits::max_part_bytes`
+    PartTooLarge { name: String, limit: usize },
+    /// 500, the temporary file of a part couldn't be written
+    Io(io::Error),
+}
+
+impl MultipartError {
+    pub fn status(&self) -> u16 {
+        match self {
+            MultipartError::Malformed(_) => 400,
+            MultipartError::TooManyParts(_) | MultipartError::PartTooLarge { .. } => 413,
+            MultipartError::Io(_) => 500,
+        }
+    }
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultipartError::Malformed(message) => write!(f, "malformed multipart body: {}", message),
+            MultipartError::TooManyParts(limit) => write!(f, "more than {} parts", limit),
+            MultipartError::PartTooLarge { name, limit } => write!(f, "part {:?} exceeds {} bytes", name, limit),
+            MultipartError::Io(e) => write!(f, "failed to buffer part: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MultipartError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(value: io::Error) -> Self {
+        MultipartError::Io(value)
+    }
+}
+
+impl From<HttpError> for MultipartError {
+    /// Errors of the part headers, everything but a broken reader is the client's fault
+    fn from(value: HttpError) -> Self {
+        match value {
+            HttpError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                MultipartError::Malformed("body ends in part headers".to_string())
+            }
+            HttpError::Io(e) => MultipartError::Io(e),
+            e => MultipartError::Malformed(e.to_string()),
+        }
+    }
+}
+
+/// Content of a part, in memory or, for large files, in a temporary file deleted on drop.
+#[derive(Debug)]
+pub enum PartBody {
+    Memory(Vec<u8>),
+    File { file: NamedTempFile, length: usize },
+}
+
+/// One part of a `multipart/form-data` body.
+/// `filename` comes from the client as is, don't use it as a path.
+#[derive(Debug)]
+pub struct Part {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub headers: Headers,
+    pub body: PartBody,
+}
+
+impl Part {
+    pub fn len(&self) -> usize {
+        match &self.body {
+            PartBody::Memory(bytes) => bytes.len(),
+            PartBody::File { length, .. } => *length,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Value of a form field, `None` for file contents and invalid UTF-8
+    pub fn text(&self) -> Option<&str> {
+        match &self.body {
+            PartBody::Memory(bytes) => std::str::from_utf8(bytes).ok(),
+            PartBody::File { .. } => None,
+        }
+    }
+
+    /// The whole content, read back from the temporary file if needed
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        match &self.body {
+            PartBody::Memory(bytes) => Ok(bytes.clone()),
+            PartBody::File { file, .. } => fs::read(file.path()),
+        }
+    }
+
+    /// Keep the content at `path`, e.g. to store an upload.
+    pub fn persist(self, path: impl AsRef<Path>) -> io::Result<()> {
+        match self.body {
+            PartBody::Memory(bytes) => fs::write(path, bytes),
+            // Renaming fails across file systems, copy then
+            PartBody::File { file, .. } => match file.persist(&path) {
+                Ok(_) => Ok(()),
+                Err(e) => fs::copy(e.file.path(), path).map(|_| ()),
+            },
+        }
+    }
+}
+
+/// A parsed `multipart/form-data` body, parts in the order they were sent.
+#[derive(Debug, Default)]
+pub struct Multipart {
+    pub parts: Vec<Part>,
+}
+
+impl Multipart {
+    /// Parse the parts delimited by `boundary` from `reader`.
+    /// The body is read in chunks and files above `limits.file_threshold` are written to
+    /// temporary files as they arrive, so `reader` may be larger than memory.
+    pub fn read_from<R: Read>(reader: R, boundary: &str, limits: &MultipartLimits) -> Result<Multipart, MultipartError> {
+        // The first boundary isn't preceded by a line break, pretend it is
+        let mut input = Input { reader, buf: b"\r\n".to_vec(), pos: 0 };
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        let header_limits = Limits { max_header_bytes: limits.max_header_bytes, ..Limits::default() };
+        let mut parts = Vec::new();
+        input.copy_until(&delimiter, |_preamble| Ok(()))?;
+        loop {
+            if input.starts_with(b"--")? {
+                return Ok(Multipart { parts }); // the epilogue is ignored
+            }
+            input.copy_until(b"\r\n", |padding| match padding.iter().all(|b| *b == b' ' || *b == b'\t') {
+                true => Ok(()),
+                false => Err(MultipartError::Malformed("unexpected bytes after boundary".to_string())),
+            })?;
+            if parts.len() == limits.max_parts {
+                return Err(MultipartError::TooManyParts(limits.max_parts));
+            }
+            let headers = read_headers(&mut input, &header_limits)?;
+            let (name, filename) = content_disposition(&headers)?;
+            let mut body = PartWriter::new(&name, filename.is_some(), limits);
+            input.copy_until(&delimiter, |chunk| body.write(chunk))?;
+            let body = body.finish()?;
+            let content_type = headers.get("Content-Type").map(str::to_string);
+            parts.push(Part { name, filename, content_type, headers, body });
+        }
+    }
+
+    /// First part named `name`
+    pub fn part(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|part| part.name == name)
+    }
+
+    /// Text of the first form field `name`, see `Part::text`
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.part(name).filter(|part| part.filename.is_none()).and_then(Part::text)
+    }
+
+    /// First file sent as `name`
+    pub fn file(&self, name: &str) -> Option<&Part> {
+        self.part(name).filter(|part| part.filename.is_some())
+    }
+
+    /// The text fields as a `T`, the same way `parse_form` reads an urlencoded body.
+    pub fn fields<T: DeserializeOwned>(&self) -> Result<T, serde_urlencoded::de::Error> {
+        let fields: Vec<(&str, &str)> = self
+            .parts
+            .iter()
+            .filter(|part| part.filename.is_none())
+            .filter_map(|part| Some((part.name.as_str(), part.text()?)))
+            .collect();
+        let encoded = serde_urlencoded::to_string(fields).map_err(|e| serde::de::Error::custom(e.to_string()))?;
+        serde_urlencoded::from_str(&encoded)
+    }
+}
+
+/// A `multipart/form-data` body parsed while it was read from the connection, see `HttpRequest::upload`.
+/// Clones share it, the first `take` gets it.
+#[derive(Debug, Clone)]
+pub struct Upload(Arc<Mutex<Option<Result<Multipart, MultipartError>>>>);
+
+impl Upload {
+    pub fn new(parsed: Result<Multipart, MultipartError>) -> Self {
+        Upload(Arc::new(Mutex::new(Some(parsed))))
+    }
+
+    pub fn take(&self) -> Option<Result<Multipart, MultipartError>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+/// Parse the upload of `request` from `reader` as it arrives, instead of buffering its body.
+/// The body may take up to `limits.max_upload_bytes`, its parts `limits.multipart`.
+/// Only a broken framing or connection fails: a body that isn't valid multipart is read to its end,
+/// and its `MultipartError` left in `request.upload` for `parse_multipart`.
+pub(crate) fn read_upload<R: BufRead>(request: &mut HttpRequest, reader: &mut R, boundary: &str, limits: &Limits) -> Result<(), HttpError> {
+    let upload_limits = Limits { max_body_bytes: limits.max_upload_bytes, ..limits.clone() };
+    let mut body = BodyReader::new(reader, request.body_length(&upload_limits)?, &upload_limits);
+    let parsed = Multipart::read_from(&mut body, boundary, &limits.multipart);
+    // The epilogue, or the rest after a part too large: the next request starts after it
+    let drained = io::copy(&mut body, &mut io::sink());
+    if let Some(e) = body.take_error() {
+        return Err(e);
+    }
+    drained?;
+    request.trailers = mem::take(&mut body.trailers);
+    request.upload = Some(Upload::new(parsed));
+    Ok(())
+}
+
+/// The body being parsed, with lookahead for finding boundaries across reads.
+struct Input<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// Start of the unconsumed bytes in `buf`
+    pos: usize,
+}
+
+impl<R: Read> Input<R> {
+    fn unconsumed(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Append the next chunk of the body after the unconsumed bytes, `false` at the end of the body.
+    fn fill_more(&mut self) -> io::Result<bool> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + CHUNK_BYTES, 0);
+        let read = loop {
+            match self.reader.read(&mut self.buf[len..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                read => break read,
+            }
+        };
+        self.buf.truncate(len + *read.as_ref().unwrap_or(&0));
+        Ok(read? > 0)
+    }
+
+    fn starts_with(&mut self, prefix: &[u8]) -> io::Result<bool> {
+        while self.unconsumed().len() < prefix.len() {
+            if !self.fill_more()? {
+                break;
+            }
+        }
+        Ok(self.unconsumed().starts_with(prefix))
+    }
+
+    /// Pass the bytes up to `delimiter` to `sink` as they arrive, and skip the delimiter.
+    fn copy_until<F>(&mut self, delimiter: &[u8], mut sink: F) -> Result<(), MultipartError>
+    where
+        F: FnMut(&[u8]) -> Result<(), MultipartError>,
+    {
+        loop {
+            let unconsumed = self.unconsumed();
+            if let Some(at) = unconsumed.windows(delimiter.len()).position(|window| window == delimiter) {
+                sink(&unconsumed[..at])?;
+                self.pos += at + delimiter.len();
+                return Ok(());
+            }
+            // The delimiter may begin in the last bytes, keep them for the next round
+            let safe = unconsumed.len().saturating_sub(delimiter.len() - 1);
+            sink(&unconsumed[..safe])?;
+            self.pos += safe;
+            if !self.fill_more()? {
+                return Err(MultipartError::Malformed("body ends before the closing boundary".to_string()));
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Input<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.fill_buf()?.read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<R: Read> BufRead for Input<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.unconsumed().is_empty() {
+            self.fill_more()?;
+        }
+        Ok(self.unconsumed())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos = (self.pos + amount).min(self.buf.len());
+    }
+}
+
+/// Collects the content of a part, moving files to a temporary file past the threshold.
+struct PartWriter<'a> {
+    name: &'a str,
+    is_file: bool,
+    limits: &'a MultipartLimits,
+    memory: Vec<u8>,
+    file: Option<NamedTempFile>,
+    length: usize,
+}
+
+impl<'a> PartWriter<'a> {
+    fn new(name: &'a str, is_file: bool, limits: &'a MultipartLimits) -> Self {
+        PartWriter { name, is_file, limits, memory: Vec::new(), file: None, length: 0 }
+    }
+
+    fn write(&mut self, chunk: &[u8]) -> Result<(), MultipartError> {
+        self.length += chunk.len();
+        if self.length > self.limits.max_part_bytes {
+            return Err(MultipartError::PartTooLarge { name: self.name.to_string(), limit: self.limits.max_part_bytes });
+        }
+        if self.file.is_none() && self.is_file && self.length > self.limits.file_threshold {
+            let mut file = NamedTempFile::new()?;
+            file.write_all(&self.memory)?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+        match &mut self.file {
+            Some(file) => file.write_all(chunk)?,
+            None => self.memory.extend_from_slice(chunk),
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<PartBody, MultipartError> {
+        Ok(match self.file {
+            Some(mut file) => {
+                file.flush()?;
+                PartBody::File { file, length: self.length }
+            }
+            None => PartBody::Memory(self.memory),
+        })
+    }
+}
+
+/// `name` and `filename` of `Content-Disposition: form-data; name="avatar"; filename="me.png"`.
+/// `filename*=UTF-8''...` wins over `filename`.
+fn content_disposition(headers: &Headers) -> Result<(String, Option<String>), MultipartError> {
+    let value = headers
+        .get("Content-Disposition")
+        .ok_or_else(|| MultipartError::Malformed("part without Content-Disposition".to_string()))?;
+    let mut params = header_params(value).into_iter();
+    if !params.next().is_some_and(|(kind, _)| kind.eq_ignore_ascii_case("form-data")) {
+        return Err(MultipartError::Malformed(format!("not a form-data part: {:?}", value)));
+    }
+    let (mut name, mut filename, mut extended_filename) = (None, None, None);
+    for (key, value) in params {
+        match key.to_ascii_lowercase().as_str() {
+            "name" => name = Some(value),
+            "filename" => filename = Some(value),
+            "filename*" => {
+                // RFC 5987: charset'language'percent-encoded, only UTF-8 is worth supporting
+                if let Some((charset, rest)) = value.split_once('\'') {
+                    if charset.eq_ignore_ascii_case("utf-8") {
+                        extended_filename = rest.split_once('\'').map(|(_, encoded)| percent_decode(encoded, false));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let name = name.ok_or_else(|| MultipartError::Malformed(format!("part without name: {:?}", value)))?;
+    Ok((name, extended_filename.or(filename)))
+}
+
+/// `form-data; name="a;b"; x=1` => `[("form-data", ""), ("name", "a;b"), ("x", "1")]`
+fn header_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+    loop {
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && *c != ';') {
+            key.push(c);
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+            if chars.next_if_eq(&'"').is_some() {
+                // quoted-string, `\` escapes the next character
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => value.extend(chars.next()),
+                        c => value.push(c),
+                    }
+                }
+            }
+            while let Some(c) = chars.next_if(|c| *c != ';') {
+                value.push(c);
+            }
+        }
+        params.push((key.trim().to_string(), value.trim_end().to_string()));
+        if chars.next().is_none() {
+            return params;
+        }
+    }
+}
+
+/// Media type of `request` without its parameters, lowercase
+fn media_type(request: &HttpRequest) -> Option<String> {
+    let content_type = request.header("Content-Type")?;
+    Some(content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+}
+
+/// Deserialize an `application/x-www-form-urlencoded` body, e.g. `name=Eric&age=42`.
+/// Fail with the JSON envelope: 415 for another `Content-Type`, 400 if it doesn't fit `T`.
+pub fn parse_form<T: DeserializeOwned>(request: &HttpRequest) -> Result<T, HttpResponse> {
+    let media_type = media_type(request);
+    if media_type.as_deref() != Some("application/x-www-form-urlencoded") {
+        let error = Response::failure(415, "expected Content-Type: application/x-www-form-urlencoded", media_type);
+        return Err(error.into());
+    }
+    serde_urlencoded::from_bytes(&request.body)
+        .map_err(|e| Response::failure(400, "invalid form body", e.to_string()).into())
+}
+
+/// Boundary of a `multipart/form-data` request, `None` for another `Content-Type` or an invalid boundary
+pub(crate) fn multipart_boundary(request: &HttpRequest) -> Option<String> {
+    if media_type(request).as_deref() != Some("multipart/form-data") {
+        return None;
+    }
+    let content_type = request.header("Content-Type").unwrap_or_default();
+    header_params(content_type)
+        .into_iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+        .map(|(_, boundary)| boundary)
+        .filter(|boundary| (1..=70).contains(&boundary.len()))
+}
+
+/// Parse a `multipart/form-data` body.
+/// Fail with the JSON envelope: 415 for another `Content-Type`, then the status of the `MultipartError`.
+///
+/// An upload read from a connection was parsed on arrival with `Limits::multipart`, it can be taken once.
+/// `limits` apply to a buffered `body`, e.g. of a request built in a test.
+pub fn parse_multipart(request: &HttpRequest, limits: &MultipartLimits) -> Result<Multipart, HttpResponse> {
+    let media_type = media_type(request);
+    if media_type.as_deref() != Some("multipart/form-data") {
+        return Err(Response::failure(415, "expected Content-Type: multipart/form-data", media_type).into());
+    }
+    let Some(boundary) = multipart_boundary(request) else {
+        return Err(Response::failure(400, "missing or invalid multipart boundary", ()).into());
+    };
+    let parsed = match &request.upload {
+        Some(upload) => upload.take().ok_or_else(|| Response::failure(500, "upload already parsed", ()))?,
+        None => Multipart::read_from(request.body.as_slice(), &boundary, limits),
+    };
+    parsed.map_err(|e| {
+        // The reason of a server-side failure is for the log, not the client
+        let message = match &e {
+            MultipartError::Io(_) => "failed to buffer upload".to_string(),
+            e => e.to_string(),
+        };
+        Response::failure(e.status() as u32, message, ()).into()
+    })
+}
+
+#[cfg(test)]
+pub mod form_test_cases {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Signup {
+        name: String,
+        age: u32,
+        #[serde(default)]
+        newsletter: bool,
+    }
+
+    fn request(content_type: &str, body: &[u8]) -> HttpRequest {
+        let head = format!("POST /upload HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n", content_type, body.len());
+        let raw = [head.as_bytes(), body].concat();
+        HttpRequest::read_from(&mut raw.as_slice()).unwrap().unwrap()
+    }
+
+    /// `request`, with a multipart body parsed on arrival within `limits`
+    fn upload(content_type: &str, body: &[u8], limits: &MultipartLimits) -> HttpRequest {
+        let head = format!("POST /upload HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n", content_type, body.len());
+        let raw = [head.as_bytes(), body].concat();
+        let limits = Limits { multipart: limits.clone(), ..Limits::default() };
+        HttpRequest::read_with_limits(&mut raw.as_slice(), &limits).unwrap().unwrap()
+    }
+
+    /// `body` in chunks of at most 1000 bytes, then a trailer
+    fn chunked(body: &[u8]) -> Vec<u8> {
+        let mut chunked = Vec::new();
+        for chunk in body.chunks(1000) {
+            chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            chunked.extend_from_slice(chunk);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\nX-Checksum: 42\r\n\r\n");
+        chunked
+    }
+
+    /// Body with one part per `(disposition, content)`, boundary `XyZ`
+    fn multipart_body(parts: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = b"preamble\r\n".to_vec();
+        for (disposition, content) in parts {
+            body.extend_from_slice(format!("--XyZ\r\nContent-Disposition: {}\r\n\r\n", disposition).as_bytes());
+            body.extend_from_slice(content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--XyZ--\r\nepilogue");
+        body
+    }
+
+    /// Hands out at most 3 bytes per read, so boundaries are split across reads
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    pub fn test_parse_form() {
+        let request = request("application/x-www-form-urlencoded", b"name=Eric+Chen&age=42&newsletter=true");
+        let signup: Signup = parse_form(&request).unwrap();
+        assert_eq!(signup, Signup { name: "Eric Chen".to_string(), age: 42, newsletter: true });
+
+        let request = self::request("application/x-www-form-urlencoded", b"name=Eric&age=old");
+        assert_eq!(parse_form::<Signup>(&request).unwrap_err().status, 400);
+        let request = self::request("text/plain", b"name=Eric&age=42");
+        assert_eq!(parse_form::<Signup>(&request).unwrap_err().status, 415);
+    }
+
+    #[test]
+    pub fn test_parse_multipart() {
+        let photo: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        let body = multipart_body(&[
+            ("form-data; name=\"name\"", "Eric".as_bytes()),
+            ("form-data; name=\"age\"", b"42"),
+            ("form-data; name=\"note\"; filename=\"a \\\"b\\\".txt\"", b"line 1\r\nXyZ-- is not a boundary\r\n"),
+            ("form-data; name=\"photo\"; filename=\"x.bin\"; filename*=UTF-8''%E5%B9%B3%E5%AE%89.bin", &photo),
+        ]);
+        let limits = MultipartLimits { file_threshold: 1024, ..MultipartLimits::default() };
+        let request = upload("multipart/form-data; boundary=\"XyZ\"", &body, &limits);
+        let form = parse_multipart(&request, &limits).unwrap();
+        assert_eq!(form.parts.len(), 4);
+        assert_eq!(form.field("name"), Some("Eric"));
+        assert_eq!(form.fields::<Signup>().unwrap(), Signup { name: "Eric".to_string(), age: 42, newsletter: false });
+
+        let note = form.file("note").unwrap();
+        assert_eq!(note.filename.as_deref(), Some("a \"b\".txt"));
+        assert_eq!(note.text(), Some("line 1\r\nXyZ-- is not a boundary\r\n"));
+
+        let photo_part = form.file("photo").unwrap();
+        assert_eq!(photo_part.filename.as_deref(), Some("平安.bin"));
+        assert!(matches!(photo_part.body, PartBody::File { length: 100_000, .. }));
+        assert_eq!(photo_part.bytes().unwrap(), photo);
+
+        // Same parts when the body arrives a few bytes at a time
+        let trickled = Multipart::read_from(Trickle(&body), "XyZ", &limits).unwrap();
+        let lengths: Vec<usize> = trickled.parts.iter().map(Part::len).collect();
+        assert_eq!(lengths, vec![4, 2, 33, 100_000]);
+    }
+
+    #[test]
+    pub fn test_multipart_errors() {
+        let limits = MultipartLimits { max_parts: 2, max_part_bytes: 10, ..MultipartLimits::default() };
+        let status = |content_type: &str, body: &[u8]| parse_multipart(&upload(content_type, body, &limits), &limits).unwrap_err().status;
+
+        let three = multipart_body(&[("form-data; name=\"a\"", b"1"), ("form-data; name=\"b\"", b"2"), ("form-data; name=\"c\"", b"3")]);
+        assert_eq!(status("multipart/form-data; boundary=XyZ", &three), 413);
+        let large = multipart_body(&[("form-data; name=\"a\"; filename=\"a\"", b"more than ten bytes")]);
+        assert_eq!(status("multipart/form-data; boundary=XyZ", &large), 413);
+        let nameless = multipart_body(&[("form-data", b"1")]);
+        assert_eq!(status("multipart/form-data; boundary=XyZ", &nameless), 400);
+        assert_eq!(status("multipart/form-data; boundary=XyZ", b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1"), 400);
+        assert_eq!(status("multipart/form-data", &three), 400);
+        assert_eq!(status("application/json", &three), 415);
+    }
+
+    #[test]
+    pub fn test_streamed_upload() {
+        let photo: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        let body = multipart_body(&[("form-data; name=\"name\"", b"Eric"), ("form-data; name=\"photo\"; filename=\"x.bin\"", &photo)]);
+        let limits = Limits {
+            max_body_bytes: 16,
+            max_upload_bytes: 200_000,
+            multipart: MultipartLimits { file_threshold: 1024, ..MultipartLimits::default() },
+            ..Limits::default()
+        };
+        let head = "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let raw = [head.as_bytes(), &chunked(&body), b"GET /next HTTP/1.1\r\n\r\n"].concat();
+        let mut reader = raw.as_slice();
+
+        // Far beyond `max_body_bytes`, never in `body`, and the next request starts right after it
+        let request = HttpRequest::read_with_limits(&mut reader, &limits).unwrap().unwrap();
+        assert!(request.body.is_empty());
+        assert_eq!(request.trailers.get("X-Checksum"), Some("42"));
+        let form = parse_multipart(&request, &MultipartLimits::default()).unwrap();
+        assert_eq!(form.field("name"), Some("Eric"));
+        assert!(matches!(form.file("photo").unwrap().body, PartBody::File { length: 100_000, .. }));
+        assert_eq!(parse_multipart(&request, &MultipartLimits::default()).unwrap_err().status, 500);
+        assert_eq!(HttpRequest::read_with_limits(&mut reader, &limits).unwrap().unwrap().path, "/next");
+
+        // A part too large is the handler's to answer, the body is still read to its end
+        let small = Limits { multipart: MultipartLimits { max_part_bytes: 1000, ..MultipartLimits::default() }, ..limits.clone() };
+        let head = format!("POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n", body.len());
+        let raw = [head.as_bytes(), &body, b"GET /next HTTP/1.1\r\n\r\n"].concat();
+        let mut reader = raw.as_slice();
+        let request = HttpRequest::read_with_limits(&mut reader, &small).unwrap().unwrap();
+        assert_eq!(parse_multipart(&request, &MultipartLimits::default()).unwrap_err().status, 413);
+        assert_eq!(HttpRequest::read_with_limits(&mut reader, &small).unwrap().unwrap().path, "/next");
+
+        // Beyond `max_upload_bytes` the request itself fails
+        let tiny = Limits { max_upload_bytes: 1000, ..limits.clone() };
+        let too_large = HttpRequest::read_with_limits(&mut raw.as_slice(), &tiny);
+        assert!(matches!(too_large, Err(HttpError::PayloadTooLarge { limit: 1000, .. })));
+        let raw = [head.as_bytes(), &body[..1000]].concat();
+        assert!(matches!(HttpRequest::read_with_limits(&mut raw.as_slice(), &limits), Err(HttpError::Io(_))));
+    }
+}
@@ -0,0 +1,909 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+
+use crate::webapp::chunked::{check_chunk_end, chunk_end, parse_chunk_line, read_chunked, MAX_CHUNK_LINE};
+use crate::webapp::connection::{StreamBody, Upgrade};
+use crate::webapp::cookie::{parse_cookies, Cookie};
+use crate::webapp::error::HttpError;
+use crate::webapp::form::{multipart_boundary, read_upload, MultipartLimits, Upload};
+use crate::webapp::session::Session;
+
+/// HTTP request methods, RFC 9110 section 9
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Trace,
+    Connect,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = HttpError;
+
+    /// Method names are case-sensitive, `get` is not `GET`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "PATCH" => Ok(Method::Patch),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "CONNECT" => Ok(Method::Connect),
+            _ => Err(HttpError::NotImplemented(format!("method {}", s))),
+        }
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Protocol versions understood by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = HttpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HTTP/1.0" => Ok(Version::Http10),
+            "HTTP/1.1" => Ok(Version::Http11),
+            _ if s.starts_with("HTTP/") => Err(HttpError::VersionNotSupported(s.to_string())),
+            _ => Err(HttpError::BadRequest(format!("malformed version: {:?}", s))),
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Header fields in arrival order. Names are compared case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers { entries: Vec::new() }
+    }
+
+    /// First value of `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All values of `name`, e.g. repeated `Set-Cookie`
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether the comma-separated list header `name` contains `token`,
+    /// e.g. `Connection: keep-alive, Upgrade` contains `upgrade`.
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Replace every existing value of `name`
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Keep existing values of `name` and add another one
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Upper bounds of what a client may send.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub max_body_bytes: usize,
+    /// Longest request line (414) and largest header section (431)
+    pub max_header_bytes: usize,
+    /// Most header fields per request (431)
+    pub max_headers: usize,
+    /// Largest `multipart/form-data` body (413), parsed into `HttpRequest::upload` as it arrives instead of buffered
+    pub max_upload_bytes: usize,
+    /// Limits of the parts of an upload
+    pub multipart: MultipartLimits,
+}
+
+impl Limits {
+    /// No limits, e.g. to read responses of a trusted server
+    pub fn unlimited() -> Self {
+        Limits {
+            max_body_bytes: usize::MAX,
+            max_header_bytes: usize::MAX,
+            max_headers: usize::MAX,
+            max_upload_bytes: usize::MAX,
+            multipart: MultipartLimits::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_bytes: 1024 * 1024,
+            max_header_bytes: 8 * 1024,
+            max_headers: 100,
+            max_upload_bytes: 16 * 1024 * 1024,
+            multipart: MultipartLimits::default(),
+        }
+    }
+}
+
+/// A parsed HTTP/1.x request.
+/// Request line: `GET /hello?name=Eric HTTP/1.1`
+/// - `target` is the raw request-target: `/hello?name=Eric`
+/// - `path` is the percent-decoded path: `/hello`
+/// - `query` is the decoded query string: `{"name": "Eric"}`
+/// - `peer_addr` is the client address, filled in by `serve_connection`
+/// - `session` is the client's session, filled in by the `Sessions` middleware
+/// - `route` is the pattern of the route that handled the request: `/hello/:name`, filled in by `Router::handle`
+/// - `trailers` are the fields sent after a chunked body, kept apart from `headers`
+/// - `upload` is a `multipart/form-data` body, parsed while it was read instead of kept in `body`
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub target: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    pub peer_addr: Option<SocketAddr>,
+    pub session: Option<Session>,
+    pub route: Option<String>,
+    pub trailers: Headers,
+    pub upload: Option<Upload>,
+}
+
+impl HttpRequest {
+    /// Read one request (request line, headers and `Content-Length` or chunked body) from `reader`.
+    /// Return `Ok(None)` if the peer closed the connection before sending anything.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>, HttpError> {
+        HttpRequest::read_with_limits(reader, &Limits::default())
+    }
+
+    /// Same as `read_from`, but reject requests exceeding `limits`.
+    pub fn read_with_limits<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<HttpRequest>, HttpError> {
+        let Some(mut request) = HttpRequest::read_head(reader, limits)? else {
+            return Ok(None);
+        };
+        request.read_body(reader, limits)?;
+        Ok(Some(request))
+    }
+
+    /// Read the request line and headers only, `body` is left empty.
+    /// The caller reads the body as told by `body_length` itself, e.g. from an async stream.
+    pub fn read_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<HttpRequest>, HttpError> {
+        let Some(mut request) = HttpRequest::read_request_line(reader, limits)? else {
+            return Ok(None);
+        };
+        request.headers = read_headers(reader, limits)?;
+        Ok(Some(request))
+    }
+
+    /// Read the request line only, `headers` are left empty.
+    /// Once it's read, an error page for the rest of the request can answer in its `version`.
+    pub fn read_request_line<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<HttpRequest>, HttpError> {
+        let request_line = match read_line(reader, limits.max_header_bytes, || HttpError::UriTooLong(limits.max_header_bytes))? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) if !target.is_empty() => (method, target, version),
+            _ => return Err(HttpError::BadRequest(format!("malformed request line: {:?}", request_line))),
+        };
+        let method: Method = method.parse()?;
+        let version: Version = version.parse()?;
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_query(query)),
+            None => (target, HashMap::new()),
+        };
+        Ok(Some(HttpRequest {
+            method,
+            target: target.to_string(),
+            path: percent_decode(path, false),
+            query,
+            version,
+            headers: Headers::new(),
+            body: Vec::new(),
+            peer_addr: None,
+            session: None,
+            route: None,
+            trailers: Headers::new(),
+            upload: None,
+        }))
+    }
+
+    /// Read the body after `read_head`, framed as told by `body_length`.
+    /// An upload is parsed as it arrives, into `upload`, see `Limits::max_upload_bytes`.
+    pub fn read_body<R: BufRead>(&mut self, reader: &mut R, limits: &Limits) -> Result<(), HttpError> {
+        if let Some(boundary) = multipart_boundary(self) {
+            return read_upload(self, reader, &boundary, limits);
+        }
+        match self.body_length(limits)? {
+            BodyLength::Fixed(length) => {
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body)?;
+                self.body = body;
+            }
+            BodyLength::Chunked => (self.body, self.trailers) = read_chunked(reader, limits)?,
+        }
+        Ok(())
+    }
+
+    /// How the body is framed, 413 if its `Content-Length` exceeds `limits.max_body_bytes`.
+    pub fn body_length(&self, limits: &Limits) -> Result<BodyLength, HttpError> {
+        // HTTP/1.0 has no transfer codings, RFC 9112 section 6.1
+        if self.version == Version::Http10 && self.headers.contains("Transfer-Encoding") {
+            return Err(HttpError::BadRequest("Transfer-Encoding in an HTTP/1.0 request".to_string()));
+        }
+        body_length(&self.headers, limits.max_body_bytes)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Value of the cookie `name`, from any `Cookie` header
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.headers
+            .get_all("Cookie")
+            .flat_map(parse_cookies)
+            .find(|(cookie, _)| cookie == name)
+            .map(|(_, value)| value)
+    }
+
+    /// HTTP/1.1 connections persist unless `Connection: close`,
+    /// HTTP/1.0 connections only persist with `Connection: keep-alive`.
+    pub fn wants_keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !self.headers.contains_token("Connection", "close"),
+            Version::Http10 => self.headers.contains_token("Connection", "keep-alive"),
+        }
+    }
+}
+
+/// `Server` header of every response, unless the handler set one
+pub const SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Response produced by a handler. `Content-Length`, `Date` and `Server` are filled in by `write_to`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Takes over the connection after a `101 Switching Protocols` response
+    pub upgrade: Option<Upgrade>,
+    /// Written after the head instead of `body`: chunked for HTTP/1.1,
+    /// for HTTP/1.0 the connection closes when it ends
+    pub stream: Option<StreamBody>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> Self {
+        HttpResponse { status, headers: Headers::new(), body: Vec::new(), upgrade: None, stream: None }
+    }
+
+    /// 200, text/html
+    pub fn html(body: impl Into<String>) -> Self {
+        HttpResponse::new(200)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body.into())
+    }
+
+    /// text/plain response with the reason phrase as body, e.g. `404 Not Found`
+    pub fn status_page(status: u16) -> Self {
+        HttpResponse::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(format!("{} {}", status, reason_phrase(status)))
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Add a `Set-Cookie` header, other cookies are kept
+    pub fn with_cookie(mut self, cookie: &Cookie) -> Self {
+        self.headers.append("Set-Cookie", cookie.to_string());
+        self
+    }
+
+    pub fn with_upgrade(mut self, upgrade: Upgrade) -> Self {
+        self.upgrade = Some(upgrade);
+        self
+    }
+
+    pub fn with_stream(mut self, stream: StreamBody) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Read a response written by `write_to`, e.g. on the client side of a test.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<HttpResponse, HttpError> {
+        let limits = Limits::unlimited();
+        let status_line = read_line(reader, limits.max_header_bytes, || HttpError::UriTooLong(limits.max_header_bytes))?
+            .ok_or_else(|| unexpected_eof("connection closed before response"))?;
+        let mut parts = status_line.splitn(3, ' ');
+        let status = match (parts.next().map(str::parse::<Version>), parts.next().map(str::parse::<u16>)) {
+            (Some(Ok(_)), Some(Ok(status))) => status,
+            _ => return Err(HttpError::BadRequest(format!("malformed status line: {:?}", status_line))),
+        };
+        let headers = read_headers(reader, &limits)?;
+        let body = match body_length(&headers, limits.max_body_bytes)? {
+            BodyLength::Fixed(length) => {
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body)?;
+                body
+            }
+            BodyLength::Chunked => read_chunked(reader, &limits)?.0,
+        };
+        Ok(HttpResponse { status, headers, body, upgrade: None, stream: None })
+    }
+
+    /// Write status line, headers and body to `writer`.
+    /// Of a streamed response only the head is written, its body is up to the connection.
+    pub fn write_to<W: Write>(&self, version: Version, writer: &mut W) -> io::Result<()> {
+        self.write(version, writer, true)
+    }
+
+    /// `write_to` answering a `HEAD` request: the head of the full response, without its body.
+    pub fn write_head_to<W: Write>(&self, version: Version, writer: &mut W) -> io::Result<()> {
+        self.write(version, writer, false)
+    }
+
+    fn write<W: Write>(&self, version: Version, writer: &mut W, with_body: bool) -> io::Result<()> {
+        let mut head = format!("{} {} {}\r\n", version, self.status, reason_phrase(self.status));
+        if !self.headers.contains("Date") {
+            head.push_str(&format!("Date: {}\r\n", http_date(SystemTime::now())));
+        }
+        if !self.headers.contains("Server") {
+            head.push_str(&format!("Server: {}\r\n", SERVER));
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // 204 and 304 never have a body, a streamed one of unknown length is chunked or ends with the connection
+        let has_body = !matches!(self.status, 100..=199 | 204 | 304);
+        match self.stream.as_ref().map(StreamBody::length) {
+            _ if !has_body => {}
+            None => head.push_str(&format!("Content-Length: {}\r\n", self.body.len())),
+            Some(Some(length)) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            Some(None) if version == Version::Http11 => head.push_str("Transfer-Encoding: chunked\r\n"),
+            Some(None) => {}
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+        if with_body && self.stream.is_none() {
+            writer.write_all(&self.body)?;
+        }
+        writer.flush()
+    }
+}
+
+/// Reason phrases of the status codes used by this server.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// Format as IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Parse an IMF-fixdate, e.g. `If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    DateTime::parse_from_rfc2822(s).ok().map(SystemTime::from)
+}
+
+/// Parse `a=1&b=hello+world` into a map. Later duplicated keys win.
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) => (percent_decode(k, true), percent_decode(v, true)),
+            None => (percent_decode(pair, true), String::new()),
+        })
+        .collect()
+}
+
+/// Decode `%XX` escapes. Invalid escapes are kept as they are.
+/// `plus_as_space` is for query strings and form bodies, where `+` means ` `.
+pub fn percent_decode(s: &str, plus_as_space: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        decoded.push(hi << 4 | lo);
+                        i += 3;
+                        continue;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encode every byte but the unreserved characters as `%XX`, e.g. a file name as a path segment.
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Read header lines up to the empty line ending the header section.
+/// Fail with 431 if the section exceeds `limits.max_header_bytes` or `limits.max_headers`.
+pub(crate) fn read_headers<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Headers, HttpError> {
+    let mut headers = Headers::new();
+    let mut remaining = limits.max_header_bytes;
+    loop {
+        let too_large = || HttpError::HeadersTooLarge(format!("header section exceeds {} bytes", limits.max_header_bytes));
+        let line = read_line(reader, remaining, too_large)?.ok_or_else(|| unexpected_eof("connection closed in headers"))?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        remaining -= line.len();
+        if headers.len() == limits.max_headers {
+            return Err(HttpError::HeadersTooLarge(format!("more than {} header fields", limits.max_headers)));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| HttpError::BadRequest(format!("malformed header: {:?}", line)))?;
+        if !is_token(name) {
+            return Err(HttpError::BadRequest(format!("malformed header name: {:?}", name)));
+        }
+        headers.append(name, value.trim_matches([' ', '\t']));
+    }
+}
+
+/// `1*tchar`, the grammar of header names and methods, RFC 9110 section 5.6.2
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// How the end of a message body is found, RFC 9112 section 6.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    /// `Content-Length` bytes, no header means no body
+    Fixed(usize),
+    /// `Transfer-Encoding: chunked`, read with `chunked::read_chunked`
+    Chunked,
+}
+
+/// Reads a request body as it arrives, framed as told by `body_length`, e.g. to parse an upload without buffering it.
+/// A broken framing or connection fails every read from then on, `take_error` tells with which `HttpError`.
+pub struct BodyReader<'a, R: BufRead> {
+    reader: &'a mut R,
+    limits: &'a Limits,
+    framing: BodyLength,
+    /// Left of the body, or of the current chunk
+    remaining: usize,
+    /// Decoded so far, for the limit of chunked bodies
+    length: usize,
+    done: bool,
+    /// Fields after a chunked body, once it's read to its end
+    pub trailers: Headers,
+    error: Option<HttpError>,
+}
+
+impl<'a, R: BufRead> BodyReader<'a, R> {
+    pub fn new(reader: &'a mut R, framing: BodyLength, limits: &'a Limits) -> Self {
+        let remaining = match framing {
+            BodyLength::Fixed(length) => length,
+            BodyLength::Chunked => 0,
+        };
+        BodyReader { reader, limits, framing, remaining, length: 0, done: false, trailers: Headers::new(), error: None }
+    }
+
+    /// Why reading failed
+    pub fn take_error(&mut self) -> Option<HttpError> {
+        self.error.take()
+    }
+
+    fn read_body(&mut self, buf: &mut [u8]) -> Result<usize, HttpError> {
+        if buf.is_empty() || self.done {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            if self.framing != BodyLength::Chunked {
+                self.done = true;
+                return Ok(0);
+            }
+            let mut line = Vec::new();
+            (&mut *self.reader).take(MAX_CHUNK_LINE as u64 + 2).read_until(b'\n', &mut line)?;
+            let size = parse_chunk_line(&line)?;
+            if size == 0 {
+                self.trailers = read_headers(self.reader, self.limits)?;
+                self.done = true;
+                return Ok(0);
+            }
+            self.length = chunk_end(self.length, size, self.limits)?;
+            self.remaining = size;
+        }
+        let wanted = buf.len().min(self.remaining);
+        let read = self.reader.read(&mut buf[..wanted])?;
+        if read == 0 {
+            return Err(unexpected_eof("connection closed in body").into());
+        }
+        self.remaining -= read;
+        if self.remaining == 0 && self.framing == BodyLength::Chunked {
+            let mut crlf = [0; 2];
+            self.reader.read_exact(&mut crlf)?;
+            check_chunk_end(&crlf)?;
+        }
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> Read for BodyReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.error.is_none() {
+            match self.read_body(buf) {
+                Ok(read) => return Ok(read),
+                Err(e) => self.error = Some(e),
+            }
+        }
+        Err(io::Error::other(self.error.as_ref().map_or("body already failed".to_string(), |e| e.to_string())))
+    }
+}
+
+fn body_length(headers: &Headers, max_body_bytes: usize) -> Result<BodyLength, HttpError> {
+    let codings: Vec<String> = headers
+        .get_all("Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    if codings.is_empty() {
+        return content_length(headers, max_body_bytes).map(BodyLength::Fixed);
+    }
+    // Two framings at once is how requests get smuggled past a proxy
+    if headers.contains("Content-Length") {
+        return Err(HttpError::BadRequest("both Transfer-Encoding and Content-Length".to_string()));
+    }
+    match codings.as_slice() {
+        [coding] if coding == "chunked" => Ok(BodyLength::Chunked),
+        [.., last] if last == "chunked" => Err(HttpError::NotImplemented(format!("transfer coding {}", codings.join(", ")))),
+        _ => Err(HttpError::BadRequest("chunked must be the final transfer coding".to_string())),
+    }
+}
+
+/// `Content-Length` of the body, 0 without one.
+/// Repeated values must all be the same `1*DIGIT`: two lengths is another way to smuggle a request.
+fn content_length(headers: &Headers, max_body_bytes: usize) -> Result<usize, HttpError> {
+    let values: Vec<&str> = headers.get_all("Content-Length").flat_map(|value| value.split(',')).map(str::trim).collect();
+    let Some(&length) = values.first() else {
+        return Ok(0);
+    };
+    let invalid = || HttpError::BadRequest(format!("invalid Content-Length: {:?}", values.join(", ")));
+    // `parse` takes a leading `+`, the grammar doesn't
+    if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) || values.iter().any(|value| *value != length) {
+        return Err(invalid());
+    }
+    let length: usize = length.parse().map_err(|_| invalid())?;
+    if length > max_body_bytes {
+        return Err(HttpError::PayloadTooLarge { length, limit: max_body_bytes });
+    }
+    Ok(length)
+}
+
+/// Read a line terminated by `\r\n` (or a bare `\n`) without the terminator.
+/// Return `None` on EOF before any byte was read, and `too_long()` once the line exceeds `max_bytes`
+/// without reading the rest of it.
+fn read_line<R, F>(reader: &mut R, max_bytes: usize, too_long: F) -> Result<Option<String>, HttpError>
+where
+    R: BufRead,
+    F: FnOnce() -> HttpError,
+{
+    let mut line = Vec::new();
+    let limit = max_bytes.saturating_add(2) as u64; // room for the terminator
+    if reader.by_ref().take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() as u64 == limit {
+            return Err(too_long());
+        }
+        return Err(unexpected_eof("connection closed in the middle of a line").into());
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    if line.len() > max_bytes {
+        return Err(too_long());
+    }
+    // Some recipients take a bare CR for a line break, RFC 9112 section 2.2
+    if line.contains(&b'\r') {
+        return Err(HttpError::BadRequest("bare CR in a line".to_string()));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| HttpError::BadRequest("line is not valid UTF-8".to_string()))
+}
+
+pub(crate) fn unexpected_eof(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, message)
+}
+
+#[cfg(test)]
+pub mod http_test_cases {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<HttpRequest>, HttpError> {
+        HttpRequest::read_from(&mut raw.as_bytes())
+    }
+
+    #[test]
+    pub fn test_parse_request_line() {
+        let request = parse("GET /hello%20world?name=Eric&lang=zh+CN HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.target, "/hello%20world?name=Eric&lang=zh+CN");
+        assert_eq!(request.path, "/hello world");
+        assert_eq!(request.query.get("name").map(String::as_str), Some("Eric"));
+        assert_eq!(request.query.get("lang").map(String::as_str), Some("zh CN"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    pub fn test_parse_headers_and_body() {
+        let raw = "POST /echo HTTP/1.0\r\nContent-Type: text/plain\r\ncontent-length: 5\r\nX-Tag: a\r\nX-Tag:b\r\n\r\nhello";
+        let request = parse(raw).unwrap().unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.version, Version::Http10);
+        assert_eq!(request.header("CONTENT-TYPE"), Some("text/plain"));
+        assert_eq!(request.headers.get_all("x-tag").collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    pub fn test_parse_eof_and_errors() {
+        assert!(parse("").unwrap().is_none());
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost").unwrap_err().status(), None);
+        assert_eq!(parse("GET /\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("FETCH / HTTP/1.1\r\n\r\n").unwrap_err().status(), Some(501));
+        assert_eq!(parse("GET / HTTP/2.0\r\n\r\n").unwrap_err().status(), Some(505));
+        assert_eq!(parse("GET / FTP\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\nNoColon\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\nshort").unwrap_err().status(), None);
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello").unwrap_err().status(), Some(400));
+        let twice = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 0\r\n\r\nhello";
+        assert_eq!(parse(twice).unwrap_err().status(), Some(400));
+        let same = parse("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello").unwrap().unwrap();
+        assert_eq!(same.body, b"hello");
+        let smuggled = "POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(parse(smuggled).unwrap_err().status(), Some(400));
+        assert_eq!(parse("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").unwrap_err().status(), Some(501));
+        assert_eq!(parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(HttpRequest::read_from(&mut &b"GET /\xff HTTP/1.1\r\n\r\n"[..]).unwrap_err().status(), Some(400));
+        // A bare CR is no line break, and never ends up in a target or a header value
+        assert_eq!(parse("GET /docs\rSet-Cookie:x HTTP/1.1\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\nX-Tag: a\rb\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\nX-Tag: a\r\r\n\r\n").unwrap_err().status(), Some(400));
+        // Header names are tokens
+        assert_eq!(parse("GET / HTTP/1.1\r\nX Tag: a\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\nX-(Tag): a\r\n\r\n").unwrap_err().status(), Some(400));
+        assert_eq!(parse("GET / HTTP/1.1\r\n X-Tag: a\r\n\r\n").unwrap_err().status(), Some(400));
+    }
+
+    #[test]
+    pub fn test_body_limit() {
+        let limits = Limits { max_body_bytes: 4, ..Limits::default() };
+        let ok = HttpRequest::read_with_limits(&mut "POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd".as_bytes(), &limits);
+        assert_eq!(ok.unwrap().unwrap().body, b"abcd");
+        let too_large = HttpRequest::read_with_limits(&mut "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde".as_bytes(), &limits);
+        assert_eq!(too_large.unwrap_err().status(), Some(413));
+    }
+
+    #[test]
+    pub fn test_header_limits() {
+        let limits = Limits { max_header_bytes: 32, max_headers: 2, ..Limits::default() };
+        let read = |raw: &str| HttpRequest::read_with_limits(&mut raw.as_bytes(), &limits);
+        assert!(read("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n").is_ok());
+        assert_eq!(read(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32))).unwrap_err().status(), Some(414));
+        assert_eq!(read("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n").unwrap_err().status(), Some(431));
+        assert_eq!(read("GET / HTTP/1.1\r\nA: 0123456789abcdef\r\nB: 0123456789abcdef\r\n\r\n").unwrap_err().status(), Some(431));
+        // The limit is checked before the rest of an endless line is read
+        let endless = format!("GET / HTTP/1.1\r\nA: {}", "a".repeat(1000));
+        assert_eq!(read(&endless).unwrap_err().status(), Some(431));
+    }
+
+    #[test]
+    pub fn test_wants_keep_alive() {
+        assert!(parse("GET / HTTP/1.1\r\n\r\n").unwrap().unwrap().wants_keep_alive());
+        assert!(!parse("GET / HTTP/1.1\r\nConnection: Close\r\n\r\n").unwrap().unwrap().wants_keep_alive());
+        assert!(!parse("GET / HTTP/1.0\r\n\r\n").unwrap().unwrap().wants_keep_alive());
+        assert!(parse("GET / HTTP/1.0\r\nConnection: TE, keep-alive\r\n\r\n").unwrap().unwrap().wants_keep_alive());
+    }
+
+    #[test]
+    pub fn test_write_and_read_response() {
+        let response = HttpResponse::html("<h1>hi</h1>").with_header("X-Tag", "a");
+        let mut buf = Vec::new();
+        response.write_to(Version::Http11, &mut buf).unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        let read = HttpResponse::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(read.status, 200);
+        assert_eq!(read.headers.get("x-tag"), Some("a"));
+        assert_eq!(read.headers.get("Content-Length"), Some("11"));
+        assert_eq!(read.headers.get("Server"), Some(SERVER));
+        assert!(read.headers.get("Date").and_then(parse_http_date).is_some());
+        assert_eq!(read.body, b"<h1>hi</h1>");
+
+        // Same head for HEAD, without the body
+        let mut head = Vec::new();
+        response.write_head_to(Version::Http10, &mut head).unwrap();
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(head.ends_with("Content-Length: 11\r\n\r\n"));
+    }
+
+    #[test]
+    pub fn test_http_date() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    pub fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Fb", false), "a/b");
+        assert_eq!(percent_decode("a+b", false), "a+b");
+        assert_eq!(percent_decode("a+b", true), "a b");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%zz%4", false), "%zz%4");
+        assert_eq!(percent_decode("%E5%B9%B3%E5%AE%89", false), "平安");
+        assert_eq!(percent_encode("100% #1?.txt"), "100%25%20%231%3F.txt");
+        assert_eq!(percent_encode("平安"), "%E5%B9%B3%E5%AE%89");
+        assert_eq!(percent_decode(&percent_encode("a/b:c"), false), "a/b:c");
+    }
+}
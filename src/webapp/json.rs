@@ -0,0 +1,159 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::webapp::http::{HttpRequest, HttpResponse};
+use crate::webapp::router::Params;
+
+/// Envelope of every JSON API response, `code` is also the HTTP status if it is one.
+/// ```json
+/// { "code": 200, "message": "ok", "data": { "name": "Eric" } }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response<T> {
+    pub code: u32,
+    pub message: String,
+    pub data: T,
+}
+
+impl<T> Response<T> {
+    /// Failure with any `code`, e.g. 400, and the details of the error in `data`
+    pub fn failure(code: u32, message: impl Into<String>, data: T) -> Response<T> {
+        Response { code, message: message.into(), data }
+    }
+
+    /// Success with `code`, e.g. 200 or 201, and the message `"ok"`
+    pub fn success(code: u32, data: T) -> Response<T> {
+        Response { code, message: "ok".to_string(), data }
+    }
+}
+
+impl<T: Serialize> From<Response<T>> for HttpResponse {
+    /// `application/json` response, business codes outside 100..=599 are sent as 200.
+    fn from(value: Response<T>) -> Self {
+        let status = match value.code {
+            code @ 100..=599 => code as u16,
+            _ => 200,
+        };
+        match serde_json::to_vec(&value) {
+            Ok(body) => HttpResponse::new(status)
+                .with_header("Content-Type", "application/json; charset=utf-8")
+                .with_body(body),
+            Err(e) => Response::failure(500, "failed to serialize response", e.to_string()).into(),
+        }
+    }
+}
+
+/// Deserialize the JSON body of `request`.
+/// Fail with the envelope: 415 without a JSON `Content-Type`, 400 with where and why the body is invalid.
+pub fn parse_json<T: DeserializeOwned>(request: &HttpRequest) -> Result<T, HttpResponse> {
+    let media_type = request.header("Content-Type").and_then(|value| value.split(';').next()).map(str::trim);
+    if !media_type.is_some_and(|media_type| media_type.eq_ignore_ascii_case("application/json")) {
+        let error = Response::failure(415, "expected Content-Type: application/json", media_type.map(str::to_string));
+        return Err(error.into());
+    }
+    serde_json::from_slice(&request.body).map_err(|e| {
+        let category = match e.classify() {
+            serde_json::error::Category::Io => "io",
+            serde_json::error::Category::Syntax => "syntax",
+            serde_json::error::Category::Data => "data",
+            serde_json::error::Category::Eof => "eof",
+        };
+        let details = json!({ "category": category, "line": e.line(), "column": e.column(), "error": e.to_string() });
+        Response::failure(400, "invalid JSON body", details).into()
+    })
+}
+
+/// Route handler with a JSON body `B`, answering with the envelope.
+/// ```ignore
+/// router.post("/api/greetings", json(|greeting: Greeting, _, _| Response::success(201, greeting)))
+/// ```
+pub fn json<B, T, F>(handler: F) -> impl Fn(&HttpRequest, &Params) -> HttpResponse + Send + Sync + 'static
+where
+    B: DeserializeOwned,
+    T: Serialize,
+    F: Fn(B, &HttpRequest, &Params) -> Response<T> + Send + Sync + 'static,
+{
+    move |request, params| match parse_json(request) {
+        Ok(body) => handler(body, request, params).into(),
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+pub mod json_test_cases {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::webapp::router::Router;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Greeting {
+        name: String,
+        wishes: Vec<String>,
+    }
+
+    fn post(router: &Router, content_type: &str, body: &str) -> (u16, Value) {
+        let raw = format!(
+            "POST /api/greetings HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        );
+        let response = router.dispatch(&HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap());
+        assert_eq!(response.headers.get("Content-Type"), Some("application/json; charset=utf-8"));
+        (response.status, serde_json::from_slice(&response.body).unwrap())
+    }
+
+    fn router() -> Router {
+        Router::new().post(
+            "/api/greetings",
+            json(|greeting: Greeting, _, _| {
+                if greeting.name.is_empty() {
+                    Response::failure(422, "name must not be empty", None)
+                } else {
+                    Response::success(201, Some(greeting))
+                }
+            }),
+        )
+    }
+
+    #[test]
+    pub fn test_envelope() {
+        let response: HttpResponse = Response::success(200, vec![1, 2]).into();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, br#"{"code":200,"message":"ok","data":[1,2]}"#);
+        let response: HttpResponse = Response::failure(10001, "quota exceeded", ()).into();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, br#"{"code":10001,"message":"quota exceeded","data":null}"#);
+    }
+
+    #[test]
+    pub fn test_json_handler() {
+        let router = router();
+        let (status, body) = post(&router, "application/json", r#"{"name": "Eric", "wishes": ["平安喜乐"]}"#);
+        assert_eq!(status, 201);
+        assert_eq!(body["data"]["wishes"][0], "平安喜乐");
+        let (status, body) = post(&router, "application/json; charset=utf-8", r#"{"name": "", "wishes": []}"#);
+        assert_eq!(status, 422);
+        assert_eq!(body["message"], "name must not be empty");
+    }
+
+    #[test]
+    pub fn test_bad_json() {
+        let router = router();
+        let (status, body) = post(&router, "application/json", "{\n  \"name\": \"Eric\",,\n}");
+        assert_eq!(status, 400);
+        assert_eq!(body["code"], 400);
+        assert_eq!(body["data"]["category"], "syntax");
+        assert_eq!(body["data"]["line"], 2);
+
+        let (status, body) = post(&router, "application/json", r#"{"name": 1}"#);
+        assert_eq!(status, 400);
+        assert_eq!(body["data"]["category"], "data");
+
+        let (status, body) = post(&router, "text/plain", r#"{"name": "Eric", "wishes": []}"#);
+        assert_eq!(status, 415);
+        assert_eq!(body["data"], "text/plain");
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::webapp::connection::Exchange;
+use crate::webapp::http::{HttpRequest, HttpResponse};
+use crate::webapp::middleware::{Middleware, Next};
+
+/// Path answered with the metrics
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Value that only goes up, e.g. requests served.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value that goes up and down, e.g. open connections.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    pub fn add(&self, amount: i64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Distribution of observed values over fixed buckets, e.g. request latency.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramState {
+    /// Observations per bucket, not cumulative. The last one is `+Inf`.
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    /// Buckets with the upper `bounds`, in increasing order, plus `+Inf`.
+    pub fn new(bounds: &[f64]) -> Self {
+        let state = HistogramState { counts: vec![0; bounds.len() + 1], sum: 0.0, count: 0 };
+        Histogram { bounds: bounds.to_vec(), state: Mutex::new(state) }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.counts[bucket] += 1;
+        state.sum += value;
+        state.count += 1;
+    }
+
+    pub fn snapshot(&self) -> HistogramState {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// One metric per combination of label values, created on first use.
+pub struct Family<M> {
+    label_names: &'static [&'static str],
+    metrics: Mutex<BTreeMap<Vec<String>, Arc<M>>>,
+    new_metric: Box<dyn Fn() -> M + Send + Sync>,
+}
+
+/// Label names paired with their values
+type Labels = Vec<(&'static str, String)>;
+
+impl<M> Family<M> {
+    pub fn new<F>(label_names: &'static [&'static str], new_metric: F) -> Self
+    where
+        F: Fn() -> M + Send + Sync + 'static,
+    {
+        Family { label_names, metrics: Mutex::new(BTreeMap::new()), new_metric: Box::new(new_metric) }
+    }
+
+    /// The metric for `values`, one per label name.
+    pub fn get(&self, values: &[&str]) -> Arc<M> {
+        debug_assert_eq!(values.len(), self.label_names.len());
+        let key: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(metrics.entry(key).or_insert_with(|| Arc::new((self.new_metric)())))
+    }
+
+    /// Every metric with its labels, sorted by label values
+    fn collect(&self) -> Vec<(Labels, Arc<M>)> {
+        let metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        metrics
+            .iter()
+            .map(|(values, metric)| (self.label_names.iter().copied().zip(values.iter().cloned()).collect(), Arc::clone(metric)))
+            .collect()
+    }
+}
+
+/// Builds the Prometheus text exposition format, version 0.0.4.
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(self.text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, String)], value: impl Display) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(name, value)| {
+                    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+                    format!("{}=\"{}\"", name, value)
+                })
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {}", value);
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, String)], bounds: &[f64], state: &HistogramState) {
+        let mut cumulative = 0;
+        for (i, count) in state.counts.iter().enumerate() {
+            cumulative += count;
+            let le = bounds.get(i).map(|bound| bound.to_string()).unwrap_or_else(|| "+Inf".to_string());
+            let mut labels = labels.to_vec();
+            labels.push(("le", le));
+            self.sample(&format!("{}_bucket", name), &labels, cumulative);
+        }
+        self.sample(&format!("{}_sum", name), labels, state.sum);
+        self.sample(&format!("{}_count", name), labels, state.count);
+    }
+}
+
+/// Numbers of the server, served on `METRICS_PATH` in the Prometheus text format.
+///
+/// As a middleware it counts and times the requests passing through it. The endpoint is a route
+/// like any other, so it's rate limited and answers `HEAD` too.
+/// Connections and the worker queue are reported by the server loops, the bytes of the responses
+/// by the `Observer` of the connections once they're written. Clones share the same metrics.
+/// ```ignore
+/// let metrics = Metrics::new();
+/// let router = router.get(METRICS_PATH, { let metrics = metrics.clone(); move |_, _| metrics.response() });
+/// let pipeline = Pipeline::new(router).with(metrics.clone());
+/// let _connection = metrics.track_connection(); // while serving a connection
+/// let observer: Observer = Arc::new(move |exchange| metrics.count_sent(exchange));
+/// ```
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Registry>,
+}
+
+struct Registry {
+    requests: Family<Counter>,
+    latency: Family<Histogram>,
+    request_bytes: Counter,
+    response_bytes: Counter,
+    connections: Counter,
+    active_connections: Gauge,
+    queue_depth: Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry {
+            requests: Family::new(&["method", "path", "status"], Counter::default),
+            latency: Family::new(&["method", "path"], || Histogram::new(&LATENCY_BUCKETS)),
+            request_bytes: Counter::default(),
+            response_bytes: Counter::default(),
+            connections: Counter::default(),
+            active_connections: Gauge::default(),
+            queue_depth: Gauge::default(),
+        };
+        Metrics { inner: Arc::new(registry) }
+    }
+
+    /// Count a connection as active until the returned value is dropped.
+    pub fn track_connection(&self) -> ActiveConnection {
+        self.inner.connections.inc();
+        self.inner.active_connections.inc();
+        ActiveConnection { metrics: self.clone() }
+    }
+
+    /// Connections accepted, but not picked up by a worker yet
+    pub fn queue_depth(&self) -> &Gauge {
+        &self.inner.queue_depth
+    }
+
+    /// Record a request answered with `response`. `route` is the pattern of the route that ran,
+    /// `None` if none did (404, or a middleware answered), so the label values stay few.
+    pub fn observe(&self, request: &HttpRequest, route: Option<&str>, response: &HttpResponse, seconds: f64) {
+        let (method, path) = (request.method.as_str(), route.unwrap_or("unmatched"));
+        self.inner.requests.get(&[method, path, &response.status.to_string()]).inc();
+        self.inner.latency.get(&[method, path]).observe(seconds);
+        self.inner.request_bytes.inc_by(request.body.len() as u64);
+    }
+
+    /// Count the body bytes of `exchange` that went on the wire, as its `Observer`
+    pub fn count_sent(&self, exchange: &Exchange) {
+        self.inner.response_bytes.inc_by(exchange.bytes);
+    }
+
+    /// `200 text/plain` response with all metrics, for the route on `METRICS_PATH`
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::new(200)
+            .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .with_body(self.render())
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let registry = &self.inner;
+        let mut out = Exposition::default();
+        out.header("http_requests_total", "counter", "Requests answered, by method, route and status.");
+        for (labels, counter) in registry.requests.collect() {
+            out.sample("http_requests_total", &labels, counter.get());
+        }
+        out.header("http_request_duration_seconds", "histogram", "Time from the parsed request to the response, by method and route.");
+        for (labels, histogram) in registry.latency.collect() {
+            out.histogram("http_request_duration_seconds", &labels, &histogram.bounds, &histogram.snapshot());
+        }
+        out.header("http_request_body_bytes_total", "counter", "Bytes of request bodies received.");
+        out.sample("http_request_body_bytes_total", &[], registry.request_bytes.get());
+        out.header("http_response_body_bytes_total", "counter", "Bytes of response bodies written to the socket.");
+        out.sample("http_response_body_bytes_total", &[], registry.response_bytes.get());
+        out.header("http_connections_total", "counter", "Connections accepted.");
+        out.sample("http_connections_total", &[], registry.connections.get());
+        out.header("http_connections_active", "gauge", "Connections being served.");
+        out.sample("http_connections_active", &[], registry.active_connections.get());
+        out.header("worker_queue_depth", "gauge", "Connections waiting for a worker thread.");
+        out.sample("worker_queue_depth", &[], registry.queue_depth.get());
+        out.text
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Middleware for Metrics {
+    fn handle(&self, request: &mut HttpRequest, next: Next<'_>) -> HttpResponse {
+        let started = Instant::now();
+        let response = next.run(request);
+        self.observe(request, request.route.as_deref(), &response, started.elapsed().as_secs_f64());
+        response
+    }
+}
+
+/// A connection counted in `http_connections_active`, see `Metrics::track_connection`.
+pub struct ActiveConnection {
+    metrics: Metrics,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics.inner.active_connections.dec();
+    }
+}
+
+#[cfg(test)]
+pub mod metrics_test_cases {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::webapp::middleware::Pipeline;
+    use crate::webapp::router::Router;
+
+    fn get(pipeline: &Pipeline, target: &str) -> HttpResponse {
+        request(pipeline, "GET", target)
+    }
+
+    fn request(pipeline: &Pipeline, method: &str, target: &str) -> HttpResponse {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+        pipeline.handle(&mut HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap())
+    }
+
+    #[test]
+    pub fn test_histogram() {
+        let histogram = Histogram::new(&[0.25, 1.0]);
+        for value in [0.125, 0.25, 0.5, 3.0] {
+            histogram.observe(value);
+        }
+        let mut out = Exposition::default();
+        out.histogram("latency", &[("path", "/".to_string())], &histogram.bounds, &histogram.snapshot());
+        assert_eq!(
+            out.text,
+            "latency_bucket{path=\"/\",le=\"0.25\"} 2\n\
+             latency_bucket{path=\"/\",le=\"1\"} 3\n\
+             latency_bucket{path=\"/\",le=\"+Inf\"} 4\n\
+             latency_sum{path=\"/\"} 3.875\n\
+             latency_count{path=\"/\"} 4\n"
+        );
+    }
+
+    #[test]
+    pub fn test_label_escaping() {
+        let mut out = Exposition::default();
+        out.sample("x", &[("path", "a\"b\\c\nd".to_string())], 1);
+        assert_eq!(out.text, "x{path=\"a\\\"b\\\\c\\nd\"} 1\n");
+    }
+
+    #[test]
+    pub fn test_metrics_endpoint() {
+        let metrics = Metrics::new();
+        let endpoint = metrics.clone();
+        let router = Router::new()
+            .get("/hello/:name", |_, params| HttpResponse::new(200).with_body(params.get("name").unwrap().to_string()))
+            .get(METRICS_PATH, move |_, _| endpoint.response());
+        let pipeline = Pipeline::new(router).with(metrics.clone());
+        get(&pipeline, "/hello/Eric");
+        get(&pipeline, "/hello/Fan");
+        get(&pipeline, "/nowhere");
+        // Counted, but no body is sent
+        request(&pipeline, "HEAD", "/hello/Eric");
+        // Bytes come from the connections, streams and error pages included
+        for bytes in [4, 16, 0] {
+            metrics.count_sent(&Exchange { request: None, peer_addr: None, status: 200, bytes, latency: Duration::ZERO });
+        }
+        let connection = metrics.track_connection();
+        metrics.queue_depth().inc();
+
+        let response = get(&pipeline, METRICS_PATH);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain; version=0.0.4; charset=utf-8"));
+        let text = String::from_utf8(response.body).unwrap();
+        for line in [
+            "# TYPE http_requests_total counter",
+            "http_requests_total{method=\"GET\",path=\"/hello/:name\",status=\"200\"} 2",
+            "http_requests_total{method=\"GET\",path=\"unmatched\",status=\"404\"} 1",
+            "http_request_duration_seconds_count{method=\"GET\",path=\"/hello/:name\"} 2",
+            "http_request_duration_seconds_bucket{method=\"GET\",path=\"/hello/:name\",le=\"+Inf\"} 2",
+            "http_requests_total{method=\"HEAD\",path=\"/hello/:name\",status=\"200\"} 1",
+            "http_response_body_bytes_total 20",
+            "http_connections_active 1",
+            "worker_queue_depth 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{:?} not in\n{}", line, text);
+        }
+        assert_eq!(request(&pipeline, "HEAD", METRICS_PATH).status, 200);
+        drop(connection);
+        assert!(metrics.render().lines().any(|l| l == "http_connections_active 0"));
+        assert!(metrics.render().lines().any(|l| l == "http_connections_total 1"));
+    }
+}
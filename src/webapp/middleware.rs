@@ -0,0 +1,132 @@
+use crate::webapp::http::{HttpRequest, HttpResponse};
+use crate::webapp::router::Router;
+
+/// Hook around the router, e.g. auth, logging, compression or CORS.
+///
+/// Implement `before` and `after` for the simple cases, or `handle` to wrap the rest of the chain
+/// (timing, spans, catching the response of an inner middleware, ...).
+pub trait Middleware: Send + Sync {
+    /// Called before the router. Return a response to answer right away:
+    /// later middlewares and the router are skipped, but `after` of this one still runs.
+    fn before(&self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    /// Called with the response on its way out, in reverse registration order.
+    fn after(&self, _request: &HttpRequest, _response: &mut HttpResponse) {}
+
+    /// Run `before`, the rest of the chain and `after`.
+    fn handle(&self, request: &mut HttpRequest, next: Next<'_>) -> HttpResponse {
+        let mut response = match self.before(request) {
+            Some(response) => response,
+            None => next.run(request),
+        };
+        self.after(request, &mut response);
+        response
+    }
+}
+
+/// The middlewares after the current one, then the router.
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &mut HttpRequest) -> HttpResponse {
+        match self.middlewares.split_first() {
+            Some((first, rest)) => first.handle(request, Next { middlewares: rest, router: self.router }),
+            None => self.router.handle(request),
+        }
+    }
+}
+
+/// Ordered middleware chain in front of a `Router`.
+/// The first registered middleware sees the request first and the response last.
+/// ```ignore
+/// let pipeline = Pipeline::new(router).with(AccessLog::new(format)).with(auth);
+/// ```
+pub struct Pipeline {
+    middlewares: Vec<Box<dyn Middleware>>,
+    router: Router,
+}
+
+impl Pipeline {
+    pub fn new(router: Router) -> Self {
+        Pipeline { middlewares: Vec::new(), router }
+    }
+
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    pub fn handle(&self, request: &mut HttpRequest) -> HttpResponse {
+        Next { middlewares: &self.middlewares, router: &self.router }.run(request)
+    }
+}
+
+#[cfg(test)]
+pub mod middleware_test_cases {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Records its hooks into a shared trace, answers 401 without `Authorization` if `auth`.
+    struct Recorder {
+        name: &'static str,
+        auth: bool,
+        trace: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Recorder {
+        fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+            self.trace.lock().unwrap().push(format!("before {}", self.name));
+            if self.auth && request.header("Authorization").is_none() {
+                return Some(HttpResponse::status_page(401));
+            }
+            request.headers.append("X-Seen-By", self.name);
+            None
+        }
+
+        fn after(&self, _request: &HttpRequest, response: &mut HttpResponse) {
+            self.trace.lock().unwrap().push(format!("after {}", self.name));
+            response.headers.append("X-After", self.name);
+        }
+    }
+
+    fn request(raw: &str) -> HttpRequest {
+        HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn pipeline(trace: &Arc<Mutex<Vec<String>>>) -> Pipeline {
+        let router = Router::new().get("/", |request, _| {
+            HttpResponse::new(200).with_body(request.headers.get_all("X-Seen-By").collect::<Vec<_>>().join(","))
+        });
+        Pipeline::new(router)
+            .with(Recorder { name: "outer", auth: false, trace: Arc::clone(trace) })
+            .with(Recorder { name: "auth", auth: true, trace: Arc::clone(trace) })
+            .with(Recorder { name: "inner", auth: false, trace: Arc::clone(trace) })
+    }
+
+    #[test]
+    pub fn test_order() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let response = pipeline(&trace).handle(&mut request("GET / HTTP/1.1\r\nAuthorization: Basic eA==\r\n\r\n"));
+        assert_eq!(response.body, b"outer,auth,inner");
+        assert_eq!(response.headers.get_all("X-After").collect::<Vec<_>>(), vec!["inner", "auth", "outer"]);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec!["before outer", "before auth", "before inner", "after inner", "after auth", "after outer"]
+        );
+    }
+
+    #[test]
+    pub fn test_short_circuit() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let response = pipeline(&trace).handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, 401);
+        assert_eq!(response.headers.get_all("X-After").collect::<Vec<_>>(), vec!["auth", "outer"]);
+        assert_eq!(*trace.lock().unwrap(), vec!["before outer", "before auth", "after auth", "after outer"]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+
+/// Fixed number of worker threads fed through a bounded queue.
+/// Unlike `thread::spawn` per connection, a busy pool rejects new jobs instead of growing.
+///
+/// Every job is a value of `T` (e.g. a `TcpStream`) passed to the shared `handler`.
+/// `queue_depth` is the number of jobs waiting for a free worker. With `0` a job is accepted
+/// only if some worker is idle right now.
+pub struct WorkerPool<T: Send + 'static> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F>(size: usize, queue_depth: usize, handler: F) -> WorkerPool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        assert!(size > 0, "worker pool size must be greater than 0");
+        let (sender, receiver) = sync_channel::<T>(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let workers = (0..size)
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let handler = Arc::clone(&handler);
+                thread::Builder::new()
+                    .name(format!("worker-{}", id))
+                    .spawn(move || Self::work(id, receiver, handler))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+        WorkerPool { sender: Some(sender), workers }
+    }
+
+    /// Queue `job` for the workers. Give it back as `Err(job)` if the queue is full.
+    pub fn try_execute(&self, job: T) -> Result<(), T> {
+        match self.sender.as_ref() {
+            Some(sender) => sender.try_send(job).map_err(|e| match e {
+                TrySendError::Full(job) | TrySendError::Disconnected(job) => job,
+            }),
+            None => Err(job),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    fn work<F>(id: usize, receiver: Arc<Mutex<Receiver<T>>>, handler: Arc<F>)
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        loop {
+            // Release the lock before running the job, otherwise workers run one by one.
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+            let Ok(job) = job else {
+                return; // pool dropped and queue drained
+            };
+            // A panicking job must not shrink the pool.
+            if panic::catch_unwind(AssertUnwindSafe(|| handler(job))).is_err() {
+                tracing::error!("worker-{} recovered from a panicking job", id);
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for WorkerPool<T> {
+    /// Close the queue, let workers finish the queued jobs, then join them.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod pool_test_cases {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    pub fn test_execute_all_jobs() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        {
+            let counter = Arc::clone(&counter);
+            let pool = WorkerPool::new(3, 16, move |n: usize| {
+                counter.fetch_add(n, Ordering::SeqCst);
+            });
+            for n in 1..=10 {
+                pool.try_execute(n).unwrap();
+            }
+            // drop waits for queued jobs
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 55);
+    }
+
+    #[test]
+    pub fn test_reject_when_queue_full() {
+        let (release_tx, release_rx) = channel::<()>();
+        let (started_tx, started_rx) = channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let started_tx = Mutex::new(started_tx);
+        let pool = WorkerPool::new(1, 1, move |_: u32| {
+            started_tx.lock().unwrap().send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+        });
+
+        pool.try_execute(1).unwrap(); // running
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.try_execute(2).unwrap(); // queued
+        assert_eq!(pool.try_execute(3), Err(3)); // rejected
+
+        release_tx.send(()).unwrap();
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    pub fn test_survive_panicking_job() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        {
+            let counter = Arc::clone(&counter);
+            let pool = WorkerPool::new(1, 4, move |n: usize| {
+                if n == 0 {
+                    panic!("bad job");
+                }
+                counter.fetch_add(n, Ordering::SeqCst);
+            });
+            pool.try_execute(0).unwrap();
+            pool.try_execute(5).unwrap();
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 5);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::webapp::http::{HttpRequest, HttpResponse};
+use crate::webapp::middleware::Middleware;
+
+/// Tokens of one client, refilled at `RateLimiter::rate` up to `RateLimiter::burst`
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token-bucket rate limiting per client IP, answering 429 with `Retry-After` to clients out of tokens.
+///
+/// Every request takes a token, a client has `burst` of them and gets `rate` back per second.
+/// The client IP is the peer address, unless the peer is a trusted proxy:
+/// then it's the last address in `X-Forwarded-For` that isn't a trusted proxy.
+/// ```ignore
+/// Pipeline::new(router).with(RateLimiter::new(10.0, 20).with_trusted_proxies(vec!["127.0.0.1".parse()?]))
+/// ```
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    trusted_proxies: Vec<IpAddr>,
+    header: String,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    sweep_interval: Duration,
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// `rate` requests per second on average, `burst` at once.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        assert!(rate > 0.0, "rate must be positive");
+        RateLimiter {
+            rate,
+            burst: burst.max(1) as f64,
+            trusted_proxies: Vec::new(),
+            header: "X-Forwarded-For".to_string(),
+            buckets: Mutex::new(HashMap::new()),
+            sweep_interval: Duration::from_secs(60),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Proxies whose forwarding header tells the client IP
+    pub fn with_trusted_proxies(mut self, trusted_proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    /// Header listing the client and proxy addresses, `X-Forwarded-For` by default.
+    pub fn with_header(mut self, header: impl Into<String>) -> Self {
+        self.header = header.into();
+        self
+    }
+
+    /// How often buckets of idle clients are dropped
+    pub fn with_sweep_interval(mut self, sweep_interval: Duration) -> Self {
+        self.sweep_interval = sweep_interval;
+        self
+    }
+
+    /// The IP `request` is counted against, `None` without a peer address.
+    pub fn client_ip(&self, request: &HttpRequest) -> Option<IpAddr> {
+        let peer = request.peer_addr?.ip();
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+        // Each proxy appends the address it got the request from, so read from the right.
+        // Entries left of the first untrusted one may be forged by the client.
+        let forwarded: Vec<&str> = request.headers.get_all(&self.header).flat_map(|value| value.split(',')).collect();
+        let client = forwarded
+            .iter()
+            .rev()
+            .map(|entry| entry.trim().parse::<IpAddr>())
+            .find(|ip| !matches!(ip, Ok(ip) if self.trusted_proxies.contains(ip)));
+        match client {
+            Some(Ok(ip)) => Some(ip),
+            // Garbage is not worth a bucket of its own, count it against the proxy
+            Some(Err(_)) | None => Some(peer),
+        }
+    }
+
+    /// Take a token of `client` at `now`, or tell how long until it has one.
+    pub fn check_at(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        self.sweep(now);
+        let mut buckets = self.lock();
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: self.burst, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    /// Number of clients with a bucket
+    pub fn clients(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Every `sweep_interval`, drop the buckets that refilled completely: a new bucket is the same.
+    fn sweep(&self, now: Instant) {
+        let mut last_sweep = self.last_sweep.lock().unwrap_or_else(|e| e.into_inner());
+        if now.saturating_duration_since(*last_sweep) < self.sweep_interval {
+            return;
+        }
+        *last_sweep = now;
+        let (rate, burst) = (self.rate, self.burst);
+        self.lock().retain(|_, bucket| {
+            bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate < burst
+        });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<IpAddr, Bucket>> {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        let client = self.client_ip(request)?;
+        let retry_after = self.check(client).err()?;
+        // Whole seconds, rounded up so the retry finds a token
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        Some(HttpResponse::status_page(429).with_header("Retry-After", seconds.max(1).to_string()))
+    }
+}
+
+/// Caps the open connections of each client IP, checked when a connection is accepted.
+///
+/// `RateLimiter` only sees requests: a client opening idle connections still gets a thread,
+/// or a place in the queue, for each of them. Trusted proxies are not capped, they carry many clients.
+/// Cheap to clone, every clone counts the same connections.
+/// ```ignore
+/// let Some(permit) = limiter.acquire(stream.peer_addr()?.ip()) else { return refuse(stream) };
+/// thread::spawn(move || { serve(stream); drop(permit) });
+/// ```
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// One open connection of a client, counted until it's dropped.
+pub struct ConnectionPermit {
+    client: Option<IpAddr>,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    /// At most `max_per_ip` connections per client IP, `0` for no limit.
+    pub fn new(max_per_ip: usize) -> Self {
+        ConnectionLimiter { max_per_ip, trusted_proxies: Arc::new(Vec::new()), open: Arc::default() }
+    }
+
+    pub fn with_trusted_proxies(mut self, trusted_proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = Arc::new(trusted_proxies);
+        self
+    }
+
+    /// A permit for one more connection of `client`, `None` if it has `max_per_ip` open already.
+    pub fn acquire(&self, client: IpAddr) -> Option<ConnectionPermit> {
+        if self.max_per_ip == 0 || self.trusted_proxies.contains(&client) {
+            return Some(ConnectionPermit { client: None, open: Arc::clone(&self.open) });
+        }
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let count = open.entry(client).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionPermit { client: Some(client), open: Arc::clone(&self.open) })
+    }
+
+    /// Connections of `client` holding a permit
+    pub fn open_connections(&self, client: IpAddr) -> usize {
+        self.open.lock().unwrap_or_else(|e| e.into_inner()).get(&client).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let Some(client) = self.client else {
+            return;
+        };
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = open.get_mut(&client) {
+            *count -= 1;
+            // Clients come and go, don't keep an entry for each of them
+            if *count == 0 {
+                open.remove(&client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod rate_limit_test_cases {
+    use super::*;
+
+    fn request(peer: &str, forwarded_for: Option<&str>) -> HttpRequest {
+        let header = forwarded_for.map(|value| format!("X-Forwarded-For: {}\r\n", value)).unwrap_or_default();
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", header);
+        let mut request = HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap();
+        request.peer_addr = Some(format!("{}:40000", peer).parse().unwrap());
+        request
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    pub fn test_token_bucket() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(ip("10.0.0.1"), start), Ok(()));
+        }
+        assert_eq!(limiter.check_at(ip("10.0.0.1"), start), Err(Duration::from_millis(500)));
+        // Other clients have their own bucket
+        assert_eq!(limiter.check_at(ip("10.0.0.2"), start), Ok(()));
+        // Half a second brings one token back, and never more than the burst
+        assert_eq!(limiter.check_at(ip("10.0.0.1"), start + Duration::from_millis(500)), Ok(()));
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(ip("10.0.0.1"), later), Ok(()));
+        }
+        assert!(limiter.check_at(ip("10.0.0.1"), later).is_err());
+    }
+
+    #[test]
+    pub fn test_client_ip() {
+        let limiter = RateLimiter::new(1.0, 1).with_trusted_proxies(vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+        // Untrusted peers can't pick their IP
+        assert_eq!(limiter.client_ip(&request("203.0.113.9", Some("1.2.3.4"))), Some(ip("203.0.113.9")));
+        assert_eq!(limiter.client_ip(&request("10.0.0.1", Some("6.6.6.6, 198.51.100.7, 10.0.0.2"))), Some(ip("198.51.100.7")));
+        assert_eq!(limiter.client_ip(&request("10.0.0.1", Some("::1"))), Some(ip("::1")));
+        assert_eq!(limiter.client_ip(&request("10.0.0.1", Some("unknown"))), Some(ip("10.0.0.1")));
+        assert_eq!(limiter.client_ip(&request("10.0.0.1", None)), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    pub fn test_too_many_requests() {
+        let limiter = RateLimiter::new(0.5, 2);
+        let mut request = request("203.0.113.9", None);
+        assert!(limiter.before(&mut request).is_none());
+        assert!(limiter.before(&mut request).is_none());
+        let response = limiter.before(&mut request).unwrap();
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers.get("Retry-After"), Some("2"));
+    }
+
+    #[test]
+    pub fn test_evict_idle_buckets() {
+        let limiter = RateLimiter::new(1.0, 2).with_sweep_interval(Duration::from_secs(10));
+        let start = Instant::now();
+        limiter.check_at(ip("10.0.0.1"), start).unwrap();
+        limiter.check_at(ip("10.0.0.2"), start).unwrap();
+        limiter.check_at(ip("10.0.0.2"), start).unwrap();
+        assert_eq!(limiter.clients(), 2);
+        // Both buckets are full again after 2 seconds, but the sweep waits for its interval
+        limiter.check_at(ip("10.0.0.3"), start + Duration::from_secs(5)).unwrap();
+        assert_eq!(limiter.clients(), 3);
+        limiter.check_at(ip("10.0.0.3"), start + Duration::from_secs(10)).unwrap();
+        assert_eq!(limiter.clients(), 1);
+    }
+
+    #[test]
+    pub fn test_connection_limiter() {
+        let limiter = ConnectionLimiter::new(2).with_trusted_proxies(vec![ip("10.0.0.9")]);
+        let first = limiter.acquire(ip("10.0.0.1")).unwrap();
+        let second = limiter.clone().acquire(ip("10.0.0.1")).unwrap();
+        assert!(limiter.acquire(ip("10.0.0.1")).is_none());
+        assert_eq!(limiter.open_connections(ip("10.0.0.1")), 2);
+        // Other clients and trusted proxies aren't affected
+        assert!(limiter.acquire(ip("10.0.0.2")).is_some());
+        let proxied: Vec<_> = (0..5).map(|_| limiter.acquire(ip("10.0.0.9")).unwrap()).collect();
+        assert_eq!(limiter.open_connections(ip("10.0.0.9")), 0);
+
+        drop(first);
+        let third = limiter.acquire(ip("10.0.0.1")).unwrap();
+        drop((second, third, proxied));
+        assert_eq!(limiter.open_connections(ip("10.0.0.1")), 0);
+        assert!(limiter.open.lock().unwrap().is_empty());
+
+        let unlimited = ConnectionLimiter::new(0);
+        let permits: Vec<_> = (0..100).map(|_| unlimited.acquire(ip("10.0.0.1")).unwrap()).collect();
+        assert_eq!(permits.len(), 100);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use crate::webapp::http::{HttpRequest, HttpResponse, Method};
+use crate::webapp::static_files::StaticFiles;
+
+/// Route handler. It's shared between worker threads, so it must be `Send + Sync`.
+pub type Handler = Box<dyn Fn(&HttpRequest, &Params) -> HttpResponse + Send + Sync>;
+
+/// Values captured from the path by `:name` and `*name` segments.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    values: HashMap<String, String>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+/// One segment of a route pattern.
+/// - `/users` => `Static("users")`
+/// - `/:id` => `Param("id")`, matches exactly one segment
+/// - `/*path` => `Wildcard("path")`, matches the rest of the path (maybe empty). `/*` captures nothing.
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl Segment {
+    /// Lower is more specific: `/users/new` wins over `/users/:id`, which wins over `/users/*`.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+struct Route {
+    method: Method,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+impl Route {
+    fn matches(&self, path: &[&str]) -> Option<Params> {
+        let mut params = Params::default();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    if !name.is_empty() {
+                        params.values.insert(name.clone(), path[i.min(path.len())..].join("/"));
+                    }
+                    return Some(params);
+                }
+                Segment::Static(s) => {
+                    if path.get(i) != Some(&s.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.values.insert(name.clone(), path.get(i)?.to_string());
+                }
+            }
+        }
+        if self.segments.len() == path.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    fn rank(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+}
+
+/// Dispatch requests by method and path pattern.
+/// ```ignore
+/// let router = Router::new()
+///     .get("/", |_, _| HttpResponse::html("index"))
+///     .get("/users/:id", |_, params| HttpResponse::html(params.get("id").unwrap()))
+///     .get("/static/*path", |_, params| HttpResponse::html(params.get("path").unwrap()));
+/// ```
+/// A path matching no pattern gets 404, a matched path without a route for the method gets 405.
+///
+/// `HEAD` runs the `GET` route unless there's a `HEAD` route, the connection drops the body.
+/// `OPTIONS` is answered with the allowed methods unless there's an `OPTIONS` route.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + Send + Sync + 'static,
+    {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|s| {
+                if let Some(name) = s.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = s.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Static(s.to_string())
+                }
+            })
+            .collect();
+        self.routes.push(Route { method, pattern: pattern.to_string(), segments, handler: Box::new(handler) });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&HttpRequest, &Params) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Serve `files` under `prefix`, e.g. `/static` => `GET /static/*path`
+    pub fn static_files(self, prefix: &str, files: StaticFiles) -> Self {
+        let pattern = format!("{}/*path", prefix.trim_end_matches('/'));
+        self.get(&pattern, move |request, params| files.serve(request, params.get("path").unwrap_or("")))
+    }
+
+    /// Run the most specific route matching the request, or answer 404/405.
+    pub fn dispatch(&self, request: &HttpRequest) -> HttpResponse {
+        match self.find(request) {
+            Ok((route, params)) => (route.handler)(request, &params),
+            Err(allowed) => not_routed(request, &allowed),
+        }
+    }
+
+    /// Same as `dispatch`, and set `request.route` to the pattern of the route that ran.
+    pub fn handle(&self, request: &mut HttpRequest) -> HttpResponse {
+        match self.find(request) {
+            Ok((route, params)) => {
+                request.route = Some(route.pattern.clone());
+                (route.handler)(request, &params)
+            }
+            Err(allowed) => not_routed(request, &allowed),
+        }
+    }
+
+    /// The most specific route for the request, or the methods allowed for its path.
+    /// The allowed methods include `HEAD` with `GET`, and always `OPTIONS`.
+    fn find(&self, request: &HttpRequest) -> Result<(&Route, Params), Vec<Method>> {
+        // `OPTIONS *` asks about the server as a whole
+        let whole_server = request.method == Method::Options && request.target == "*";
+        let path = split_path(&request.path);
+        let mut best: Option<(&Route, Params)> = None;
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in &self.routes {
+            let params = match route.matches(&path) {
+                Some(params) => params,
+                None if whole_server => Params::default(),
+                None => continue,
+            };
+            allowed.push(route.method);
+            if whole_server || !serves(route.method, request.method) {
+                continue;
+            }
+            // On a tie the route of the method itself wins over `GET` serving a `HEAD`
+            let key = |route: &Route| (route.rank(), route.method != request.method);
+            match &best {
+                Some((current, _)) if key(current) <= key(route) => {}
+                _ => best = Some((route, params)),
+            }
+        }
+        if let Some(best) = best {
+            return Ok(best);
+        }
+        if !allowed.is_empty() {
+            if allowed.contains(&Method::Get) {
+                allowed.push(Method::Head);
+            }
+            allowed.push(Method::Options);
+        }
+        allowed.sort();
+        allowed.dedup();
+        Err(allowed)
+    }
+}
+
+/// Whether a route for `route_method` answers a `method` request
+fn serves(route_method: Method, method: Method) -> bool {
+    route_method == method || (route_method == Method::Get && method == Method::Head)
+}
+
+/// 404 if no pattern matched, the `allowed` methods for `OPTIONS`, 405 listing them otherwise
+fn not_routed(request: &HttpRequest, allowed: &[Method]) -> HttpResponse {
+    if allowed.is_empty() {
+        return HttpResponse::status_page(404);
+    }
+    let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+    match request.method {
+        Method::Options => HttpResponse::new(204).with_header("Allow", allow.join(", ")),
+        _ => HttpResponse::status_page(405).with_header("Allow", allow.join(", ")),
+    }
+}
+
+/// `/users//1/` => `["users", "1"]`
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+#[cfg(test)]
+pub mod router_test_cases {
+    use super::*;
+
+    fn request(method: Method, target: &str) -> HttpRequest {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+        HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn body(response: &HttpResponse) -> String {
+        String::from_utf8(response.body.clone()).unwrap()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_, _| HttpResponse::html("index"))
+            .get("/users/new", |_, _| HttpResponse::html("new user"))
+            .get("/users/:id", |_, params| HttpResponse::html(format!("user {}", params.get("id").unwrap())))
+            .delete("/users/:id", |_, _| HttpResponse::new(204))
+            .get("/users/:id/posts/:post", |_, params| {
+                HttpResponse::html(format!("{}/{}", params.get("id").unwrap(), params.get("post").unwrap()))
+            })
+            .get("/static/*path", |_, params| HttpResponse::html(format!("file {}", params.get("path").unwrap())))
+            .get("/any/*", |_, _| HttpResponse::html("any"))
+    }
+
+    #[test]
+    pub fn test_static_and_params() {
+        let router = router();
+        assert_eq!(body(&router.dispatch(&request(Method::Get, "/"))), "index");
+        assert_eq!(body(&router.dispatch(&request(Method::Get, "/users/new"))), "new user");
+        assert_eq!(body(&router.dispatch(&request(Method::Get, "/users/42"))), "user 42");
+        assert_eq!(body(&router.dispatch(&request(Method::Get, "/users/42/posts/7"))), "42/7");
+        assert_eq!(body(&router.dispatch(&request(Method::Get, "/users/%E5%B9%B3"))), "user 平");
+    }
+
+    #[test]
+    pub fn test_wildcard() {
+        let router = router();
+        assert_eq!(body(&router.dispatch(&request(Method::Get, "/static/css/app.css"))), "file css/app.css");
+        assert_eq!(body(&router.dispatch(&request(Method::Get, "/static"))), "file ");
+        assert_eq!(body(&router.dispatch(&request(Method::Get, "/any/thing/else"))), "any");
+    }
+
+    #[test]
+    pub fn test_not_found_and_method_not_allowed() {
+        let router = router();
+        assert_eq!(router.dispatch(&request(Method::Get, "/nothing")).status, 404);
+        assert_eq!(router.dispatch(&request(Method::Get, "/users/1/posts")).status, 404);
+        assert_eq!(router.dispatch(&request(Method::Delete, "/users/1")).status, 204);
+
+        let response = router.dispatch(&request(Method::Post, "/users/1"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, DELETE, OPTIONS"));
+    }
+
+    #[test]
+    pub fn test_head_and_options() {
+        let router = router().route(Method::Head, "/users/new", |_, _| HttpResponse::new(200).with_header("X-Head", "1"));
+        assert_eq!(body(&router.dispatch(&request(Method::Head, "/users/42"))), "user 42");
+        assert_eq!(router.dispatch(&request(Method::Head, "/users/new")).headers.get("X-Head"), Some("1"));
+        assert_eq!(router.dispatch(&request(Method::Head, "/nothing")).status, 404);
+
+        let options = router.dispatch(&request(Method::Options, "/users/1"));
+        assert_eq!(options.status, 204);
+        assert_eq!(options.headers.get("Allow"), Some("GET, HEAD, DELETE, OPTIONS"));
+        assert_eq!(router.dispatch(&request(Method::Options, "/nothing")).status, 404);
+        let server = router.dispatch(&request(Method::Options, "*"));
+        assert_eq!(server.headers.get("Allow"), Some("GET, HEAD, DELETE, OPTIONS"));
+        // An explicit route answers its own OPTIONS
+        let router = router.route(Method::Options, "/", |_, _| HttpResponse::new(200).with_body("custom"));
+        assert_eq!(body(&router.dispatch(&request(Method::Options, "/"))), "custom");
+    }
+
+    #[test]
+    pub fn test_handle_sets_route() {
+        let router = router();
+        let mut matched = request(Method::Get, "/users/42/posts/7");
+        assert_eq!(body(&router.handle(&mut matched)), "42/7");
+        assert_eq!(matched.route.as_deref(), Some("/users/:id/posts/:post"));
+
+        let mut unmatched = request(Method::Get, "/nothing");
+        assert_eq!(router.handle(&mut unmatched).status, 404);
+        assert_eq!(unmatched.route, None);
+    }
+}
@@ -0,0 +1,242 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often a non-blocking listener checks for new connections and for shutdown.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Shared by the accept loop, every accepted connection and the `ServerHandle`.
+#[derive(Default)]
+struct ServerState {
+    stopping: AtomicBool,
+    active: Mutex<usize>,
+    idle: Condvar,
+}
+
+/// Counts a connection as in-flight until it's dropped.
+/// Move it together with the `TcpStream` into the thread or job serving the connection.
+pub struct ConnectionGuard {
+    state: Arc<ServerState>,
+}
+
+impl ConnectionGuard {
+    /// `true` once shutdown started, e.g. don't wait for another request on this connection.
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.stopping.load(Ordering::SeqCst)
+    }
+
+    /// `is_shutting_down` for code that outlives the borrow of the guard, e.g. an upgraded protocol.
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        ShutdownSignal { state: Arc::clone(&self.state) }
+    }
+}
+
+/// Tells whether the server is shutting down. Unlike `ConnectionGuard` it doesn't count as a connection.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    state: Arc<ServerState>,
+}
+
+impl ShutdownSignal {
+    /// A signal that never fires, e.g. to write a streamed body outside a server.
+    pub fn never() -> Self {
+        ShutdownSignal { state: Arc::default() }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.stopping.load(Ordering::SeqCst)
+    }
+}
+
+impl Clone for ConnectionGuard {
+    /// Another count for the same connection, e.g. for the thread it's handed off to.
+    /// The server waits for every clone.
+    fn clone(&self) -> Self {
+        *self.state.active.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        ConnectionGuard { state: Arc::clone(&self.state) }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self.state.active.lock().unwrap_or_else(|e| e.into_inner());
+        *active -= 1;
+        if *active == 0 {
+            self.state.idle.notify_all();
+        }
+    }
+}
+
+/// Accepted connections, like `TcpListener::incoming()` but ends when shutdown starts.
+pub struct Incoming {
+    listener: TcpListener,
+    state: Arc<ServerState>,
+}
+
+impl Iterator for Incoming {
+    type Item = (TcpStream, ConnectionGuard);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.state.stopping.load(Ordering::SeqCst) {
+                return None;
+            }
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    // accepted sockets inherit non-blocking mode on some platforms
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        tracing::warn!("Drop connection: {:?}", e);
+                        continue;
+                    }
+                    *self.state.active.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+                    let guard = ConnectionGuard { state: Arc::clone(&self.state) };
+                    return Some((stream, guard));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => sleep(ACCEPT_POLL_INTERVAL),
+                Err(e) => tracing::warn!("Failed to accept connection: {:?}", e),
+            }
+        }
+    }
+}
+
+/// A running WebServer. The accept loop runs on its own thread until `shutdown`.
+/// ```ignore
+/// let listener = TcpListener::bind("127.0.0.1:8080")?;
+/// let server = ServerHandle::spawn(listener, |incoming| {
+///     for (stream, guard) in incoming {
+///         handle(stream);
+///     }
+/// })?;
+/// server.shutdown(Duration::from_secs(10));
+/// ```
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    state: Arc<ServerState>,
+    acceptor: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Run `serve` with the connections of `listener` on a new thread.
+    /// `serve` should return once `Incoming` is exhausted, after joining the threads it started.
+    pub fn spawn<F>(listener: TcpListener, serve: F) -> io::Result<ServerHandle>
+    where
+        F: FnOnce(Incoming) + Send + 'static,
+    {
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let state = Arc::new(ServerState {
+            stopping: AtomicBool::new(false),
+            active: Mutex::new(0),
+            idle: Condvar::new(),
+        });
+        let incoming = Incoming { listener, state: Arc::clone(&state) };
+        let acceptor = thread::Builder::new()
+            .name("acceptor".to_string())
+            .spawn(move || serve(incoming))?;
+        Ok(ServerHandle { local_addr, state, acceptor })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn active_connections(&self) -> usize {
+        *self.state.active.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stop accepting, wait up to `deadline` for in-flight connections, then join the server threads.
+    /// Return `false` if some connections were still running at the deadline. Their threads are left
+    /// behind and die with the process.
+    pub fn shutdown(self, deadline: Duration) -> bool {
+        self.state.stopping.store(true, Ordering::SeqCst);
+        let expire_at = Instant::now() + deadline;
+        let mut active = self.state.active.lock().unwrap_or_else(|e| e.into_inner());
+        while *active > 0 {
+            let now = Instant::now();
+            if now >= expire_at {
+                tracing::warn!("Shutdown deadline exceeded, {} connection(s) still running", *active);
+                return false;
+            }
+            active = self.state.idle.wait_timeout(active, expire_at - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+        drop(active);
+        self.acceptor.join().is_ok()
+    }
+
+    /// Block until SIGINT (Ctrl+C) or SIGTERM, then `shutdown`.
+    /// The signal handler is process-wide, so only one server can wait for signals.
+    pub fn shutdown_on_signal(self, deadline: Duration) -> io::Result<bool> {
+        let (sender, receiver) = channel();
+        ctrlc::set_handler(move || {
+            let _ = sender.send(());
+        })
+        .map_err(io::Error::other)?;
+        let _ = receiver.recv();
+        println!("Signal received, shutting down...");
+        Ok(self.shutdown(deadline))
+    }
+}
+
+#[cfg(test)]
+pub mod server_test_cases {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    /// Echo server, every connection sleeps `delay` before answering.
+    fn spawn_echo_server(delay: Duration) -> ServerHandle {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        ServerHandle::spawn(listener, move |incoming| {
+            let mut workers = Vec::new();
+            for (mut stream, guard) in incoming {
+                workers.push(thread::spawn(move || {
+                    let mut buf = [0u8; 4];
+                    stream.read_exact(&mut buf).unwrap();
+                    sleep(delay);
+                    stream.write_all(&buf).unwrap();
+                    drop(guard);
+                }));
+            }
+            for worker in workers {
+                worker.join().unwrap();
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    pub fn test_shutdown_waits_in_flight() {
+        let server = spawn_echo_server(Duration::from_millis(300));
+        let addr = server.local_addr();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        while server.active_connections() == 0 {
+            sleep(Duration::from_millis(5));
+        }
+
+        assert!(server.shutdown(Duration::from_secs(5)));
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    pub fn test_shutdown_deadline_exceeded() {
+        let server = spawn_echo_server(Duration::from_secs(2));
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        client.write_all(b"ping").unwrap();
+        while server.active_connections() == 0 {
+            sleep(Duration::from_millis(5));
+        }
+
+        let started = Instant::now();
+        assert!(!server.shutdown(Duration::from_millis(100)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::webapp::cookie::{Cookie, SameSite};
+use crate::webapp::http::{HttpRequest, HttpResponse};
+use crate::webapp::middleware::Middleware;
+
+/// Values of a session by key
+pub type SessionData = Map<String, Value>;
+
+/// Random bytes of a session ID, 43 characters once encoded
+const ID_BYTES: usize = 32;
+
+/// New session ID: 256 bits from the thread's CSPRNG, base64url without padding.
+pub fn new_session_id() -> String {
+    URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; ID_BYTES]>())
+}
+
+/// Whether `id` looks like a `new_session_id`. Anything else is never passed to a store,
+/// so a forged cookie can't be used as a file name.
+fn is_session_id(id: &str) -> bool {
+    id.len() == (ID_BYTES * 4).div_ceil(3) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Where sessions live between requests.
+pub trait SessionStore: Send + Sync {
+    /// Data of session `id`, `None` if it is unknown or expired.
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>>;
+
+    /// Create or replace session `id`, it expires `ttl` from now.
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()>;
+
+    fn remove(&self, id: &str) -> io::Result<()>;
+
+    /// Forget the expired sessions, returns how many.
+    fn purge_expired(&self) -> io::Result<usize>;
+}
+
+/// Sessions in a map, lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (Instant, SessionData)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (Instant, SessionData)>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        let mut sessions = self.lock();
+        match sessions.get(id) {
+            Some((expires_at, data)) if *expires_at > Instant::now() => Ok(Some(data.clone())),
+            Some(_) => {
+                sessions.remove(id);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()> {
+        self.lock().insert(id.to_string(), (Instant::now() + ttl, data.clone()));
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.lock().remove(id);
+        Ok(())
+    }
+
+    fn purge_expired(&self) -> io::Result<usize> {
+        let mut sessions = self.lock();
+        let (before, now) = (sessions.len(), Instant::now());
+        sessions.retain(|_, (expires_at, _)| *expires_at > now);
+        Ok(before - sessions.len())
+    }
+}
+
+/// One JSON file per session, `<dir>/<id>.json`, so sessions survive a restart.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+/// Content of a `FileStore` file
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    /// Seconds since the Unix epoch
+    expires_at: u64,
+    data: SessionData,
+}
+
+impl FileStore {
+    /// Store the sessions in `dir`, created if missing.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn read(&self, path: &PathBuf) -> io::Result<Option<StoredSession>> {
+        match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json).map(Some).map_err(io::Error::from),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        let path = self.path(id);
+        match self.read(&path)? {
+            Some(session) if session.expires_at > unix_now() => Ok(Some(session.data)),
+            Some(_) => {
+                self.remove(id)?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()> {
+        let session = StoredSession { expires_at: unix_now() + ttl.as_secs(), data: data.clone() };
+        // Write aside and rename, a concurrent `load` never sees half a file
+        let path = self.path(id);
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_vec(&session)?)?;
+        fs::rename(&partial, &path)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn purge_expired(&self) -> io::Result<usize> {
+        let now = unix_now();
+        let mut purged = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                // Unreadable files are left to whoever put them there
+                if let Ok(Some(session)) = self.read(&path) {
+                    if session.expires_at <= now && fs::remove_file(&path).is_ok() {
+                        purged += 1;
+                    }
+                }
+            }
+        }
+        Ok(purged)
+    }
+}
+
+/// The session of a request, see `Sessions`. Clones share the same session.
+/// ```ignore
+/// let session = request.session.as_ref().unwrap();
+/// let visits = session.get::<u64>("visits").unwrap_or(0) + 1;
+/// session.insert("visits", visits);
+/// ```
+#[derive(Clone)]
+pub struct Session(Arc<Mutex<SessionState>>);
+
+struct SessionState {
+    /// `None` until the session is first saved
+    id: Option<String>,
+    data: SessionData,
+    changed: bool,
+    destroyed: bool,
+    regenerate: bool,
+}
+
+impl Session {
+    fn new(id: Option<String>, data: SessionData) -> Self {
+        let state = SessionState { id, data, changed: false, destroyed: false, regenerate: false };
+        Session(Arc::new(Mutex::new(state)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SessionState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// ID of a session the client already has, `None` for a new one
+    pub fn id(&self) -> Option<String> {
+        self.lock().id.clone()
+    }
+
+    /// Value of `key`, `None` if missing or not a `T`
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let state = self.lock();
+        state.data.get(key).and_then(|value| T::deserialize(value).ok())
+    }
+
+    pub fn insert<T: Serialize>(&self, key: impl Into<String>, value: T) -> Result<(), serde_json::Error> {
+        let value = serde_json::to_value(value)?;
+        let mut state = self.lock();
+        state.data.insert(key.into(), value);
+        state.changed = true;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        let mut state = self.lock();
+        state.changed = true;
+        state.data.remove(key)
+    }
+
+    /// Delete the session from the store and the client, e.g. on logout.
+    pub fn destroy(&self) {
+        let mut state = self.lock();
+        state.data.clear();
+        state.destroyed = true;
+    }
+
+    /// Keep the data under a new ID, e.g. on login against session fixation.
+    pub fn regenerate(&self) {
+        self.lock().regenerate = true;
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The ID is a credential, keep it out of logs
+        let state = self.lock();
+        f.debug_struct("Session").field("keys", &state.data.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+/// Middleware giving every request a `Session`, found by the ID in its session cookie.
+///
+/// A session is only stored, and its cookie only sent, once something was inserted.
+/// It expires `ttl` after its last change, in the store and in the client.
+/// ```ignore
+/// Pipeline::new(router).with(Sessions::new(MemoryStore::new()).with_ttl(Duration::from_secs(1800)))
+/// ```
+pub struct Sessions {
+    store: Box<dyn SessionStore>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    purge_interval: Duration,
+    last_purge: Mutex<Instant>,
+}
+
+impl Sessions {
+    /// Cookie `sid`, sessions expire after 30 minutes.
+    pub fn new<S: SessionStore + 'static>(store: S) -> Self {
+        Sessions {
+            store: Box::new(store),
+            cookie_name: "sid".to_string(),
+            ttl: Duration::from_secs(30 * 60),
+            secure: false,
+            purge_interval: Duration::from_secs(60),
+            last_purge: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn with_cookie_name(mut self, cookie_name: impl Into<String>) -> Self {
+        self.cookie_name = cookie_name.into();
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    fn cookie(&self, value: &str) -> Cookie {
+        Cookie::new(&self.cookie_name, value)
+            .with_path("/")
+            .with_http_only(true)
+            .with_same_site(SameSite::Lax)
+            .with_secure(self.secure)
+    }
+
+    /// Run `purge_expired` on the store at most every `purge_interval`
+    fn purge_if_due(&self) {
+        let mut last_purge = self.last_purge.lock().unwrap_or_else(|e| e.into_inner());
+        if last_purge.elapsed() < self.purge_interval {
+            return;
+        }
+        *last_purge = Instant::now();
+        if let Err(e) = self.store.purge_expired() {
+            tracing::warn!("Failed to purge expired sessions: {}", e);
+        }
+    }
+}
+
+impl Middleware for Sessions {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        let id = request.cookie(&self.cookie_name).filter(|id| is_session_id(id));
+        let loaded = id.and_then(|id| match self.store.load(&id) {
+            Ok(data) => data.map(|data| (id, data)),
+            Err(e) => {
+                tracing::warn!("Failed to load session: {}", e);
+                None
+            }
+        });
+        request.session = Some(match loaded {
+            Some((id, data)) => Session::new(Some(id), data),
+            None => Session::new(None, SessionData::new()),
+        });
+        None
+    }
+
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        self.purge_if_due();
+        let Some(session) = &request.session else {
+            return;
+        };
+        let mut state = session.lock();
+        if state.destroyed {
+            if let Some(id) = state.id.take() {
+                if let Err(e) = self.store.remove(&id) {
+                    tracing::warn!("Failed to remove session: {}", e);
+                }
+                response.headers.append("Set-Cookie", Cookie::removal(&self.cookie_name).with_path("/").to_string());
+            }
+            return;
+        }
+        if !state.changed && !state.regenerate {
+            return;
+        }
+        if state.regenerate {
+            if let Some(id) = state.id.take() {
+                let _ = self.store.remove(&id);
+            }
+        }
+        if state.id.is_none() && state.data.is_empty() {
+            return; // nothing worth a session
+        }
+        let id = state.id.get_or_insert_with(new_session_id).clone();
+        match self.store.save(&id, &state.data, self.ttl) {
+            Ok(()) => {
+                let cookie = self.cookie(&id).with_max_age(self.ttl).with_expires(SystemTime::now() + self.ttl);
+                response.headers.append("Set-Cookie", cookie.to_string());
+            }
+            Err(e) => tracing::warn!("Failed to save session: {}", e),
+        }
+        state.changed = false;
+        state.regenerate = false;
+    }
+}
+
+#[cfg(test)]
+pub mod session_test_cases {
+    use std::thread;
+
+    use super::*;
+    use crate::webapp::middleware::Pipeline;
+    use crate::webapp::router::Router;
+
+    /// `/visits` counts the visits of the session, `/login` regenerates it and `/logout` destroys it.
+    fn pipeline<S: SessionStore + 'static>(store: S, ttl: Duration) -> Pipeline {
+        let router = Router::new()
+            .get("/visits", |request, _| {
+                let session = request.session.as_ref().unwrap();
+                let visits = session.get::<u64>("visits").unwrap_or(0) + 1;
+                session.insert("visits", visits).unwrap();
+                HttpResponse::new(200).with_body(visits.to_string())
+            })
+            .get("/login", |request, _| {
+                request.session.as_ref().unwrap().regenerate();
+                HttpResponse::new(204)
+            })
+            .get("/logout", |request, _| {
+                request.session.as_ref().unwrap().destroy();
+                HttpResponse::new(204)
+            })
+            .get("/peek", |_, _| HttpResponse::new(204));
+        Pipeline::new(router).with(Sessions::new(store).with_ttl(ttl))
+    }
+
+    /// Response and the session ID it sets, `""` for a removal
+    fn get(pipeline: &Pipeline, path: &str, sid: Option<&str>) -> (HttpResponse, Option<String>) {
+        let cookie = sid.map(|sid| format!("Cookie: theme=dark; sid={}\r\n", sid)).unwrap_or_default();
+        let raw = format!("GET {} HTTP/1.1\r\n{}\r\n", path, cookie);
+        let response = pipeline.handle(&mut HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap());
+        let set_sid = response
+            .headers
+            .get("Set-Cookie")
+            .and_then(|cookie| cookie.strip_prefix("sid="))
+            .map(|cookie| cookie.split(';').next().unwrap().to_string());
+        (response, set_sid)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rs-tutorial-{}-{}", name, new_session_id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    pub fn test_session_id() {
+        let (a, b) = (new_session_id(), new_session_id());
+        assert_ne!(a, b);
+        assert!(is_session_id(&a));
+        assert!(!is_session_id("../../etc/passwd"));
+        assert!(!is_session_id(&format!("{}.", &a[1..])));
+    }
+
+    #[test]
+    pub fn test_session_lifecycle() {
+        let pipeline = pipeline(MemoryStore::new(), Duration::from_secs(60));
+        // Nothing stored, nothing sent
+        assert_eq!(get(&pipeline, "/peek", None).1, None);
+
+        let (response, sid) = get(&pipeline, "/visits", None);
+        let sid = sid.unwrap();
+        assert!(is_session_id(&sid));
+        let cookie = response.headers.get("Set-Cookie").unwrap();
+        assert!(cookie.contains("; Max-Age=60; Path=/; HttpOnly; SameSite=Lax"), "{}", cookie);
+        assert_eq!(get(&pipeline, "/visits", Some(&sid)).0.body, b"2");
+
+        // Unknown IDs get a new session
+        let (response, other) = get(&pipeline, "/visits", Some(&new_session_id()));
+        assert_eq!(response.body, b"1");
+        assert_ne!(other.unwrap(), sid);
+
+        let (_, new_sid) = get(&pipeline, "/login", Some(&sid));
+        let new_sid = new_sid.unwrap();
+        assert_ne!(new_sid, sid);
+        assert_eq!(get(&pipeline, "/visits", Some(&sid)).0.body, b"1");
+        assert_eq!(get(&pipeline, "/visits", Some(&new_sid)).0.body, b"3");
+
+        let (response, removed) = get(&pipeline, "/logout", Some(&new_sid));
+        assert_eq!(removed.as_deref(), Some(""));
+        assert!(response.headers.get("Set-Cookie").unwrap().contains("Max-Age=0"));
+        assert_eq!(get(&pipeline, "/visits", Some(&new_sid)).0.body, b"1");
+    }
+
+    #[test]
+    pub fn test_memory_store_expiry() {
+        let store = MemoryStore::new();
+        let data: SessionData = serde_json::from_str(r#"{"user": "Eric"}"#).unwrap();
+        store.save("short", &data, Duration::from_millis(50)).unwrap();
+        store.save("long", &data, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.load("short").unwrap(), Some(data.clone()));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.load("short").unwrap(), None);
+        store.save("short", &data, Duration::ZERO).unwrap();
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        assert_eq!(store.load("long").unwrap(), Some(data));
+    }
+
+    #[test]
+    pub fn test_file_store() {
+        let dir = temp_dir("sessions");
+        let data: SessionData = serde_json::from_str(r#"{"user": "Eric", "visits": 3}"#).unwrap();
+        {
+            let store = FileStore::new(&dir).unwrap();
+            store.save("a", &data, Duration::from_secs(60)).unwrap();
+            store.save("b", &data, Duration::ZERO).unwrap();
+            store.save("c", &data, Duration::ZERO).unwrap();
+            assert_eq!(store.load("b").unwrap(), None);
+            assert!(!dir.join("b.json").exists());
+        }
+        // Survives a restart
+        let store = FileStore::new(&dir).unwrap();
+        assert_eq!(store.load("a").unwrap(), Some(data));
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        store.remove("a").unwrap();
+        store.remove("a").unwrap();
+        assert_eq!(store.load("a").unwrap(), None);
+
+        let pipeline = pipeline(store, Duration::from_secs(60));
+        let (_, sid) = get(&pipeline, "/visits", None);
+        assert_eq!(get(&pipeline, "/visits", sid.as_deref()).0.body, b"2");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
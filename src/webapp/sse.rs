@@ -0,0 +1,378 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::webapp::connection::{StreamBody, IDLE_POLL_INTERVAL};
+use crate::webapp::http::{HttpRequest, HttpResponse};
+use crate::webapp::server::ShutdownSignal;
+
+/// Comment line sent when nothing was published for a while, keeps proxies from closing the connection.
+const HEARTBEAT: &[u8] = b": heartbeat\n\n";
+
+/// One Server-Sent Event, see the `text/event-stream` format of the HTML standard.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Event {
+    pub id: Option<String>,
+    /// Event type, `message` for the client if `None`
+    pub event: Option<String>,
+    pub data: String,
+    /// Reconnection delay asked from the client
+    pub retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Event { data: data.into(), ..Event::default() }
+    }
+
+    pub fn with_event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// The event on the wire, one `data:` line per line of `data`.
+    /// ```text
+    /// id: 7
+    /// event: tick
+    /// data: first line
+    /// data: second line
+    ///
+    /// ```
+    pub fn to_frame(&self) -> String {
+        // A line break in `id` or `event` would start another field
+        let single_line = |value: &str| value.replace(['\r', '\n'], " ");
+        let mut frame = String::new();
+        if let Some(id) = &self.id {
+            frame.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(event) = &self.event {
+            frame.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(retry) = self.retry {
+            frame.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\n', '\r']) {
+            frame.push_str(&format!("data: {}\n", line));
+        }
+        frame.push('\n');
+        frame
+    }
+}
+
+/// Publishes events to every connected `text/event-stream` client.
+/// Cheap to clone, every clone publishes to the same clients, from any thread.
+///
+/// Published events get increasing ids and the last `history` of them are kept,
+/// so a client reconnecting with `Last-Event-ID` gets what it missed.
+/// ```ignore
+/// let events = Broadcaster::new();
+/// router.get("/events", { let events = events.clone(); move |request, _| events.subscribe(request) });
+/// thread::spawn(move || events.publish(Event::new("42").with_event("answer")));
+/// ```
+#[derive(Clone)]
+pub struct Broadcaster {
+    channel: Arc<Mutex<Channel>>,
+    heartbeat: Duration,
+}
+
+struct Channel {
+    next_id: u64,
+    history: VecDeque<(u64, Arc<str>)>,
+    max_history: usize,
+    subscribers: Vec<Sender<Arc<str>>>,
+}
+
+impl Broadcaster {
+    /// Heartbeat every 15 seconds, keeps the last 100 events.
+    pub fn new() -> Self {
+        let channel = Channel { next_id: 1, history: VecDeque::new(), max_history: 100, subscribers: Vec::new() };
+        Broadcaster { channel: Arc::new(Mutex::new(channel)), heartbeat: Duration::from_secs(15) }
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Keep the last `max_history` events for reconnecting clients, `0` keeps none.
+    pub fn with_history(self, max_history: usize) -> Self {
+        self.lock().max_history = max_history;
+        self
+    }
+
+    /// Send `event` to every connected client, its `id` is replaced by the next one. Returns that id.
+    pub fn publish(&self, event: Event) -> u64 {
+        let mut channel = self.lock();
+        let id = channel.next_id;
+        channel.next_id += 1;
+        let frame: Arc<str> = Event { id: Some(id.to_string()), ..event }.to_frame().into();
+        // Clients that are gone dropped their receiver
+        channel.subscribers.retain(|subscriber| subscriber.send(Arc::clone(&frame)).is_ok());
+        if channel.max_history > 0 {
+            if channel.history.len() == channel.max_history {
+                channel.history.pop_front();
+            }
+            channel.history.push_back((id, frame));
+        }
+        id
+    }
+
+    /// Number of clients that didn't disconnect before the last `publish`
+    pub fn subscribers(&self) -> usize {
+        self.lock().subscribers.len()
+    }
+
+    /// `200 text/event-stream` response streaming the published events until the client
+    /// disconnects or the server shuts down. Events published after the `Last-Event-ID`
+    /// of `request` are sent first, if they are still in the history.
+    /// The body is `long_lived`: `serve_connection_with` hands it off rather than keep a worker for it.
+    pub fn subscribe(&self, request: &HttpRequest) -> HttpResponse {
+        let last_id = request.header("Last-Event-ID").and_then(|id| id.trim().parse::<u64>().ok());
+        let (sender, receiver) = mpsc::channel();
+        let missed: Vec<Arc<str>> = {
+            // Under the lock, so no event is both missed and received, or neither
+            let mut channel = self.lock();
+            channel.subscribers.push(sender);
+            match last_id {
+                Some(last_id) => channel.history.iter().filter(|(id, _)| *id > last_id).map(|(_, frame)| Arc::clone(frame)).collect(),
+                None => Vec::new(),
+            }
+        };
+        let heartbeat = self.heartbeat;
+        HttpResponse::new(200)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            .with_stream(StreamBody::new(move |writer, shutdown| stream_events(writer, shutdown, missed, receiver, heartbeat)).with_long_lived(true))
+    }
+
+    /// Handle that doesn't keep the channel open, for a thread publishing as long as anyone can subscribe.
+    pub fn downgrade(&self) -> WeakBroadcaster {
+        WeakBroadcaster { channel: Arc::downgrade(&self.channel), heartbeat: self.heartbeat }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Channel> {
+        self.channel.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// `Broadcaster` without ownership of its channel, see `Broadcaster::downgrade`.
+/// ```ignore
+/// let ticker = events.downgrade();
+/// thread::spawn(move || while let Some(events) = ticker.upgrade() { events.publish(tick()); sleep(TICK) });
+/// ```
+#[derive(Clone)]
+pub struct WeakBroadcaster {
+    channel: Weak<Mutex<Channel>>,
+    heartbeat: Duration,
+}
+
+impl WeakBroadcaster {
+    /// `None` once every `Broadcaster` is dropped, e.g. with the router of a stopped server
+    pub fn upgrade(&self) -> Option<Broadcaster> {
+        self.channel.upgrade().map(|channel| Broadcaster { channel, heartbeat: self.heartbeat })
+    }
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Broadcaster::new()
+    }
+}
+
+/// Write `missed`, then every received frame, with a heartbeat after `heartbeat` of silence.
+/// A failing write means the client is gone.
+fn stream_events(
+    writer: &mut dyn Write,
+    shutdown: &ShutdownSignal,
+    missed: Vec<Arc<str>>,
+    receiver: Receiver<Arc<str>>,
+    heartbeat: Duration,
+) -> io::Result<()> {
+    for frame in missed {
+        writer.write_all(frame.as_bytes())?;
+    }
+    // Sends the head right away, even with nothing missed
+    writer.flush()?;
+    let mut last_write = Instant::now();
+    while !shutdown.is_shutting_down() {
+        match receiver.recv_timeout(IDLE_POLL_INTERVAL.min(heartbeat)) {
+            Ok(frame) => writer.write_all(frame.as_bytes())?,
+            Err(RecvTimeoutError::Timeout) if last_write.elapsed() >= heartbeat => writer.write_all(HEARTBEAT)?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            // Every `Broadcaster` is gone, nothing will come anymore
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        writer.flush()?;
+        last_write = Instant::now();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod sse_test_cases {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+    use crate::webapp::chunked::parse_chunk_line;
+    use crate::webapp::connection::{serve_connection, KeepAlive, Timeouts};
+    use crate::webapp::http::Limits;
+    use crate::webapp::server::ServerHandle;
+
+    fn spawn_server(events: &Broadcaster) -> ServerHandle {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let events = events.clone();
+        ServerHandle::spawn(listener, move |incoming| {
+            for (stream, guard) in incoming {
+                let events = events.clone();
+                thread::spawn(move || {
+                    let _ = serve_connection(stream, &guard, &KeepAlive::default(), &Timeouts::default(), &Limits::default(), |request| {
+                        events.subscribe(request)
+                    });
+                });
+            }
+        })
+        .unwrap()
+    }
+
+    /// The chunked body of a response, decoded as the chunks arrive
+    struct Chunks {
+        reader: BufReader<TcpStream>,
+        remaining: usize,
+    }
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                let mut line = Vec::new();
+                self.reader.read_until(b'\n', &mut line)?;
+                self.remaining = parse_chunk_line(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                if self.remaining == 0 {
+                    return Ok(0);
+                }
+            }
+            let read = self.reader.by_ref().take(self.remaining as u64).read(buf)?;
+            self.remaining -= read;
+            if self.remaining == 0 {
+                self.reader.read_exact(&mut [0; 2])?;
+            }
+            Ok(read)
+        }
+    }
+
+    /// Connect, check the head and give back the reader positioned at the first event.
+    fn connect(server: &ServerHandle, last_event_id: Option<u64>) -> BufReader<Chunks> {
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        let last_event_id = last_event_id.map(|id| format!("Last-Event-ID: {}\r\n", id)).unwrap_or_default();
+        write!(client, "GET /events HTTP/1.1\r\n{}\r\n", last_event_id).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(client);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert!(reader.read_line(&mut head).unwrap() > 0);
+        }
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: text/event-stream\r\n"));
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Content-Length"));
+        BufReader::new(Chunks { reader, remaining: 0 })
+    }
+
+    /// Lines of the next event or comment, without the blank line ending it
+    fn next_frame(reader: &mut BufReader<Chunks>) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0, "stream ended");
+            match line.trim_end_matches('\n') {
+                "" => return lines,
+                line => lines.push(line.to_string()),
+            }
+        }
+    }
+
+    fn wait_for_subscribers(events: &Broadcaster, count: usize) {
+        let started = Instant::now();
+        while events.subscribers() != count {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    pub fn test_frame() {
+        let event = Event::new("one\ntwo\r\nthree").with_event("tick\nid: 9").with_id("7").with_retry(Duration::from_secs(3));
+        assert_eq!(event.to_frame(), "id: 7\nevent: tick id: 9\nretry: 3000\ndata: one\ndata: two\ndata: three\n\n");
+        assert_eq!(Event::new("").to_frame(), "data: \n\n");
+    }
+
+    #[test]
+    pub fn test_publish_from_other_threads() {
+        let events = Broadcaster::new();
+        let server = spawn_server(&events);
+        let mut clients = [connect(&server, None), connect(&server, None)];
+        wait_for_subscribers(&events, 2);
+
+        let publisher = events.clone();
+        thread::spawn(move || publisher.publish(Event::new("42").with_event("answer"))).join().unwrap();
+        for client in clients.iter_mut() {
+            assert_eq!(next_frame(client), vec!["id: 1", "event: answer", "data: 42"]);
+        }
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_weak_broadcaster() {
+        let events = Broadcaster::new();
+        let ticker = events.downgrade();
+        assert_eq!(ticker.upgrade().map(|events| events.publish(Event::new("tick"))), Some(1));
+        // The router went away with its server, the ticker stops with it
+        drop(events);
+        assert!(ticker.upgrade().is_none());
+    }
+
+    #[test]
+    pub fn test_last_event_id() {
+        let events = Broadcaster::new().with_history(2);
+        for data in ["a", "b", "c"] {
+            events.publish(Event::new(data));
+        }
+        let server = spawn_server(&events);
+        // 1 is out of the history already, 2 and 3 are replayed
+        let mut client = connect(&server, Some(1));
+        assert_eq!(next_frame(&mut client), vec!["id: 2", "data: b"]);
+        assert_eq!(next_frame(&mut client), vec!["id: 3", "data: c"]);
+        wait_for_subscribers(&events, 1);
+        events.publish(Event::new("d"));
+        assert_eq!(next_frame(&mut client), vec!["id: 4", "data: d"]);
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_heartbeat_and_disconnect() {
+        let events = Broadcaster::new().with_heartbeat(Duration::from_millis(100));
+        let server = spawn_server(&events);
+        let mut client = connect(&server, None);
+        assert_eq!(next_frame(&mut client), vec![": heartbeat"]);
+        drop(client);
+        // The next heartbeat fails, the stream ends and the next publish forgets the client
+        thread::sleep(Duration::from_millis(500));
+        events.publish(Event::new("anyone?"));
+        assert_eq!(events.subscribers(), 0);
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+}
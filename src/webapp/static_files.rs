@@ -0,0 +1,374 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::webapp::conditional::{strong_eq, truncate_to_secs, Precondition};
+use crate::webapp::connection::StreamBody;
+use crate::webapp::http::{http_date, parse_http_date, percent_encode, HttpRequest, HttpResponse};
+use crate::webapp::template::escape_html;
+
+/// Serve the files below `root`, mounted on a URL prefix by `Router::static_files`.
+/// - `GET /static/css/app.css` => `{root}/css/app.css`
+/// - `GET /static/docs/` => `{root}/docs/index.html`, or a listing if enabled
+/// - `Range` is supported, and so are `If-None-Match`, `If-Modified-Since` and the other conditional headers
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+    listing: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        StaticFiles { root: root.into(), index: "index.html".to_string(), listing: false }
+    }
+
+    /// Show the entries of directories without an index file
+    pub fn with_listing(mut self, listing: bool) -> Self {
+        self.listing = listing;
+        self
+    }
+
+    pub fn with_index(mut self, index: impl Into<String>) -> Self {
+        self.index = index.into();
+        self
+    }
+
+    /// Serve `relative` (the path below the mount prefix) for `request`.
+    pub fn serve(&self, request: &HttpRequest, relative: &str) -> HttpResponse {
+        let Some(path) = self.resolve(relative) else {
+            return HttpResponse::status_page(404);
+        };
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => return io_error_page(&e),
+        };
+        if metadata.is_dir() {
+            // Relative links of the listing need the trailing slash.
+            // Built from the raw target: the decoded path may hold anything, e.g. `%0D%0A`
+            if !request.path.ends_with('/') {
+                let location = match request.target.split_once('?') {
+                    Some((path, query)) => format!("{}/?{}", path, query),
+                    None => format!("{}/", request.target),
+                };
+                return HttpResponse::status_page(301).with_header("Location", location);
+            }
+            let index = path.join(&self.index);
+            if index.is_file() {
+                return self.serve_file(request, &index);
+            }
+            if self.listing {
+                return match list_directory(&path, &request.path) {
+                    Ok(html) => HttpResponse::html(html),
+                    Err(e) => io_error_page(&e),
+                };
+            }
+            return HttpResponse::status_page(403);
+        }
+        self.serve_file(request, &path)
+    }
+
+    /// Map `relative` below `root`. `None` if it tries to leave `root`, e.g. `../../etc/passwd`.
+    fn resolve(&self, relative: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for segment in relative.split('/').filter(|s| !s.is_empty()) {
+            if segment == "." || segment == ".." || segment.contains(['\\', '\0']) || Path::new(segment).is_absolute() {
+                return None;
+            }
+            path.push(segment);
+        }
+        // Symbolic links may still point outside of `root`
+        let root = self.root.canonicalize().ok()?;
+        match path.canonicalize() {
+            Ok(canonical) if canonical.starts_with(&root) => Some(path),
+            Ok(_) => None,
+            Err(_) => Some(path), // not found, let `serve` answer 404
+        }
+    }
+
+    fn serve_file(&self, request: &HttpRequest, path: &Path) -> HttpResponse {
+        match self.try_serve_file(request, path) {
+            Ok(response) => response,
+            Err(e) => io_error_page(&e),
+        }
+    }
+
+    fn try_serve_file(&self, request: &HttpRequest, path: &Path) -> io::Result<HttpResponse> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let length = metadata.len();
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let etag = file_etag(length, modified);
+
+        let response = HttpResponse::new(200)
+            .with_header("Content-Type", mime_type(path))
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("ETag", etag.as_str())
+            .with_header("Last-Modified", http_date(modified));
+
+        match Precondition::evaluate(request, Some(&etag), Some(modified)) {
+            Precondition::Passed => {}
+            Precondition::NotModified => return Ok(HttpResponse { status: 304, body: Vec::new(), ..response }),
+            Precondition::Failed => return Ok(HttpResponse::status_page(412)),
+        }
+
+        // `If-Range`: only send the range if the file didn't change since the client got the rest.
+        // Tags compare strongly, a weak one, e.g. of a compressed copy, never gets a range
+        let range = match request.header("If-Range") {
+            Some(tag) if tag.starts_with('"') || tag.starts_with("W/") => request.header("Range").filter(|_| strong_eq(tag, &etag)),
+            Some(date) if parse_http_date(date) != Some(truncate_to_secs(modified)) => None,
+            _ => request.header("Range"),
+        };
+        // Streamed from the file, it never sits in memory as a whole
+        let (response, start, length) = match range.map(|range| parse_range(range, length)) {
+            Some(Some(Ok((start, end)))) => {
+                let response = HttpResponse { status: 206, ..response }
+                    .with_header("Content-Range", format!("bytes {}-{}/{}", start, end, length));
+                (response, start, end - start + 1)
+            }
+            Some(Some(Err(()))) => {
+                return Ok(HttpResponse::status_page(416).with_header("Content-Range", format!("bytes */{}", length)))
+            }
+            // no `Range`, or a form we don't support: send the whole file
+            _ => (response, 0, length),
+        };
+        file.seek(SeekFrom::Start(start))?;
+        Ok(response.with_stream(StreamBody::sized(length, move |writer, _| {
+            io::copy(&mut file.take(length), writer).map(drop)
+        })))
+    }
+}
+
+/// Validator from size and modification time, like nginx does.
+/// Strong, as the nanoseconds change with every write on the file systems we care about.
+fn file_etag(length: u64, modified: SystemTime) -> String {
+    let nanos = modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    format!("\"{:x}-{:x}\"", length, nanos)
+}
+
+/// Parse a single `bytes=` range into inclusive offsets.
+/// - `None`: not a range we support (other unit, multiple ranges, garbage), serve the whole file
+/// - `Some(Err(()))`: syntactically fine but outside of the file, answer 416
+fn parse_range(range: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // `bytes=-500` is the last 500 bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()));
+        }
+        (length.saturating_sub(suffix), length - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end: u64 = if end.is_empty() { u64::MAX } else { end.parse().ok()? };
+        if end < start {
+            return None;
+        }
+        if start >= length {
+            return Some(Err(()));
+        }
+        (start, end.min(length - 1))
+    };
+    Some(Ok(range))
+}
+
+/// Guess `Content-Type` from the file extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn list_directory(dir: &Path, url_path: &str) -> io::Result<String> {
+    let mut entries: Vec<(String, bool)> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| {
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            (entry.file_name().to_string_lossy().into_owned(), is_dir)
+        })
+        .collect();
+    entries.sort();
+
+    let title = escape_html(url_path);
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"/><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n", title);
+    if url_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let suffix = if is_dir { "/" } else { "" };
+        // Encoded, `#`, `?` and `%` in a name don't end or escape the path, `:` doesn't make it a scheme
+        html.push_str(&format!(
+            "<li><a href=\"{0}{2}\">{1}{2}</a></li>\n",
+            percent_encode(&name),
+            escape_html(&name),
+            suffix
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    Ok(html)
+}
+
+fn io_error_page(e: &io::Error) -> HttpResponse {
+    match e.kind() {
+        io::ErrorKind::NotFound => HttpResponse::status_page(404),
+        io::ErrorKind::PermissionDenied => HttpResponse::status_page(403),
+        _ => HttpResponse::status_page(500),
+    }
+}
+
+#[cfg(test)]
+pub mod static_files_test_cases {
+    use super::*;
+    use crate::webapp::server::ShutdownSignal;
+
+    /// A fresh directory: `hello.txt`, `docs/index.html`, `empty/`, `<b>.txt`
+    fn fixture(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rs-tutorial-static-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("hello.txt"), "Hello, Eric!").unwrap();
+        fs::write(root.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(root.join("<b>.txt"), "bold").unwrap();
+        fs::write(root.join("100% #1?.txt"), "first").unwrap();
+        root
+    }
+
+    fn get(files: &StaticFiles, path: &str, relative: &str, headers: &[(&str, &str)]) -> HttpResponse {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        let request = HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap();
+        let mut response = files.serve(&request, relative);
+        // Files are streamed with their length
+        if let Some(stream) = response.stream.take() {
+            let length = stream.length();
+            stream.write_framed(request.version, &mut response.body, &ShutdownSignal::never()).unwrap();
+            assert_eq!(length, Some(response.body.len() as u64));
+        }
+        response
+    }
+
+    #[test]
+    pub fn test_serve_file() {
+        let files = StaticFiles::new(fixture("file"));
+        let response = get(&files, "/static/hello.txt", "hello.txt", &[]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"Hello, Eric!");
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert!(response.headers.contains("ETag"));
+        assert!(response.headers.contains("Last-Modified"));
+
+        assert_eq!(get(&files, "/static/nothing.txt", "nothing.txt", &[]).status, 404);
+    }
+
+    #[test]
+    pub fn test_path_traversal() {
+        let root = fixture("traversal");
+        let files = StaticFiles::new(root.join("docs"));
+        assert_eq!(get(&files, "/static/../hello.txt", "../hello.txt", &[]).status, 404);
+        assert_eq!(get(&files, "/static/a/../../hello.txt", "a/../../hello.txt", &[]).status, 404);
+        assert_eq!(get(&files, "/static/..%5Chello.txt", "..\\hello.txt", &[]).status, 404);
+        assert_eq!(get(&files, "/static/index.html", "index.html", &[]).status, 200);
+    }
+
+    #[test]
+    pub fn test_directory() {
+        let files = StaticFiles::new(fixture("directory"));
+        let redirect = get(&files, "/static/docs", "docs", &[]);
+        assert_eq!(redirect.status, 301);
+        assert_eq!(redirect.headers.get("Location"), Some("/static/docs/"));
+        // Decoded, the path would inject a header
+        let redirect = get(&files, "/static/docs%0D%0ASet-Cookie:%20x=1?page=2", "docs", &[]);
+        assert_eq!(redirect.headers.get("Location"), Some("/static/docs%0D%0ASet-Cookie:%20x=1/?page=2"));
+        assert_eq!(get(&files, "/static/docs/", "docs/", &[]).body, b"<h1>docs</h1>");
+        assert_eq!(get(&files, "/static/empty/", "empty/", &[]).status, 403);
+
+        let files = files.with_listing(true);
+        let listing = String::from_utf8(get(&files, "/static/", "", &[]).body).unwrap();
+        assert!(listing.contains("<a href=\"docs/\">docs/</a>"));
+        assert!(listing.contains("<a href=\"hello.txt\">hello.txt</a>"));
+        assert!(listing.contains("<a href=\"%3Cb%3E.txt\">&lt;b&gt;.txt</a>"));
+        assert!(listing.contains("<a href=\"100%25%20%231%3F.txt\">100% #1?.txt</a>"));
+        let response = get(&files, "/static/100%25%20%231%3F.txt", "100% #1?.txt", &[]);
+        assert_eq!(response.body, b"first");
+    }
+
+    #[test]
+    pub fn test_range() {
+        let files = StaticFiles::new(fixture("range"));
+        let response = get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=0-4")]);
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, b"Hello");
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 0-4/12"));
+
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=7-")]).body, b"Eric!");
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=-5")]).body, b"Eric!");
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=7-100")]).body, b"Eric!");
+
+        let unsatisfiable = get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=100-")]);
+        assert_eq!(unsatisfiable.status, 416);
+        assert_eq!(unsatisfiable.headers.get("Content-Range"), Some("bytes */12"));
+
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=0-1,4-5")]).status, 200);
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("Range", "lines=1-2")]).status, 200);
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=0-4"), ("If-Range", "\"old\"")]).status, 200);
+        let etag = get(&files, "/static/hello.txt", "hello.txt", &[]).headers.get("ETag").unwrap().to_string();
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=0-4"), ("If-Range", &etag)]).status, 206);
+        // A weak tag never gets a range, not even one that compares equal weakly
+        let weak = format!("W/{}", etag);
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("Range", "bytes=0-4"), ("If-Range", &weak)]).status, 200);
+    }
+
+    #[test]
+    pub fn test_revalidation() {
+        let files = StaticFiles::new(fixture("revalidation"));
+        let response = get(&files, "/static/hello.txt", "hello.txt", &[]);
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        let last_modified = response.headers.get("Last-Modified").unwrap().to_string();
+
+        let cached = get(&files, "/static/hello.txt", "hello.txt", &[("If-None-Match", &etag)]);
+        assert_eq!(cached.status, 304);
+        assert!(cached.body.is_empty());
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("If-None-Match", "\"other\"")]).status, 200);
+        assert_eq!(get(&files, "/static/hello.txt", "hello.txt", &[("If-Modified-Since", &last_modified)]).status, 304);
+        assert_eq!(
+            get(&files, "/static/hello.txt", "hello.txt", &[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]).status,
+            200
+        );
+    }
+}
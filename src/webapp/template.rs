@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use serde_json::Value;
+
+/// Includes and layouts deeper than this are most likely a cycle.
+const MAX_DEPTH: usize = 16;
+
+/// Render HTML templates from a directory with a `serde_json::Value` context.
+///
+/// Syntax:
+/// - `{{ user.name }}` prints a value, HTML-escaped. `{{ html | raw }}` prints it as it is.
+/// - `{% if user.admin %}...{% else %}...{% endif %}`, `{% if not items %}`
+/// - `{% for item in items %}{{ loop.index }}. {{ item }}{% endfor %}`, also `loop.first` and `loop.last`
+/// - `{% include "header.html" %}`
+/// - `{% extends "layout.html" %}` with `{% block content %}...{% endblock %}` in both files
+/// - `{# comment #}`
+///
+/// Compiled templates are cached and compiled again when their file changes.
+pub struct TemplateEngine {
+    dir: PathBuf,
+    cache: RwLock<HashMap<String, (SystemTime, Arc<Template>)>>,
+}
+
+impl TemplateEngine {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        TemplateEngine { dir: dir.into(), cache: RwLock::new(HashMap::new()) }
+    }
+
+    pub fn render(&self, name: &str, context: &Value) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut scope = Scope { root: context, locals: Vec::new() };
+        self.render_template(name, &mut scope, &HashMap::new(), 0, &mut out)?;
+        Ok(out)
+    }
+
+    /// Compile `source` without a file, e.g. in tests. It can still include files of the directory.
+    pub fn render_str(&self, source: &str, context: &Value) -> Result<String, TemplateError> {
+        let template = Template::compile("<string>", source)?;
+        let mut out = String::new();
+        let mut scope = Scope { root: context, locals: Vec::new() };
+        self.render_compiled(&template, &mut scope, &HashMap::new(), 0, &mut out)?;
+        Ok(out)
+    }
+
+    fn load(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        // Only names below `dir`: `dir.join` replaces `dir` with an absolute name.
+        // `\` separates on Windows only, its `..` are rejected everywhere.
+        let relative = Path::new(name);
+        if relative.is_absolute()
+            || !relative.components().all(|component| matches!(component, Component::Normal(_)))
+            || name.split(['/', '\\']).any(|segment| segment == "..")
+        {
+            return Err(TemplateError::NotFound(name.to_string()));
+        }
+        let path = self.dir.join(name);
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| io_error(name, e))?;
+        if let Some((cached_at, template)) = self.cache.read().unwrap_or_else(|e| e.into_inner()).get(name) {
+            if *cached_at == modified {
+                return Ok(Arc::clone(template));
+            }
+        }
+        let source = fs::read_to_string(&path).map_err(|e| io_error(name, e))?;
+        let template = Arc::new(Template::compile(name, &source)?);
+        self.cache
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), (modified, Arc::clone(&template)));
+        Ok(template)
+    }
+
+    fn render_template(
+        &self,
+        name: &str,
+        scope: &mut Scope,
+        blocks: &HashMap<String, Vec<Node>>,
+        depth: usize,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        if depth > MAX_DEPTH {
+            return Err(TemplateError::TooDeep(name.to_string()));
+        }
+        let template = self.load(name)?;
+        self.render_compiled(&template, scope, blocks, depth, out)
+    }
+
+    fn render_compiled(
+        &self,
+        template: &Template,
+        scope: &mut Scope,
+        blocks: &HashMap<String, Vec<Node>>,
+        depth: usize,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        match &template.parent {
+            Some(parent) => {
+                // Blocks of the most derived template win
+                let mut merged = template.blocks.clone();
+                merged.extend(blocks.iter().map(|(k, v)| (k.clone(), v.clone())));
+                self.render_template(parent, scope, &merged, depth + 1, out)
+            }
+            None => self.render_nodes(&template.nodes, scope, blocks, depth, out),
+        }
+    }
+
+    fn render_nodes(
+        &self,
+        nodes: &[Node],
+        scope: &mut Scope,
+        blocks: &HashMap<String, Vec<Node>>,
+        depth: usize,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Var { path, raw } => {
+                    let text = scope.lookup(path).map(to_text).unwrap_or_default();
+                    if *raw {
+                        out.push_str(&text);
+                    } else {
+                        out.push_str(&escape_html(&text));
+                    }
+                }
+                Node::If { path, negate, then, otherwise } => {
+                    let truthy = scope.lookup(path).map(is_truthy).unwrap_or(false);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.render_nodes(branch, scope, blocks, depth, out)?;
+                }
+                Node::For { var, path, body } => {
+                    let items: Vec<Value> = match scope.lookup(path) {
+                        Some(Value::Array(items)) => items.clone(),
+                        Some(Value::Object(map)) => map.values().cloned().collect(),
+                        _ => Vec::new(),
+                    };
+                    let len = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let meta = serde_json::json!({ "index": i + 1, "first": i == 0, "last": i + 1 == len });
+                        scope.locals.push((var.clone(), item));
+                        scope.locals.push(("loop".to_string(), meta));
+                        let result = self.render_nodes(body, scope, blocks, depth, out);
+                        scope.locals.truncate(scope.locals.len() - 2);
+                        result?;
+                    }
+                }
+                Node::Include(name) => self.render_template(name, scope, blocks, depth + 1, out)?,
+                Node::Block { name, body } => {
+                    let body = blocks.get(name).unwrap_or(body);
+                    self.render_nodes(body, scope, blocks, depth, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Variables visible while rendering: loop variables shadow the root context.
+struct Scope<'a> {
+    root: &'a Value,
+    locals: Vec<(String, Value)>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = match self.locals.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.root.get(first)?,
+        };
+        for key in rest {
+            value = match value {
+                Value::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+                _ => value.get(key)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var { path: Vec<String>, raw: bool },
+    If { path: Vec<String>, negate: bool, then: Vec<Node>, otherwise: Vec<Node> },
+    For { var: String, path: Vec<String>, body: Vec<Node> },
+    Include(String),
+    Block { name: String, body: Vec<Node> },
+}
+
+#[derive(Debug)]
+struct Template {
+    parent: Option<String>,
+    nodes: Vec<Node>,
+    blocks: HashMap<String, Vec<Node>>,
+}
+
+/// A block tag waiting for its end tag while compiling.
+enum Open {
+    If { path: Vec<String>, negate: bool, then: Option<Vec<Node>> },
+    For { var: String, path: Vec<String> },
+    Block { name: String },
+}
+
+impl Template {
+    fn compile(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let syntax = |message: String| TemplateError::Syntax { name: name.to_string(), message };
+        let mut parent = None;
+        let mut blocks = HashMap::new();
+        // (open tag, nodes collected so far in the enclosing level)
+        let mut stack: Vec<(Open, Vec<Node>)> = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut rest = source;
+
+        while !rest.is_empty() {
+            let Some(start) = find_tag(rest) else {
+                nodes.push(Node::Text(rest.to_string()));
+                break;
+            };
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+            let open = &rest[start..start + 2];
+            let close = match open {
+                "{{" => "}}",
+                "{%" => "%}",
+                _ => "#}",
+            };
+            let end = rest[start + 2..]
+                .find(close)
+                .ok_or_else(|| syntax(format!("unclosed {}", open)))?
+                + start
+                + 2;
+            let inner = rest[start + 2..end].trim();
+            rest = &rest[end + 2..];
+
+            match open {
+                "{#" => {}
+                "{{" => {
+                    let (expr, filter) = match inner.split_once('|') {
+                        Some((expr, filter)) => (expr.trim(), Some(filter.trim())),
+                        None => (inner, None),
+                    };
+                    let raw = match filter {
+                        None => false,
+                        Some("raw") => true,
+                        Some(other) => return Err(syntax(format!("unknown filter {:?}", other))),
+                    };
+                    nodes.push(Node::Var { path: parse_path(expr).map_err(&syntax)?, raw });
+                }
+                _ => {
+                    let words: Vec<&str> = inner.split_whitespace().collect();
+                    match words.as_slice() {
+                        ["if", "not", expr] => {
+                            let path = parse_path(expr).map_err(&syntax)?;
+                            stack.push((Open::If { path, negate: true, then: None }, std::mem::take(&mut nodes)));
+                        }
+                        ["if", expr] => {
+                            let path = parse_path(expr).map_err(&syntax)?;
+                            stack.push((Open::If { path, negate: false, then: None }, std::mem::take(&mut nodes)));
+                        }
+                        ["else"] => match stack.last_mut() {
+                            Some((Open::If { then: then @ None, .. }, _)) => *then = Some(std::mem::take(&mut nodes)),
+                            _ => return Err(syntax("else without if".to_string())),
+                        },
+                        ["endif"] => match stack.pop() {
+                            Some((Open::If { path, negate, then }, outer)) => {
+                                let node = match then {
+                                    Some(then) => Node::If { path, negate, then, otherwise: std::mem::take(&mut nodes) },
+                                    None => Node::If { path, negate, then: std::mem::take(&mut nodes), otherwise: Vec::new() },
+                                };
+                                nodes = outer;
+                                nodes.push(node);
+                            }
+                            _ => return Err(syntax("endif without if".to_string())),
+                        },
+                        ["for", var, "in", expr] => {
+                            let path = parse_path(expr).map_err(&syntax)?;
+                            stack.push((Open::For { var: var.to_string(), path }, std::mem::take(&mut nodes)));
+                        }
+                        ["endfor"] => match stack.pop() {
+                            Some((Open::For { var, path }, outer)) => {
+                                let body = std::mem::replace(&mut nodes, outer);
+                                nodes.push(Node::For { var, path, body });
+                            }
+                            _ => return Err(syntax("endfor without for".to_string())),
+                        },
+                        ["block", block] => {
+                            stack.push((Open::Block { name: block.to_string() }, std::mem::take(&mut nodes)));
+                        }
+                        ["endblock"] => match stack.pop() {
+                            Some((Open::Block { name: block }, outer)) => {
+                                let body = std::mem::replace(&mut nodes, outer);
+                                blocks.insert(block.clone(), body.clone());
+                                nodes.push(Node::Block { name: block, body });
+                            }
+                            _ => return Err(syntax("endblock without block".to_string())),
+                        },
+                        ["include", file] => nodes.push(Node::Include(parse_string(file).map_err(&syntax)?)),
+                        ["extends", file] => parent = Some(parse_string(file).map_err(&syntax)?),
+                        _ => return Err(syntax(format!("unknown tag {{% {} %}}", inner))),
+                    }
+                }
+            }
+        }
+        if let Some((open, _)) = stack.last() {
+            let tag = match open {
+                Open::If { .. } => "if",
+                Open::For { .. } => "for",
+                Open::Block { .. } => "block",
+            };
+            return Err(syntax(format!("unclosed {{% {} %}}", tag)));
+        }
+        Ok(Template { parent, nodes, blocks })
+    }
+}
+
+/// Offset of the next `{{`, `{%` or `{#`
+fn find_tag(s: &str) -> Option<usize> {
+    ["{{", "{%", "{#"].iter().filter_map(|tag| s.find(tag)).min()
+}
+
+/// `user.name` => `["user", "name"]`
+fn parse_path(expr: &str) -> Result<Vec<String>, String> {
+    let path: Vec<String> = expr.split('.').map(|s| s.trim().to_string()).collect();
+    if path.iter().any(|s| s.is_empty() || !s.chars().all(|c| c.is_alphanumeric() || c == '_')) {
+        return Err(format!("invalid variable {:?}", expr));
+    }
+    Ok(path)
+}
+
+/// `"header.html"` => `header.html`
+fn parse_string(s: &str) -> Result<String, String> {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .map(str::to_string)
+        .ok_or_else(|| format!("expected a quoted file name, got {}", s))
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// `null`, `false`, `0`, `""`, `[]` and `{}` are false
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+/// Escape text for HTML content and quoted attribute values.
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn io_error(name: &str, e: io::Error) -> TemplateError {
+    match e.kind() {
+        io::ErrorKind::NotFound => TemplateError::NotFound(name.to_string()),
+        _ => TemplateError::Io(name.to_string(), e),
+    }
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    NotFound(String),
+    Io(String, io::Error),
+    Syntax { name: String, message: String },
+    /// Includes or layouts nested too deep, e.g. a template including itself
+    TooDeep(String),
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::NotFound(name) => write!(f, "template {} not found", name),
+            TemplateError::Io(name, e) => write!(f, "failed to read template {}: {}", name, e),
+            TemplateError::Syntax { name, message } => write!(f, "syntax error in template {}: {}", name, message),
+            TemplateError::TooDeep(name) => write!(f, "template {} nested too deep", name),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[cfg(test)]
+pub mod template_test_cases {
+    use serde_json::json;
+
+    use super::*;
+
+    fn engine(name: &str, files: &[(&str, &str)]) -> TemplateEngine {
+        let dir = std::env::temp_dir().join(format!("rs-tutorial-templates-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, source) in files {
+            fs::write(dir.join(file), source).unwrap();
+        }
+        TemplateEngine::new(dir)
+    }
+
+    #[test]
+    pub fn test_variables_and_escaping() {
+        let engine = engine("variables", &[]);
+        let context = json!({ "user": { "name": "<Eric>", "age": 18 }, "tags": ["a", "b"], "html": "<b>hi</b>" });
+        assert_eq!(engine.render_str("Hi {{ user.name }}, {{user.age}}!", &context).unwrap(), "Hi &lt;Eric&gt;, 18!");
+        assert_eq!(engine.render_str("{{ html | raw }} {{ tags.1 }} [{{ missing }}]", &context).unwrap(), "<b>hi</b> b []");
+        assert_eq!(engine.render_str("{# hidden #}shown", &context).unwrap(), "shown");
+    }
+
+    #[test]
+    pub fn test_if_and_for() {
+        let engine = engine("blocks", &[]);
+        let context = json!({ "admin": true, "items": ["x", "y", "z"], "empty": [] });
+        let source = "{% if admin %}A{% else %}U{% endif %}{% if not empty %}-{% endif %}\
+                      {% for item in items %}{{ loop.index }}{{ item }}{% if not loop.last %},{% endif %}{% endfor %}";
+        assert_eq!(engine.render_str(source, &context).unwrap(), "A-1x,2y,3z");
+        assert_eq!(engine.render_str("{% for i in empty %}x{% else %}{% endfor %}", &context).unwrap_err().to_string(),
+                   "syntax error in template <string>: else without if");
+    }
+
+    #[test]
+    pub fn test_include_and_layout() {
+        let engine = engine(
+            "layout",
+            &[
+                ("layout.html", "<title>{% block title %}Default{% endblock %}</title>{% include \"nav.html\" %}<main>{% block content %}{% endblock %}</main>"),
+                ("nav.html", "<nav>{{ user }}</nav>"),
+                ("page.html", "{% extends \"layout.html\" %}{% block content %}Hello, {{ user }}!{% endblock %}"),
+                ("titled.html", "{% extends \"page.html\" %}{% block title %}Titled{% endblock %}"),
+                ("loop.html", "{% include \"loop.html\" %}"),
+            ],
+        );
+        let context = json!({ "user": "Eric" });
+        assert_eq!(
+            engine.render("page.html", &context).unwrap(),
+            "<title>Default</title><nav>Eric</nav><main>Hello, Eric!</main>"
+        );
+        assert_eq!(
+            engine.render("titled.html", &context).unwrap(),
+            "<title>Titled</title><nav>Eric</nav><main>Hello, Eric!</main>"
+        );
+        assert!(matches!(engine.render("loop.html", &context), Err(TemplateError::TooDeep(_))));
+        assert!(matches!(engine.render("missing.html", &context), Err(TemplateError::NotFound(_))));
+        assert!(matches!(engine.render("../secret.html", &context), Err(TemplateError::NotFound(_))));
+        assert!(matches!(engine.render("/etc/passwd", &context), Err(TemplateError::NotFound(_))));
+        let absolute = engine.dir.join("nav.html");
+        assert!(matches!(engine.render(absolute.to_str().unwrap(), &context), Err(TemplateError::NotFound(_))));
+        assert!(matches!(engine.render("./nav.html", &context), Err(TemplateError::NotFound(_))));
+        assert!(matches!(engine.render_str("{% include \"/etc/passwd\" %}", &context), Err(TemplateError::NotFound(_))));
+    }
+
+    #[test]
+    pub fn test_syntax_errors() {
+        let engine = engine("syntax", &[]);
+        let context = json!({});
+        for source in ["{{ name", "{% if x %}", "{% endfor %}", "{% while x %}", "{{ a b }}", "{{ x | upper }}"] {
+            assert!(matches!(engine.render_str(source, &context), Err(TemplateError::Syntax { .. })), "{}", source);
+        }
+    }
+
+    #[test]
+    pub fn test_cache_reload() {
+        let engine = engine("cache", &[("hello.html", "v1")]);
+        assert_eq!(engine.render("hello.html", &json!({})).unwrap(), "v1");
+        assert_eq!(engine.render("hello.html", &json!({})).unwrap(), "v1");
+
+        let path = engine.dir.join("hello.html");
+        fs::write(&path, "v2").unwrap();
+        // make sure the modification time moves even on coarse file systems
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!(engine.render("hello.html", &json!({})).unwrap(), "v2");
+    }
+}
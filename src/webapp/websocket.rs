@@ -0,0 +1,629 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+use crate::webapp::connection::{Upgrade, Upgraded, IDLE_POLL_INTERVAL};
+use crate::webapp::http::{HttpRequest, HttpResponse, Method};
+use crate::webapp::server::ShutdownSignal;
+
+/// Appended to `Sec-WebSocket-Key` before hashing, RFC 6455 section 1.3
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message accepted by default, fragments included.
+pub const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+/// How long the rest of a frame may take once its first byte arrived.
+pub const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Frame opcodes, RFC 6455 section 5.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Result<Opcode, WebSocketError> {
+        match bits {
+            0x0 => Ok(Opcode::Continuation),
+            0x1 => Ok(Opcode::Text),
+            0x2 => Ok(Opcode::Binary),
+            0x8 => Ok(Opcode::Close),
+            0x9 => Ok(Opcode::Ping),
+            0xA => Ok(Opcode::Pong),
+            _ => Err(WebSocketError::Protocol(format!("reserved opcode {:#x}", bits))),
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// One frame on the wire. Payloads are kept unmasked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, payload: impl Into<Vec<u8>>) -> Self {
+        Frame { fin: true, opcode, payload: payload.into() }
+    }
+
+    /// Read one frame. Client frames must be masked, server frames must not be,
+    /// so `masked` tells which side the frame comes from.
+    /// `max_payload` limits data frames, control frames never exceed 125 bytes.
+    pub fn read_from<R: Read>(reader: &mut R, masked: bool, max_payload: usize) -> Result<Frame, WebSocketError> {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head)?;
+        if head[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits set without extension".to_string()));
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = Opcode::from_bits(head[0] & 0x0F)?;
+        if (head[1] & 0x80 != 0) != masked {
+            let expected = if masked { "masked" } else { "unmasked" };
+            return Err(WebSocketError::Protocol(format!("frame must be {}", expected)));
+        }
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut bytes = [0; 2];
+                reader.read_exact(&mut bytes)?;
+                u16::from_be_bytes(bytes) as u64
+            }
+            127 => {
+                let mut bytes = [0; 8];
+                reader.read_exact(&mut bytes)?;
+                u64::from_be_bytes(bytes)
+            }
+            length => length as u64,
+        };
+        if opcode.is_control() && (length > 125 || !fin) {
+            return Err(WebSocketError::Protocol("control frames must be short and not fragmented".to_string()));
+        }
+        // Control frames may come between the fragments of a message, they don't count towards it
+        if !opcode.is_control() && length > max_payload as u64 {
+            return Err(WebSocketError::TooLarge { length, limit: max_payload });
+        }
+        let mut key = [0; 4];
+        if masked {
+            reader.read_exact(&mut key)?;
+        }
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        if masked {
+            apply_mask(&mut payload, key);
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    /// Write the frame, masked with `mask` on the client side.
+    pub fn write_to<W: Write>(&self, writer: &mut W, mask: Option<[u8; 4]>) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + 14);
+        bytes.push(if self.fin { 0x80 } else { 0 } | self.opcode.bits());
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            length @ 0..=125 => bytes.push(mask_bit | length as u8),
+            length @ 126..=0xFFFF => {
+                bytes.push(mask_bit | 126);
+                bytes.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                bytes.push(mask_bit | 127);
+                bytes.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        let start = bytes.len();
+        if let Some(key) = mask {
+            bytes.extend_from_slice(&key);
+        }
+        bytes.extend_from_slice(&self.payload);
+        if let Some(key) = mask {
+            apply_mask(&mut bytes[start + 4..], key);
+        }
+        writer.write_all(&bytes)?;
+        writer.flush()
+    }
+}
+
+/// Masking and unmasking are the same XOR, RFC 6455 section 5.3
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+/// A complete, reassembled data message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Close status codes, RFC 6455 section 7.4.1
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const TOO_BIG: u16 = 1009;
+
+    /// Whether a peer may send `code`. 1005, 1006 and 1015 only ever stand for a close without a frame.
+    pub fn is_valid(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// The peer broke the protocol, closed with 1002
+    Protocol(String),
+    /// Text message that isn't UTF-8, closed with 1007
+    InvalidUtf8,
+    /// Message larger than the limit, closed with 1009
+    TooLarge { length: u64, limit: usize },
+    Io(io::Error),
+}
+
+impl WebSocketError {
+    /// Status code of the close frame sent because of this error, `None` if the connection is gone.
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Protocol(_) => Some(close_code::PROTOCOL_ERROR),
+            WebSocketError::InvalidUtf8 => Some(close_code::INVALID_PAYLOAD),
+            WebSocketError::TooLarge { .. } => Some(close_code::TOO_BIG),
+            WebSocketError::Io(_) => None,
+        }
+    }
+}
+
+impl Display for WebSocketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketError::Protocol(message) => write!(f, "protocol error: {}", message),
+            WebSocketError::InvalidUtf8 => write!(f, "text message is not valid UTF-8"),
+            WebSocketError::TooLarge { length, limit } => {
+                write!(f, "message of {} bytes exceeds limit of {} bytes", length, limit)
+            }
+            WebSocketError::Io(e) => write!(f, "connection error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(value: io::Error) -> Self {
+        WebSocketError::Io(value)
+    }
+}
+
+/// `Sec-WebSocket-Accept` answering `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+/// Answer the opening handshake of `request`, then run `handler` with the socket where the connection is handed off to,
+/// a thread of its own with `serve_connection_with`, so it never holds a pool worker.
+/// Not a valid handshake: 400, or 426 for another WebSocket version.
+/// ```ignore
+/// router.get("/ws/echo", |request, _| websocket::upgrade(request, |socket| {
+///     let _ = socket.run(|socket, message| socket.send(message));
+/// }))
+/// ```
+pub fn upgrade<F>(request: &HttpRequest, handler: F) -> HttpResponse
+where
+    F: FnOnce(WebSocket) + Send + 'static,
+{
+    if request.method != Method::Get
+        || !request.headers.contains_token("Upgrade", "websocket")
+        || !request.headers.contains_token("Connection", "upgrade")
+    {
+        return HttpResponse::status_page(400);
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return HttpResponse::status_page(426).with_header("Sec-WebSocket-Version", "13");
+    }
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if BASE64.decode(key).map(|nonce| nonce.len() == 16).unwrap_or(false) => key,
+        _ => return HttpResponse::status_page(400),
+    };
+    HttpResponse::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key))
+        .with_upgrade(Upgrade::new(move |upgraded| match WebSocket::new(upgraded) {
+            Ok(socket) => handler(socket),
+            Err(e) => tracing::warn!("Failed to start WebSocket: {}", e),
+        }))
+}
+
+/// Server side of an open WebSocket connection.
+/// Pings are answered, fragments reassembled and the close handshake completed by `recv`.
+pub struct WebSocket {
+    reader: BufReader<io::Chain<Cursor<Vec<u8>>, TcpStream>>,
+    writer: TcpStream,
+    shutdown: ShutdownSignal,
+    max_message_bytes: usize,
+    close_sent: bool,
+}
+
+impl WebSocket {
+    fn new(upgraded: Upgraded) -> io::Result<WebSocket> {
+        let writer = upgraded.stream.try_clone()?;
+        Ok(WebSocket {
+            reader: BufReader::new(Cursor::new(upgraded.buffered).chain(upgraded.stream)),
+            writer,
+            shutdown: upgraded.shutdown,
+            max_message_bytes: MAX_MESSAGE_BYTES,
+            close_sent: false,
+        })
+    }
+
+    pub fn with_max_message_bytes(mut self, max_message_bytes: usize) -> Self {
+        self.max_message_bytes = max_message_bytes;
+        self
+    }
+
+    /// Next text or binary message, `None` once the connection is closed:
+    /// the client sent a close frame or the server is shutting down (1001).
+    /// A protocol error closes the connection with the matching status code.
+    pub fn recv(&mut self) -> Result<Option<Message>, WebSocketError> {
+        match self.read_message() {
+            Err(e) => {
+                if let Some(code) = e.close_code() {
+                    let _ = self.close(code, "");
+                }
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Option<Message>, WebSocketError> {
+        let mut message: Option<(Opcode, Vec<u8>)> = None;
+        loop {
+            if !self.wait_for_frame()? {
+                let _ = self.close(close_code::GOING_AWAY, "server shutting down");
+                return Ok(None);
+            }
+            let limit = self.max_message_bytes - message.as_ref().map_or(0, |(_, data)| data.len());
+            let deadline = Instant::now() + FRAME_TIMEOUT;
+            let mut reader = FrameReader { reader: &mut self.reader, socket: &self.writer, shutdown: &self.shutdown, deadline };
+            let frame = Frame::read_from(&mut reader, true, limit);
+            self.writer.set_read_timeout(None)?;
+            let frame = frame?;
+            match (frame.opcode, &mut message) {
+                (Opcode::Ping, _) => self.send_frame(Frame::new(Opcode::Pong, frame.payload))?,
+                (Opcode::Pong, _) => {}
+                (Opcode::Close, _) => {
+                    let code = match frame.payload.len() {
+                        0 => close_code::NORMAL,
+                        1 => return Err(WebSocketError::Protocol("truncated close code".to_string())),
+                        _ => u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                    };
+                    if !close_code::is_valid(code) {
+                        return Err(WebSocketError::Protocol(format!("invalid close code {}", code)));
+                    }
+                    if frame.payload.len() > 2 && std::str::from_utf8(&frame.payload[2..]).is_err() {
+                        return Err(WebSocketError::InvalidUtf8);
+                    }
+                    let _ = self.close(code, "");
+                    return Ok(None);
+                }
+                (Opcode::Continuation, None) => {
+                    return Err(WebSocketError::Protocol("continuation without a message".to_string()));
+                }
+                (Opcode::Continuation, Some((_, data))) => data.extend_from_slice(&frame.payload),
+                (Opcode::Text | Opcode::Binary, Some(_)) => {
+                    return Err(WebSocketError::Protocol("new message before the last one ended".to_string()));
+                }
+                (opcode, None) => message = Some((opcode, frame.payload)),
+            }
+            if frame.fin && !frame.opcode.is_control() {
+                return match message.take() {
+                    Some((Opcode::Text, data)) => match String::from_utf8(data) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => Err(WebSocketError::InvalidUtf8),
+                    },
+                    Some((_, data)) => Ok(Some(Message::Binary(data))),
+                    None => continue,
+                };
+            }
+        }
+    }
+
+    /// Wait for the next frame, checking for shutdown every `IDLE_POLL_INTERVAL`.
+    /// Return `false` if the server is shutting down.
+    fn wait_for_frame(&mut self) -> io::Result<bool> {
+        self.writer.set_read_timeout(Some(IDLE_POLL_INTERVAL))?;
+        let result = loop {
+            match self.reader.fill_buf() {
+                Ok(_) => break Ok(true),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if self.shutdown.is_shutting_down() {
+                        break Ok(false);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.writer.set_read_timeout(None)?;
+        result
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        match message {
+            Message::Text(text) => self.send_frame(Frame::new(Opcode::Text, text)),
+            Message::Binary(data) => self.send_frame(Frame::new(Opcode::Binary, data)),
+        }
+    }
+
+    pub fn send_text(&mut self, text: impl Into<String>) -> Result<(), WebSocketError> {
+        self.send(Message::Text(text.into()))
+    }
+
+    pub fn ping(&mut self, payload: impl Into<Vec<u8>>) -> Result<(), WebSocketError> {
+        self.send_frame(Frame::new(Opcode::Ping, payload))
+    }
+
+    /// Send a close frame, once. `recv` returns `None` when the client answers it.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.send_frame(Frame::new(Opcode::Close, payload))
+    }
+
+    fn send_frame(&mut self, frame: Frame) -> Result<(), WebSocketError> {
+        Ok(frame.write_to(&mut self.writer, None)?)
+    }
+
+    /// Call `on_message` for every message until the connection is closed.
+    pub fn run<F>(mut self, mut on_message: F) -> Result<(), WebSocketError>
+    where
+        F: FnMut(&mut WebSocket, Message) -> Result<(), WebSocketError>,
+    {
+        while let Some(message) = self.recv()? {
+            on_message(&mut self, message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the rest of a frame until `deadline`: a client stalling in the middle of one
+/// must not keep the thread, nor hold up a shutdown for longer than `IDLE_POLL_INTERVAL`.
+struct FrameReader<'a, R: Read> {
+    reader: &'a mut R,
+    socket: &'a TcpStream,
+    shutdown: &'a ShutdownSignal,
+    deadline: Instant,
+}
+
+impl<R: Read> Read for FrameReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let now = Instant::now();
+            if now >= self.deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "frame not completed in time"));
+            }
+            let slice = (self.deadline - now).clamp(Duration::from_millis(1), IDLE_POLL_INTERVAL);
+            self.socket.set_read_timeout(Some(slice))?;
+            match self.reader.read(buf) {
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if self.shutdown.is_shutting_down() {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "server shutting down"));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod websocket_test_cases {
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::webapp::connection::{serve_connection, KeepAlive, Timeouts};
+    use crate::webapp::http::Limits;
+    use crate::webapp::server::ServerHandle;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    #[test]
+    pub fn test_accept_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    pub fn test_frame_round_trip() {
+        // RFC 6455 section 5.7: a masked "Hello"
+        let raw = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let frame = Frame::read_from(&mut &raw[..], true, 125).unwrap();
+        assert_eq!(frame, Frame::new(Opcode::Text, "Hello"));
+        let mut written = Vec::new();
+        frame.write_to(&mut written, Some(MASK)).unwrap();
+        assert_eq!(written, raw);
+
+        for length in [0, 125, 126, 65535, 65536] {
+            let frame = Frame::new(Opcode::Binary, vec![7; length]);
+            let mut written = Vec::new();
+            frame.write_to(&mut written, None).unwrap();
+            assert_eq!(Frame::read_from(&mut written.as_slice(), false, 1 << 20).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    pub fn test_invalid_frames() {
+        let read = |raw: &[u8]| Frame::read_from(&mut &raw[..], true, 16).unwrap_err().close_code();
+        assert_eq!(read(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']), Some(close_code::PROTOCOL_ERROR));
+        assert_eq!(read(&[0xC1, 0x80, 0, 0, 0, 0]), Some(close_code::PROTOCOL_ERROR));
+        assert_eq!(read(&[0x83, 0x80, 0, 0, 0, 0]), Some(close_code::PROTOCOL_ERROR));
+        assert_eq!(read(&[0x09, 0x80, 0, 0, 0, 0]), Some(close_code::PROTOCOL_ERROR));
+        assert_eq!(read(&[0x82, 0xFE, 0x01, 0x00]), Some(close_code::TOO_BIG));
+        assert_eq!(read(&[0x82, 0x85, 0, 0]), None);
+        // Control frames are only held to their own limit
+        let ping = [0x89, 0x94, 0, 0, 0, 0].iter().copied().chain([b'p'; 20]).collect::<Vec<_>>();
+        assert_eq!(Frame::read_from(&mut ping.as_slice(), true, 0).unwrap().payload, [b'p'; 20]);
+    }
+
+    /// Echo server, one thread per connection
+    fn spawn_server() -> ServerHandle {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        ServerHandle::spawn(listener, move |incoming| {
+            for (stream, guard) in incoming {
+                thread::spawn(move || {
+                    let _ = serve_connection(stream, &guard, &KeepAlive::default(), &Timeouts::default(), &Limits::default(), |request| {
+                        upgrade(request, |socket| {
+                            let _ = socket.with_max_message_bytes(64).run(|socket, message| socket.send(message));
+                        })
+                    });
+                });
+            }
+        })
+        .unwrap()
+    }
+
+    /// Open a WebSocket and send `frames` right behind the handshake
+    fn connect(server: &ServerHandle, frames: &[Frame]) -> (HttpResponse, BufReader<TcpStream>) {
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        let mut raw = b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        for frame in frames {
+            frame.write_to(&mut raw, Some(MASK)).unwrap();
+        }
+        client.write_all(&raw).unwrap();
+        let mut reader = BufReader::new(client);
+        let response = HttpResponse::read_from(&mut reader).unwrap();
+        (response, reader)
+    }
+
+    fn send(reader: &mut BufReader<TcpStream>, frame: Frame) {
+        frame.write_to(reader.get_mut(), Some(MASK)).unwrap();
+    }
+
+    fn recv(reader: &mut BufReader<TcpStream>) -> Frame {
+        Frame::read_from(reader, false, 1 << 20).unwrap()
+    }
+
+    #[test]
+    pub fn test_echo_session() {
+        let server = spawn_server();
+        let (response, mut reader) = connect(&server, &[Frame::new(Opcode::Text, "early")]);
+        assert_eq!(response.status, 101);
+        assert_eq!(response.headers.get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(recv(&mut reader), Frame::new(Opcode::Text, "early"));
+
+        // Fragmented message with a ping in the middle
+        send(&mut reader, Frame { fin: false, opcode: Opcode::Binary, payload: vec![1, 2] });
+        send(&mut reader, Frame::new(Opcode::Ping, "are you there"));
+        send(&mut reader, Frame { fin: true, opcode: Opcode::Continuation, payload: vec![3] });
+        assert_eq!(recv(&mut reader), Frame::new(Opcode::Pong, "are you there"));
+        assert_eq!(recv(&mut reader), Frame::new(Opcode::Binary, vec![1, 2, 3]));
+
+        send(&mut reader, Frame::new(Opcode::Close, 1000u16.to_be_bytes()));
+        assert_eq!(recv(&mut reader), Frame::new(Opcode::Close, 1000u16.to_be_bytes()));
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_closed_on_errors() {
+        let server = spawn_server();
+        let (_, mut reader) = connect(&server, &[Frame::new(Opcode::Text, vec![0xff, 0xfe])]);
+        assert_eq!(recv(&mut reader).payload[..2], close_code::INVALID_PAYLOAD.to_be_bytes());
+
+        let (_, mut reader) = connect(&server, &[Frame::new(Opcode::Binary, vec![0; 65])]);
+        assert_eq!(recv(&mut reader).payload[..2], close_code::TOO_BIG.to_be_bytes());
+
+        let (_, mut reader) = connect(&server, &[Frame::new(Opcode::Continuation, "x")]);
+        assert_eq!(recv(&mut reader).payload[..2], close_code::PROTOCOL_ERROR.to_be_bytes());
+
+        // Codes that never go on the wire, or aren't assigned, are no echo but a protocol error
+        for code in [999u16, 1005, 1006, 1015, 2000, 5000] {
+            let (_, mut reader) = connect(&server, &[Frame::new(Opcode::Close, code.to_be_bytes())]);
+            assert_eq!(recv(&mut reader).payload[..2], close_code::PROTOCOL_ERROR.to_be_bytes(), "{}", code);
+        }
+        let (_, mut reader) = connect(&server, &[Frame::new(Opcode::Close, 4000u16.to_be_bytes())]);
+        assert_eq!(recv(&mut reader).payload[..2], 4000u16.to_be_bytes());
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    pub fn test_closed_on_shutdown() {
+        let server = spawn_server();
+        let (_, mut reader) = connect(&server, &[Frame::new(Opcode::Text, "up")]);
+        // Once the session echoes, it holds its own count of the connection besides the one of the request
+        assert_eq!(recv(&mut reader), Frame::new(Opcode::Text, "up"));
+        assert_eq!(server.active_connections(), 2);
+        assert!(server.shutdown(Duration::from_secs(5)));
+        assert_eq!(recv(&mut reader).payload[..2], close_code::GOING_AWAY.to_be_bytes());
+    }
+
+    #[test]
+    pub fn test_stalled_frame() {
+        let server = spawn_server();
+        let (_, mut reader) = connect(&server, &[]);
+        // Half a frame, then nothing: shutdown still gets the thread back
+        reader.get_mut().write_all(&[0x81, 0x85, 0x37]).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let started = Instant::now();
+        assert!(server.shutdown(Duration::from_secs(5)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    pub fn test_bad_handshake() {
+        let request = |raw: &str| HttpRequest::read_from(&mut raw.as_bytes()).unwrap().unwrap();
+        let ok = |_: WebSocket| {};
+        assert_eq!(upgrade(&request("GET /ws HTTP/1.1\r\n\r\n"), ok).status, 400);
+        let old = "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 8\r\n\r\n";
+        assert_eq!(upgrade(&request(old), ok).headers.get("Sec-WebSocket-Version"), Some("13"));
+        let short_key = "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: c2hvcnQ=\r\n\r\n";
+        assert_eq!(upgrade(&request(short_key), ok).status, 400);
+    }
+}